@@ -36,6 +36,25 @@ mod benches {
             test::black_box(buffer);
         }
 
+        #[bench]
+        fn atomic(b: &mut test::Bencher) {
+            use self::eep::ring_buffer::AtomicRingBuffer;
+
+            let buffer = AtomicRingBuffer::new(32 * 1024);
+            b.iter(|| buffer.trace_event(SimpleTrace::FooEvent, None));
+            test::black_box(buffer);
+        }
+
+        #[bench]
+        fn tsc_clock(b: &mut test::Bencher) {
+            use self::eep::clock::TscClock;
+            use self::eep::ring_buffer::RingBuffer;
+
+            let mut buffer = RingBuffer::with_clock(2 * 1024 * 1024, TscClock::new());
+            b.iter(|| buffer.trace_event(SimpleTrace::FooEvent, None));
+            test::black_box(buffer);
+        }
+
         #[bench]
         fn serialize_entry(b: &mut test::Bencher) {
             let mut buffer = SimpleTraceBuffer::default();
@@ -63,6 +82,95 @@ mod benches {
                 test::black_box(serde_json::to_string(&buffer).expect("should serialize OK"));
             });
         }
+
+        #[bench]
+        fn snapshot(b: &mut test::Bencher) {
+            let mut buffer = SimpleTraceBuffer::new(64 * 1024);
+            while !buffer.counters().is_truncated() {
+                buffer.trace_event(SimpleTrace::FooEvent, None);
+            }
+            b.iter(|| test::black_box(buffer.snapshot()));
+        }
+
+        #[bench]
+        fn iter_range(b: &mut test::Bencher) {
+            let mut buffer = SimpleTraceBuffer::new(2 * 1024 * 1024);
+            while !buffer.counters().is_truncated() {
+                buffer.trace_event(SimpleTrace::FooEvent, None);
+            }
+            let middle = buffer.last_n(buffer.len() / 2).next().unwrap().timestamp();
+            b.iter(|| test::black_box(buffer.iter_range(middle, middle).count()));
+        }
+
+        #[bench]
+        fn last_n(b: &mut test::Bencher) {
+            let mut buffer = SimpleTraceBuffer::new(2 * 1024 * 1024);
+            while !buffer.counters().is_truncated() {
+                buffer.trace_event(SimpleTrace::FooEvent, None);
+            }
+            b.iter(|| test::black_box(buffer.last_n(10).count()));
+        }
+    }
+
+    mod clock {
+        extern crate eep;
+        extern crate test;
+
+        use self::eep::clock::{Clock, MonotonicClock, TscClock, WallClock};
+
+        #[bench]
+        fn wall(b: &mut test::Bencher) {
+            let clock = WallClock;
+            b.iter(|| test::black_box(clock.now()));
+        }
+
+        #[bench]
+        fn monotonic(b: &mut test::Bencher) {
+            let clock = MonotonicClock::new();
+            b.iter(|| test::black_box(clock.now()));
+        }
+
+        #[bench]
+        fn tsc(b: &mut test::Bencher) {
+            let clock = TscClock::new();
+            b.iter(|| test::black_box(clock.now()));
+        }
+    }
+
+    mod stream {
+        extern crate eep;
+        extern crate test;
+
+        use self::eep::simple_trace::SimpleTrace;
+        use self::eep::stream::StreamSink;
+        use self::eep::traits::TraceSink;
+        use std::io;
+
+        #[bench]
+        fn trace(b: &mut test::Bencher) {
+            let mut sink = StreamSink::new(io::sink()).unwrap();
+            b.iter(|| sink.trace_event(SimpleTrace::FooEvent, None));
+            test::black_box(sink);
+        }
+    }
+
+    mod mmap_ring_buffer {
+        extern crate eep;
+        extern crate test;
+
+        use self::eep::mmap::MmapRingBuffer;
+        use self::eep::simple_trace::SimpleTrace;
+        use self::eep::traits::TraceSink;
+        use std::{env, fs, process};
+
+        #[bench]
+        fn trace(b: &mut test::Bencher) {
+            let path = env::temp_dir().join(format!("eep-bench-{}.eepm", process::id()));
+            let mut buffer = MmapRingBuffer::create(&path, 2 * 1024 * 1024).unwrap();
+            b.iter(|| buffer.trace_event(SimpleTrace::FooEvent, None));
+            test::black_box(buffer);
+            fs::remove_file(&path).unwrap();
+        }
     }
 
     mod toggle_sink_enabled {
@@ -97,6 +205,75 @@ mod benches {
         }
     }
 
+    mod filter_sink {
+        extern crate eep;
+        extern crate test;
+
+        use self::eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+        use self::eep::sink_combinators::FilterSink;
+        use self::eep::traits::{Trace, TraceSink};
+
+        #[bench]
+        fn enabled_tag(b: &mut test::Bencher) {
+            let mut sink = FilterSink::new_disabled(SimpleTraceBuffer::default(), 3);
+            sink.enable(SimpleTrace::FooEvent.tag());
+            b.iter(|| sink.trace_event(SimpleTrace::FooEvent, None));
+            test::black_box(sink);
+        }
+
+        #[bench]
+        fn disabled_tag(b: &mut test::Bencher) {
+            let mut sink = FilterSink::new_disabled(SimpleTraceBuffer::default(), 3);
+            sink.enable(SimpleTrace::OperationThing.tag());
+            b.iter(|| sink.trace_event(SimpleTrace::FooEvent, None));
+            test::black_box(sink);
+        }
+    }
+
+    mod sampling_sink {
+        extern crate eep;
+        extern crate test;
+
+        use self::eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+        use self::eep::sink_combinators::{SampleRate, SamplingSink};
+        use self::eep::traits::TraceSink;
+
+        #[bench]
+        fn every_nth(b: &mut test::Bencher) {
+            let mut sink = SamplingSink::new(SimpleTraceBuffer::default(),
+                                             SampleRate::EveryNth(100));
+            b.iter(|| sink.trace_event(SimpleTrace::FooEvent, None));
+            test::black_box(sink);
+        }
+
+        #[bench]
+        fn fraction(b: &mut test::Bencher) {
+            let mut sink = SamplingSink::new(SimpleTraceBuffer::default(),
+                                             SampleRate::Fraction(0.01));
+            b.iter(|| sink.trace_event(SimpleTrace::FooEvent, None));
+            test::black_box(sink);
+        }
+    }
+
+    mod rate_limit_sink {
+        extern crate eep;
+        extern crate test;
+
+        use self::eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+        use self::eep::sink_combinators::RateLimitSink;
+        use self::eep::traits::TraceSink;
+        use std::time::Duration;
+
+        #[bench]
+        fn over_limit(b: &mut test::Bencher) {
+            let mut sink = RateLimitSink::new(SimpleTraceBuffer::default(),
+                                              100,
+                                              Duration::from_secs(1));
+            b.iter(|| sink.trace_event(SimpleTrace::FooEvent, None));
+            test::black_box(sink);
+        }
+    }
+
     mod threaded_trace_id {
         extern crate eep;
         extern crate test;
@@ -0,0 +1,215 @@
+//! `#[derive(Trace)]` for `eep`.
+//!
+//! Deriving `eep::traits::Trace` for a fieldless enum generates `tag` and
+//! `label` implementations that are always in sync with each other:
+//!
+//! * Each variant's tag is its discriminant.
+//!
+//! * Each variant's label is its name, unless it is overridden with
+//!   `#[trace(label = "...")]`.
+//!
+//! * The `Id` type is `eep::ThreadedTraceId`, unless it is overridden on the
+//!   enum with `#[trace(id = "...")]`.
+//!
+//! ```
+//! #[macro_use]
+//! extern crate eep_derive;
+//! extern crate eep;
+//!
+//! #[derive(Clone, Copy, Debug, Trace)]
+//! #[trace(id = "eep::simple_trace::SimpleTraceId")]
+//! enum WebBrowserEngineTrace {
+//!     Compositing,
+//!     Painting,
+//!     #[trace(label = "Layout (reflow)")]
+//!     Layout,
+//! }
+//! ```
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use syn::spanned::Spanned;
+
+/// Derive `eep::traits::Trace` for a fieldless enum.
+///
+/// Each `#[trace(...)]` key may be given at most once, `id` only on the enum,
+/// and `label` only on its variants. These are all errors:
+///
+/// ```compile_fail
+/// # #[macro_use]
+/// # extern crate eep_derive;
+/// # extern crate eep;
+/// #[derive(Clone, Copy, Trace)]
+/// #[trace(id = "eep::ThreadedTraceId")]
+/// #[trace(id = "eep::simple_trace::SimpleTraceId")]
+/// enum Engine {
+///     A,
+/// }
+/// # fn main() {}
+/// ```
+///
+/// ```compile_fail
+/// # #[macro_use]
+/// # extern crate eep_derive;
+/// # extern crate eep;
+/// #[derive(Clone, Copy, Trace)]
+/// enum Engine {
+///     #[trace(label = "a", label = "b")]
+///     A,
+/// }
+/// # fn main() {}
+/// ```
+///
+/// ```compile_fail
+/// # #[macro_use]
+/// # extern crate eep_derive;
+/// # extern crate eep;
+/// #[derive(Clone, Copy, Trace)]
+/// #[trace(label = "a")]
+/// enum Engine {
+///     A,
+/// }
+/// # fn main() {}
+/// ```
+///
+/// ```compile_fail
+/// # #[macro_use]
+/// # extern crate eep_derive;
+/// # extern crate eep;
+/// #[derive(Clone, Copy, Trace)]
+/// enum Engine {
+///     #[trace(id = "eep::ThreadedTraceId")]
+///     A,
+/// }
+/// # fn main() {}
+/// ```
+#[proc_macro_derive(Trace, attributes(trace))]
+pub fn derive_trace(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as syn::DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(input: &syn::DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let data = match input.data {
+        syn::Data::Enum(ref data) => data,
+        _ => {
+            return Err(syn::Error::new(Span::call_site(),
+                                       "#[derive(Trace)] only supports fieldless enums"))
+        }
+    };
+
+    let id = match try!(trace_attribute(&input.attrs, "id")) {
+        Some(id) => try!(id.parse::<syn::Type>()),
+        None => syn::parse_quote!(::eep::ThreadedTraceId),
+    };
+
+    let name = &input.ident;
+    let mut tags = vec![];
+    let mut labels = vec![];
+
+    for variant in &data.variants {
+        match variant.fields {
+            syn::Fields::Unit => {}
+            _ => {
+                return Err(syn::Error::new(variant.span(),
+                                           "#[derive(Trace)] only supports fieldless enums"))
+            }
+        }
+
+        let variant_name = &variant.ident;
+        let label = match try!(trace_attribute(&variant.attrs, "label")) {
+            Some(label) => label,
+            None => syn::LitStr::new(&variant_name.to_string(), variant_name.span()),
+        };
+
+        tags.push(quote! {
+            #name::#variant_name => #name::#variant_name as u32
+        });
+        labels.push(quote! {
+            if tag == #name::#variant_name as u32 {
+                return #label;
+            }
+        });
+    }
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::eep::traits::Trace for #name #ty_generics #where_clause {
+            type Id = #id;
+
+            fn label(tag: u32) -> &'static str {
+                #(#labels)*
+                panic!("invalid tag for {}: {}", stringify!(#name), tag)
+            }
+
+            fn tag(&self) -> u32 {
+                match *self {
+                    #(#tags,)*
+                }
+            }
+        }
+    })
+}
+
+// Find the string value of `#[trace(key = "...")]` among `attrs`, if any.
+// `key` must be the only key among them, and may only be given once.
+fn trace_attribute(attrs: &[syn::Attribute], key: &str) -> syn::Result<Option<syn::LitStr>> {
+    let mut value = None;
+
+    for attr in attrs {
+        if !attr.path.is_ident("trace") {
+            continue;
+        }
+
+        let list = match try!(attr.parse_meta()) {
+            syn::Meta::List(list) => list,
+            meta => return Err(syn::Error::new(meta.span(), "expected #[trace(key = \"value\")]")),
+        };
+
+        for nested in list.nested {
+            let pair = match nested {
+                syn::NestedMeta::Meta(syn::Meta::NameValue(pair)) => pair,
+                nested => {
+                    return Err(syn::Error::new(nested.span(), "expected `key = \"value\"`"))
+                }
+            };
+
+            if !pair.path.is_ident("id") && !pair.path.is_ident("label") {
+                return Err(syn::Error::new(pair.path.span(),
+                                           "unknown trace attribute; expected `id` or `label`"));
+            }
+            if !pair.path.is_ident(key) {
+                let (misplaced, position) = if key == "id" {
+                    ("label", "variants")
+                } else {
+                    ("id", "the enum")
+                };
+                return Err(syn::Error::new(pair.path.span(),
+                                           format!("`{}` is only allowed on {}",
+                                                   misplaced,
+                                                   position)));
+            }
+            if value.is_some() {
+                return Err(syn::Error::new(pair.path.span(),
+                                           format!("duplicate `{}` trace attribute", key)));
+            }
+
+            match pair.lit {
+                syn::Lit::Str(s) => value = Some(s),
+                lit => return Err(syn::Error::new(lit.span(), "expected a string literal")),
+            }
+        }
+    }
+
+    Ok(value)
+}
@@ -0,0 +1,72 @@
+#[macro_use]
+extern crate eep_derive;
+extern crate eep;
+
+use eep::ThreadedTraceId;
+use eep::simple_trace::SimpleTraceId;
+use eep::traits::{Trace, TraceId};
+
+#[derive(Clone, Copy, Debug, Trace)]
+enum Defaults {
+    Compositing,
+    Painting,
+    Layout,
+}
+
+#[derive(Clone, Copy, Debug, Trace)]
+#[trace(id = "SimpleTraceId")]
+enum Overrides {
+    #[trace(label = "Image decoding")]
+    ImageDecoding,
+    DomEvent,
+}
+
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Trace)]
+enum Discriminants {
+    Timer = 5,
+    #[trace(label = "GC")]
+    GarbageCollection = 9,
+}
+
+fn id_of<T: Trace>(_: T) -> T::Id {
+    T::Id::new_id()
+}
+
+#[test]
+fn tags_and_labels_are_in_sync() {
+    for trace in &[Defaults::Compositing, Defaults::Painting, Defaults::Layout] {
+        assert_eq!(Defaults::label(trace.tag()), format!("{:?}", trace));
+    }
+    assert_eq!(Defaults::Compositing.tag(), 0);
+    assert_eq!(Defaults::Layout.tag(), 2);
+}
+
+#[test]
+fn label_overrides() {
+    assert_eq!(Overrides::label(Overrides::ImageDecoding.tag()), "Image decoding");
+    assert_eq!(Overrides::label(Overrides::DomEvent.tag()), "DomEvent");
+}
+
+#[test]
+fn explicit_discriminants_are_tags() {
+    assert_eq!(Discriminants::Timer.tag(), 5);
+    assert_eq!(Discriminants::GarbageCollection.tag(), 9);
+    assert_eq!(Discriminants::label(5), "Timer");
+    assert_eq!(Discriminants::label(9), "GC");
+}
+
+#[test]
+fn id_types() {
+    let id: ThreadedTraceId = id_of(Defaults::Painting);
+    assert!(id.thread().is_some());
+
+    let id: SimpleTraceId = id_of(Overrides::DomEvent);
+    assert!(id.thread().is_none());
+}
+
+#[test]
+#[should_panic]
+fn invalid_tag() {
+    Defaults::label(3);
+}
@@ -0,0 +1,394 @@
+//! Pairing starts with stops into spans, and reconstructing call trees.
+//!
+//! A `TraceKind::Start` entry and the `TraceKind::Stop` entry with the same
+//! thread and ID form a `Span`. Because a ring buffer evicts its oldest
+//! entries, a stop's start may be gone, and an operation that is still running
+//! has no stop yet; `pair` reports both kinds of orphans alongside the complete
+//! spans.
+//!
+//! ```
+//! use eep::analysis;
+//! use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+//! use eep::traits::TraceSink;
+//!
+//! let mut buffer = SimpleTraceBuffer::default();
+//! let thing = buffer.trace_start(SimpleTrace::OperationThing, None);
+//! let another = buffer.trace_start(SimpleTrace::OperationAnother, None);
+//! buffer.trace_stop(another, SimpleTrace::OperationAnother);
+//! buffer.trace_stop(thing, SimpleTrace::OperationThing);
+//!
+//! let pairing = analysis::pair(buffer.iter());
+//! assert_eq!(pairing.spans().len(), 2);
+//!
+//! let trees = pairing.trees();
+//! assert_eq!(trees.len(), 1);
+//! assert_eq!(trees[0].roots().len(), 1);
+//! assert_eq!(trees[0].roots()[0].children().len(), 1);
+//! ```
+
+use ring_buffer::{Entry, NsSinceEpoch, TraceKind};
+use std::collections::HashMap;
+use traits::{ThreadId, Trace};
+
+/// A completed operation: a start entry and its matching stop entry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span<T> {
+    start: Entry<T>,
+    stop: Entry<T>,
+}
+
+impl<T> Span<T> {
+    /// Get the entry that started this span.
+    pub fn start(&self) -> &Entry<T> {
+        &self.start
+    }
+
+    /// Get the entry that stopped this span.
+    pub fn stop(&self) -> &Entry<T> {
+        &self.stop
+    }
+
+    /// Get the time at which this span started.
+    pub fn begin(&self) -> NsSinceEpoch {
+        self.start.timestamp()
+    }
+
+    /// Get the time at which this span stopped.
+    pub fn end(&self) -> NsSinceEpoch {
+        self.stop.timestamp()
+    }
+
+    /// Get this span's duration, in nanoseconds.
+    pub fn duration(&self) -> u64 {
+        self.end().0.saturating_sub(self.begin().0)
+    }
+
+    /// Get the tag of this span's trace.
+    pub fn tag(&self) -> u32 {
+        self.start.tag()
+    }
+
+    /// Get the ID of this span.
+    pub fn id(&self) -> u32 {
+        self.start.id()
+    }
+
+    /// Get the thread that traced this span, if available.
+    pub fn thread(&self) -> Option<ThreadId> {
+        self.start.thread()
+    }
+}
+
+impl<T> Span<T>
+    where T: Trace
+{
+    /// Get the label of this span's trace.
+    pub fn label(&self) -> &'static str {
+        self.start.label()
+    }
+}
+
+/// The result of pairing a sequence of entries. See `pair`.
+#[derive(Clone, Debug)]
+pub struct Pairing<T> {
+    spans: Vec<Span<T>>,
+    events: Vec<Entry<T>>,
+    dropped: Vec<Entry<T>>,
+    orphaned_starts: Vec<Entry<T>>,
+    orphaned_stops: Vec<Entry<T>>,
+}
+
+/// Pair the starts and stops in a timestamp-ordered sequence of entries, such
+/// as a `RingBuffer::iter()`.
+///
+/// Starts and stops are matched by their thread and ID.
+pub fn pair<T, I>(entries: I) -> Pairing<T>
+    where I: IntoIterator<Item = Entry<T>>
+{
+    let mut open = HashMap::new();
+    let mut pairing = Pairing {
+        spans: vec![],
+        events: vec![],
+        dropped: vec![],
+        orphaned_starts: vec![],
+        orphaned_stops: vec![],
+    };
+
+    for entry in entries {
+        let key = (entry.thread(), entry.id());
+        match entry.kind() {
+            TraceKind::Event => pairing.events.push(entry),
+            TraceKind::Dropped => pairing.dropped.push(entry),
+            TraceKind::Start => {
+                // An ID that is reused before its first start was stopped
+                // leaves that first start without a stop.
+                if let Some(start) = open.insert(key, entry) {
+                    pairing.orphaned_starts.push(start);
+                }
+            }
+            TraceKind::Stop => {
+                match open.remove(&key) {
+                    Some(start) => {
+                        pairing.spans.push(Span {
+                            start: start,
+                            stop: entry,
+                        })
+                    }
+                    None => pairing.orphaned_stops.push(entry),
+                }
+            }
+        }
+    }
+
+    pairing.orphaned_starts.extend(open.into_iter().map(|(_, start)| start));
+    pairing.orphaned_starts.sort_by_key(|e| e.timestamp());
+    pairing.spans.sort_by_key(|s| s.begin());
+    pairing
+}
+
+impl<T> Pairing<T> {
+    /// Get the completed spans, ordered by the time they started.
+    pub fn spans(&self) -> &[Span<T>] {
+        &self.spans
+    }
+
+    /// Get the one-off `TraceKind::Event` entries.
+    pub fn events(&self) -> &[Entry<T>] {
+        &self.events
+    }
+
+    /// Get the `TraceKind::Dropped` entries, which record how many entries
+    /// were dropped before they reached the sink.
+    pub fn dropped(&self) -> &[Entry<T>] {
+        &self.dropped
+    }
+
+    /// Get the starts that have no matching stop, because their operation was
+    /// still running, ordered by timestamp.
+    pub fn orphaned_starts(&self) -> &[Entry<T>] {
+        &self.orphaned_starts
+    }
+
+    /// Get the stops that have no matching start, because it was evicted from
+    /// the ring buffer, ordered by timestamp.
+    pub fn orphaned_stops(&self) -> &[Entry<T>] {
+        &self.orphaned_stops
+    }
+
+    /// Reconstruct each thread's tree of spans.
+    ///
+    /// A span is the child of the innermost span on the same thread that
+    /// started before it and stopped after it. The trees are ordered by thread,
+    /// with spans that have no thread first.
+    pub fn trees(&self) -> Vec<ThreadTree<T>> {
+        let mut by_thread: HashMap<Option<ThreadId>, Vec<&Span<T>>> = HashMap::new();
+        for span in &self.spans {
+            by_thread.entry(span.thread()).or_insert_with(Vec::new).push(span);
+        }
+
+        let mut trees: Vec<_> = by_thread.into_iter()
+            .map(|(thread, mut spans)| {
+                // Outer spans sort before the inner spans that start at the
+                // same time.
+                spans.sort_by(|a, b| a.begin().cmp(&b.begin()).then(b.end().cmp(&a.end())));
+                ThreadTree {
+                    thread: thread,
+                    roots: nest(spans),
+                }
+            })
+            .collect();
+        trees.sort_by_key(|tree| tree.thread.map(|t| t.0));
+        trees
+    }
+}
+
+// Nest spans that are sorted by ascending start and descending stop.
+fn nest<T>(spans: Vec<&Span<T>>) -> Vec<SpanNode<T>> {
+    let mut roots = vec![];
+    let mut stack: Vec<SpanNode<T>> = vec![];
+
+    for span in spans {
+        while stack.last().map_or(false, |parent| parent.span.end() < span.end()) {
+            let done = stack.pop().unwrap();
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(done),
+                None => roots.push(done),
+            }
+        }
+        stack.push(SpanNode {
+            span: span,
+            children: vec![],
+        });
+    }
+
+    while let Some(done) = stack.pop() {
+        match stack.last_mut() {
+            Some(parent) => parent.children.push(done),
+            None => roots.push(done),
+        }
+    }
+
+    roots
+}
+
+/// One thread's tree of spans. See `Pairing::trees`.
+#[derive(Debug)]
+pub struct ThreadTree<'a, T>
+    where T: 'a
+{
+    thread: Option<ThreadId>,
+    roots: Vec<SpanNode<'a, T>>,
+}
+
+impl<'a, T> ThreadTree<'a, T> {
+    /// Get the thread, or `None` for spans that have no thread.
+    pub fn thread(&self) -> Option<ThreadId> {
+        self.thread
+    }
+
+    /// Get the outermost spans, ordered by the time they started.
+    pub fn roots(&self) -> &[SpanNode<'a, T>] {
+        &self.roots
+    }
+}
+
+/// A span and the spans nested within it.
+#[derive(Debug)]
+pub struct SpanNode<'a, T>
+    where T: 'a
+{
+    span: &'a Span<T>,
+    children: Vec<SpanNode<'a, T>>,
+}
+
+impl<'a, T> SpanNode<'a, T> {
+    /// Get this node's span.
+    pub fn span(&self) -> &'a Span<T> {
+        self.span
+    }
+
+    /// Get the spans directly nested within this one, ordered by the time they
+    /// started.
+    pub fn children(&self) -> &[SpanNode<'a, T>] {
+        &self.children
+    }
+
+    /// Get the time spent in this span but not in any of its children, in
+    /// nanoseconds.
+    pub fn self_time(&self) -> u64 {
+        let children: u64 = self.children.iter().map(|c| c.span.duration()).sum();
+        self.span.duration().saturating_sub(children)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring_buffer::{Entry, NsSinceEpoch, TraceKind};
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
+    use traits::{ThreadId, TraceSink};
+
+    fn entry(kind: TraceKind, thread: usize, id: u32, timestamp: u64) -> Entry<SimpleTrace> {
+        Entry::new(kind, id % 3, id, Some(ThreadId(thread)), None, NsSinceEpoch(timestamp))
+    }
+
+    #[test]
+    fn pairs_starts_and_stops() {
+        let entries = vec![entry(TraceKind::Start, 1, 0, 10),
+                           entry(TraceKind::Start, 2, 0, 12),
+                           entry(TraceKind::Event, 1, 1, 13),
+                           entry(TraceKind::Stop, 2, 0, 20),
+                           entry(TraceKind::Stop, 1, 0, 30)];
+
+        let pairing = pair(entries);
+        assert_eq!(pairing.spans().len(), 2);
+        assert_eq!(pairing.spans()[0].thread(), Some(ThreadId(1)));
+        assert_eq!(pairing.spans()[0].duration(), 20);
+        assert_eq!(pairing.spans()[1].thread(), Some(ThreadId(2)));
+        assert_eq!(pairing.spans()[1].duration(), 8);
+        assert_eq!(pairing.events().len(), 1);
+        assert!(pairing.orphaned_starts().is_empty());
+        assert!(pairing.orphaned_stops().is_empty());
+    }
+
+    #[test]
+    fn reports_orphans() {
+        let entries = vec![entry(TraceKind::Stop, 1, 0, 10),
+                           entry(TraceKind::Start, 1, 1, 11),
+                           entry(TraceKind::Start, 1, 2, 12),
+                           entry(TraceKind::Stop, 1, 2, 13)];
+
+        let pairing = pair(entries);
+        assert_eq!(pairing.spans().len(), 1);
+        assert_eq!(pairing.spans()[0].id(), 2);
+        assert_eq!(pairing.orphaned_stops().iter().map(|e| e.id()).collect::<Vec<_>>(),
+                   vec![0]);
+        assert_eq!(pairing.orphaned_starts().iter().map(|e| e.id()).collect::<Vec<_>>(),
+                   vec![1]);
+    }
+
+    #[test]
+    fn orphans_from_eviction() {
+        let mut buffer = SimpleTraceBuffer::new(200);
+        let outer = buffer.trace_start(SimpleTrace::OperationThing, None);
+        for _ in 0..100 {
+            let id = buffer.trace_start(SimpleTrace::OperationAnother, None);
+            buffer.trace_stop(id, SimpleTrace::OperationAnother);
+        }
+        buffer.trace_stop(outer, SimpleTrace::OperationThing);
+        let running = buffer.trace_start(SimpleTrace::OperationThing, None);
+
+        let pairing = pair(buffer.iter());
+        assert!(!pairing.spans().is_empty());
+        assert_eq!(pairing.orphaned_stops().last().map(|e| e.id()), Some(outer.0));
+        assert_eq!(pairing.orphaned_starts().iter().map(|e| e.id()).collect::<Vec<_>>(),
+                   vec![running.0]);
+    }
+
+    #[test]
+    fn builds_trees() {
+        // Thread 1: 0 [10, 100] contains 1 [20, 50] and 3 [60, 90]; 1 contains
+        // 2 [30, 40]. Thread 2: 4 [15, 25].
+        let entries = vec![entry(TraceKind::Start, 1, 0, 10),
+                           entry(TraceKind::Start, 2, 4, 15),
+                           entry(TraceKind::Start, 1, 1, 20),
+                           entry(TraceKind::Stop, 2, 4, 25),
+                           entry(TraceKind::Start, 1, 2, 30),
+                           entry(TraceKind::Stop, 1, 2, 40),
+                           entry(TraceKind::Stop, 1, 1, 50),
+                           entry(TraceKind::Start, 1, 3, 60),
+                           entry(TraceKind::Stop, 1, 3, 90),
+                           entry(TraceKind::Stop, 1, 0, 100)];
+
+        let pairing = pair(entries);
+        let trees = pairing.trees();
+        assert_eq!(trees.len(), 2);
+
+        assert_eq!(trees[0].thread(), Some(ThreadId(1)));
+        let roots = trees[0].roots();
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].span().id(), 0);
+        assert_eq!(roots[0].children().iter().map(|c| c.span().id()).collect::<Vec<_>>(),
+                   vec![1, 3]);
+        assert_eq!(roots[0].children()[0].children()[0].span().id(), 2);
+        assert_eq!(roots[0].self_time(), 90 - 30 - 30);
+        assert_eq!(roots[0].children()[0].self_time(), 30 - 10);
+
+        assert_eq!(trees[1].thread(), Some(ThreadId(2)));
+        assert_eq!(trees[1].roots().len(), 1);
+        assert_eq!(trees[1].roots()[0].span().id(), 4);
+    }
+
+    #[test]
+    fn sibling_roots() {
+        let entries = vec![entry(TraceKind::Start, 1, 0, 10),
+                           entry(TraceKind::Stop, 1, 0, 20),
+                           entry(TraceKind::Start, 1, 1, 20),
+                           entry(TraceKind::Stop, 1, 1, 30)];
+
+        let pairing = pair(entries);
+        let trees = pairing.trees();
+        assert_eq!(trees[0].roots().iter().map(|r| r.span().id()).collect::<Vec<_>>(),
+                   vec![0, 1]);
+    }
+}
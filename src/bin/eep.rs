@@ -0,0 +1,422 @@
+//! `eep`: inspect and convert saved trace dumps.
+//!
+//! Dumps are either the JSON that `RingBuffer`'s `Serialize` implementation
+//! emits, the binary streams that `StreamSink` writes, or the files that
+//! `MmapRingBuffer` maps.
+//!
+//! Build with `--features cli`.
+
+extern crate eep;
+extern crate serde;
+extern crate serde_json;
+
+use eep::causality::CausalGraph;
+use eep::chrome_trace::ChromeTrace;
+use eep::loaded_trace::LoadedTrace;
+use eep::mmap::{self, MmapReader};
+use eep::registry;
+use eep::ring_buffer::{Entry, NsSinceEpoch};
+use eep::stats;
+use eep::stream::{self, StreamReader};
+use eep::traits::ThreadId;
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process;
+
+const USAGE: &'static str = "\
+Usage:
+    eep summary <dump>...
+    eep convert [--format chrome|dot] [-o <out>] <dump>
+    eep filter [--label <label>]... [--thread <id>]... [--from <ns>] [--to <ns>]
+               [-o <out>] <dump>
+    eep merge [-o <out>] <dump>...
+
+Dumps are the JSON serialization of a `RingBuffer`, a `StreamSink`'s binary
+stream, or an `MmapRingBuffer`'s file. Use `-` to read a dump from stdin.
+Output goes to stdout unless `-o` is given.
+
+Subcommands:
+    summary    Print per-label span statistics, with durations in nanoseconds,
+               and the fraction of traces that were recorded if the dump was
+               sampled. Warns if entries were lost because the buffer was
+               full.
+    convert    Convert a dump to the Chrome Trace Event Format (the default),
+               or its causal graph to Graphviz DOT.
+    filter     Keep the entries with any of the given labels or threads, within
+               the given time range, in nanoseconds since the epoch.
+    merge      Merge several dumps into a single timeline.
+";
+
+// The entries of a dump are untyped; their labels come from the dump itself.
+#[derive(Copy, Clone, Debug)]
+enum Dump {}
+
+type Trace = LoadedTrace<Dump>;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        let _ = writeln!(io::stderr(), "eep: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let (command, args) = match args.split_first() {
+        Some((command, args)) => (command, args),
+        None => return Err(format!("missing subcommand\n\n{}", USAGE)),
+    };
+    let options = try!(Options::parse(args));
+
+    match &command[..] {
+        "summary" => summary(&options),
+        "convert" => convert(&options),
+        "filter" => filter(&options),
+        "merge" => merge(&options),
+        "help" | "-h" | "--help" => {
+            print!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(format!("unknown subcommand `{}`\n\n{}", command, USAGE)),
+    }
+}
+
+// The options that any subcommand may take.
+#[derive(Debug, Default)]
+struct Options {
+    format: Option<String>,
+    output: Option<String>,
+    labels: Vec<String>,
+    threads: Vec<usize>,
+    from: Option<u64>,
+    to: Option<u64>,
+    dumps: Vec<String>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Options, String> {
+        fn number<T: ::std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+            value.parse().map_err(|_| format!("invalid number for {}: `{}`", flag, value))
+        }
+
+        let mut options = Options::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let flag = &arg[..];
+            if flag == "-" || !flag.starts_with('-') {
+                options.dumps.push(arg.clone());
+                continue;
+            }
+
+            let value = try!(args.next().ok_or_else(|| format!("missing value for {}", flag)));
+            match flag {
+                "--format" => options.format = Some(value.clone()),
+                "-o" | "--output" => options.output = Some(value.clone()),
+                "--label" => options.labels.push(value.clone()),
+                "--thread" => options.threads.push(try!(number(flag, value))),
+                "--from" => options.from = Some(try!(number(flag, value))),
+                "--to" => options.to = Some(try!(number(flag, value))),
+                _ => return Err(format!("unknown option `{}`\n\n{}", flag, USAGE)),
+            }
+        }
+        Ok(options)
+    }
+
+    fn single_dump(&self) -> Result<Trace, String> {
+        match self.dumps.len() {
+            1 => load(&self.dumps[0]),
+            0 => Err(format!("missing dump\n\n{}", USAGE)),
+            _ => Err(format!("expected a single dump\n\n{}", USAGE)),
+        }
+    }
+
+    fn all_dumps(&self) -> Result<Vec<Trace>, String> {
+        if self.dumps.is_empty() {
+            return Err(format!("missing dump\n\n{}", USAGE));
+        }
+        self.dumps.iter().map(|path| load(path)).collect()
+    }
+
+    fn output(&self) -> Result<Box<Write>, String> {
+        match self.output {
+            Some(ref path) => {
+                let file = try!(File::create(path).map_err(|e| format!("{}: {}", path, e)));
+                Ok(Box::new(file))
+            }
+            None => Ok(Box::new(io::stdout())),
+        }
+    }
+}
+
+fn load(path: &str) -> Result<Trace, String> {
+    let mut contents = vec![];
+    let read = if path == "-" {
+        io::stdin().read_to_end(&mut contents)
+    } else {
+        File::open(path).and_then(|mut file| file.read_to_end(&mut contents))
+    };
+    try!(read.map_err(|e| format!("{}: {}", path, e)));
+
+    if contents.starts_with(stream::MAGIC) {
+        let reader = try!(StreamReader::new(&contents[..]).map_err(|e| format!("{}: {}", path, e)));
+        reader.into_loaded_trace().map_err(|e| format!("{}: {}", path, e))
+    } else if contents.starts_with(mmap::MAGIC) {
+        let reader = try!(MmapReader::from_bytes(&contents).map_err(|e| format!("{}: {}", path, e)));
+        reader.into_loaded_trace().map_err(|e| format!("{}: {}", path, e))
+    } else {
+        serde_json::from_slice(&contents).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+fn write_json<T>(options: &Options, value: &T) -> Result<(), String>
+    where T: serde::Serialize
+{
+    let mut output = try!(options.output());
+    try!(serde_json::to_writer(&mut output, value).map_err(|e| e.to_string()));
+    writeln!(output).map_err(|e| e.to_string())
+}
+
+fn summary(options: &Options) -> Result<(), String> {
+    let trace = try!(merged(try!(options.all_dumps())));
+    let summary = stats::summarize(trace.entries().iter().cloned())
+        .with_sample_rates(trace.sample_rates());
+
+    if let Some(counters) = trace.counters() {
+        if counters.is_truncated() {
+            let _ = writeln!(io::stderr(),
+                             "eep: warning: the capture is truncated: {} of {} entries were \
+                              evicted, {} dropped and {} flushed",
+                             counters.evicted(),
+                             counters.written() + counters.dropped(),
+                             counters.dropped(),
+                             counters.flushed());
+        }
+    }
+
+    let mut output = try!(options.output());
+    write!(output, "{}", summary.with_labels(trace.labels())).map_err(|e| e.to_string())
+}
+
+fn convert(options: &Options) -> Result<(), String> {
+    let trace = try!(options.single_dump());
+
+    match options.format.as_ref().map_or("chrome", |f| &f[..]) {
+        "chrome" => {
+            let chrome = ChromeTrace::new(trace.entries().iter().cloned());
+            write_json(options, &chrome.with_labels(trace.labels()))
+        }
+        "dot" => {
+            let graph = CausalGraph::new(trace.entries().iter().cloned());
+            let mut output = try!(options.output());
+            graph.write_dot_with_labels(&mut output, trace.labels()).map_err(|e| e.to_string())
+        }
+        format => Err(format!("unknown format `{}`", format)),
+    }
+}
+
+fn filter(options: &Options) -> Result<(), String> {
+    let trace = try!(options.single_dump());
+    let filtered = filter_entries(&trace, options);
+    write_json(options, &filtered)
+}
+
+fn filter_entries(trace: &Trace, options: &Options) -> Trace {
+    let keep = |entry: &Entry<Dump>| {
+        let label = trace.label(entry.tag()).unwrap_or("");
+        (options.labels.is_empty() || options.labels.iter().any(|l| l == label)) &&
+        (options.threads.is_empty() ||
+         options.threads.iter().any(|&t| entry.thread() == Some(ThreadId(t)))) &&
+        options.from.map_or(true, |from| entry.timestamp() >= NsSinceEpoch(from)) &&
+        options.to.map_or(true, |to| entry.timestamp() <= NsSinceEpoch(to))
+    };
+
+    let entries: Vec<_> = trace.entries().iter().cloned().filter(|e| keep(e)).collect();
+    let labels: HashMap<_, _> = trace.labels()
+        .iter()
+        .filter(|&(tag, _)| entries.iter().any(|e| e.tag() == *tag))
+        .map(|(&tag, label)| (tag, label.clone()))
+        .collect();
+    let sample_rates = trace.sample_rates()
+        .iter()
+        .filter(|&(tag, _)| labels.contains_key(tag))
+        .map(|(&tag, &rate)| (tag, rate))
+        .collect();
+    let filtered = LoadedTrace::new(trace.time_base(), labels, entries)
+        .with_sample_rates(sample_rates);
+    match trace.counters() {
+        Some(counters) => filtered.with_counters(counters),
+        None => filtered,
+    }
+}
+
+fn merge(options: &Options) -> Result<(), String> {
+    let merged = try!(merged(try!(options.all_dumps())));
+    write_json(options, &merged)
+}
+
+fn merged(mut traces: Vec<Trace>) -> Result<Trace, String> {
+    if traces.len() == 1 {
+        return Ok(traces.pop().unwrap());
+    }
+
+    let mut labels: HashMap<u32, String> = HashMap::new();
+    for trace in &traces {
+        for (&tag, label) in trace.labels() {
+            if let Some(existing) = labels.insert(tag, label.clone()) {
+                if existing != *label {
+                    return Err(format!("tag {} is labeled both `{}` and `{}`",
+                                       tag,
+                                       existing,
+                                       label));
+                }
+            }
+        }
+    }
+
+    let mut sample_rates: HashMap<u32, f64> = HashMap::new();
+    for trace in &traces {
+        for (&tag, &rate) in trace.sample_rates() {
+            if let Some(existing) = sample_rates.insert(tag, rate) {
+                if existing != rate {
+                    return Err(format!("tag {} is sampled at both {} and {}",
+                                       tag,
+                                       existing,
+                                       rate));
+                }
+            }
+        }
+    }
+
+    let counters = traces.iter().filter_map(|t| t.counters()).fold(None, |sum, counters| {
+        Some(sum.map_or(counters, |sum| sum + counters))
+    });
+
+    let entries = registry::merge(traces.into_iter().map(|t| t.into_entries().into_iter()))
+        .collect();
+    // Each dump's entries were already mapped to wall-clock time with its own
+    // time base, so the merged trace has none.
+    let merged = LoadedTrace::new(None, labels, entries).with_sample_rates(sample_rates);
+    Ok(match counters {
+        Some(counters) => merged.with_counters(counters),
+        None => merged,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eep::clock::WallClock;
+    use eep::mmap::MmapRingBuffer;
+    use eep::ring_buffer::TraceKind;
+    use eep::simple_trace::SimpleTrace;
+    use eep::stream::StreamSink;
+    use eep::traits::TraceSink;
+    use std::env;
+    use std::fs;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn trace(thread: usize, timestamps: &[u64]) -> Trace {
+        let mut labels = HashMap::new();
+        labels.insert(0, "Foo".to_string());
+        labels.insert(1, "Bar".to_string());
+        let entries = timestamps.iter()
+            .enumerate()
+            .map(|(i, &ts)| {
+                Entry::new(TraceKind::Event,
+                           i as u32 % 2,
+                           i as u32,
+                           Some(ThreadId(thread)),
+                           None,
+                           NsSinceEpoch(ts))
+            })
+            .collect();
+        LoadedTrace::new(None, labels, entries)
+    }
+
+    #[test]
+    fn parses_options() {
+        let options = Options::parse(&strings(&["--label", "Foo", "a.json", "--thread", "3",
+                                                "--from", "10", "-o", "out.json", "-"]))
+            .unwrap();
+        assert_eq!(options.labels, vec!["Foo"]);
+        assert_eq!(options.threads, vec![3]);
+        assert_eq!(options.from, Some(10));
+        assert_eq!(options.to, None);
+        assert_eq!(options.output, Some("out.json".to_string()));
+        assert_eq!(options.dumps, vec!["a.json", "-"]);
+
+        assert!(Options::parse(&strings(&["--thread", "x"])).is_err());
+        assert!(Options::parse(&strings(&["--label"])).is_err());
+        assert!(Options::parse(&strings(&["--bogus", "1"])).is_err());
+    }
+
+    #[test]
+    fn filters_entries() {
+        let trace = trace(1, &[10, 20, 30, 40]);
+
+        let options = Options::parse(&strings(&["--label", "Foo"])).unwrap();
+        let filtered = filter_entries(&trace, &options);
+        assert_eq!(filtered.entries().iter().map(|e| e.id()).collect::<Vec<_>>(),
+                   vec![0, 2]);
+        assert_eq!(filtered.labels().len(), 1);
+
+        let options = Options::parse(&strings(&["--from", "20", "--to", "30"])).unwrap();
+        let filtered = filter_entries(&trace, &options);
+        assert_eq!(filtered.entries().iter().map(|e| e.id()).collect::<Vec<_>>(),
+                   vec![1, 2]);
+
+        let options = Options::parse(&strings(&["--thread", "2"])).unwrap();
+        assert!(filter_entries(&trace, &options).entries().is_empty());
+    }
+
+    #[test]
+    fn merges_dumps() {
+        let merged = merged(vec![trace(1, &[10, 30]), trace(2, &[20, 40])]).unwrap();
+        let threads: Vec<_> = merged.entries().iter().map(|e| e.thread().unwrap().0).collect();
+        assert_eq!(threads, vec![1, 2, 1, 2]);
+
+        let mut conflicting = trace(2, &[20]);
+        conflicting = LoadedTrace::new(None,
+                                       Some((0, "Baz".to_string())).into_iter().collect(),
+                                       conflicting.into_entries());
+        assert!(super::merged(vec![trace(1, &[10]), conflicting]).is_err());
+    }
+
+    #[test]
+    fn loads_streams() {
+        let path = env::temp_dir().join(format!("eep-cli-test-{}.eeps", process::id()));
+        {
+            let file = File::create(&path).unwrap();
+            let mut sink = StreamSink::with_clock(file, WallClock).unwrap();
+            let id = sink.trace_start(SimpleTrace::OperationThing, None);
+            sink.trace_stop(id, SimpleTrace::OperationThing);
+        }
+
+        let loaded = load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.entries().len(), 2);
+        assert_eq!(loaded.label(loaded.entries()[0].tag()), Some("Thing"));
+    }
+
+    #[test]
+    fn loads_mmap_files() {
+        let path = env::temp_dir().join(format!("eep-cli-test-{}.eepm", process::id()));
+        {
+            let mut buffer = MmapRingBuffer::create_with_clock(&path, 4096, WallClock).unwrap();
+            let id = buffer.trace_start(SimpleTrace::OperationThing, None);
+            buffer.trace_stop(id, SimpleTrace::OperationThing);
+        }
+
+        let loaded = load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.entries().len(), 2);
+        assert_eq!(loaded.label(loaded.entries()[0].tag()), Some("Thing"));
+    }
+}
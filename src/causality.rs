@@ -0,0 +1,373 @@
+//! The causal graph of traces, built from their `why` links.
+//!
+//! Every event and every operation is a node, and each `Entry::why()` link is
+//! an edge from the causing node to the caused one. Since every trace has at
+//! most one cause, the graph is a forest, and it spans threads whenever a trace
+//! on one thread causes a trace on another.
+//!
+//! ```
+//! use eep::causality::CausalGraph;
+//! use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+//! use eep::traits::TraceSink;
+//!
+//! let mut buffer = SimpleTraceBuffer::default();
+//! let event = buffer.trace_event(SimpleTrace::FooEvent, None);
+//! let thing = buffer.trace_start(SimpleTrace::OperationThing, Some(event));
+//! let another = buffer.trace_start(SimpleTrace::OperationAnother, Some(thing));
+//! buffer.trace_stop(another, SimpleTrace::OperationAnother);
+//! buffer.trace_stop(thing, SimpleTrace::OperationThing);
+//!
+//! let graph = CausalGraph::new(buffer.iter());
+//! let node = graph.find(None, another.0).unwrap();
+//! let chain: Vec<_> = graph.cause_chain(node)
+//!     .into_iter()
+//!     .map(|n| graph.node(n).entry().id())
+//!     .collect();
+//! assert_eq!(chain, vec![event.0, thing.0, another.0]);
+//!
+//! let mut dot = vec![];
+//! graph.write_dot(&mut dot).unwrap();
+//! ```
+
+use ring_buffer::{Entry, NsSinceEpoch, TraceKind};
+use std::collections::HashMap;
+use std::io;
+use traits::{ThreadId, Trace};
+
+/// A traced event or operation in a `CausalGraph`.
+#[derive(Clone, Debug)]
+pub struct Node<T> {
+    entry: Entry<T>,
+    stop: Option<Entry<T>>,
+    cause: Option<usize>,
+    effects: Vec<usize>,
+}
+
+impl<T> Node<T> {
+    /// Get the `TraceKind::Event` or `TraceKind::Start` entry of this node.
+    pub fn entry(&self) -> &Entry<T> {
+        &self.entry
+    }
+
+    /// Get the entry that stopped this node's operation, if it is an operation
+    /// and it has stopped.
+    pub fn stop(&self) -> Option<&Entry<T>> {
+        self.stop.as_ref()
+    }
+
+    /// Get the time at which this node's event happened or its operation
+    /// started.
+    pub fn begin(&self) -> NsSinceEpoch {
+        self.entry.timestamp()
+    }
+
+    /// Get the time at which this node's event happened or its operation
+    /// stopped. Operations that have not stopped end when they begin.
+    pub fn end(&self) -> NsSinceEpoch {
+        self.stop.as_ref().unwrap_or(&self.entry).timestamp()
+    }
+
+    /// Get the time between this node's beginning and end, in nanoseconds.
+    pub fn duration(&self) -> u64 {
+        self.end().0.saturating_sub(self.begin().0)
+    }
+
+    /// Get the index of the node that caused this one, if it is in the graph.
+    pub fn cause(&self) -> Option<usize> {
+        self.cause
+    }
+
+    /// Get the indices of the nodes that this one caused, ordered by the time
+    /// they began.
+    pub fn effects(&self) -> &[usize] {
+        &self.effects
+    }
+
+    /// Return `true` if this node's entry has a `why`, but the node it refers
+    /// to is not in the graph, for example because it was evicted from the ring
+    /// buffer.
+    pub fn is_missing_cause(&self) -> bool {
+        self.entry.why().is_some() && self.cause.is_none()
+    }
+}
+
+/// The causal graph of a sequence of entries.
+///
+/// Nodes are identified by their index, in the order their entries appeared.
+#[derive(Clone, Debug)]
+pub struct CausalGraph<T> {
+    nodes: Vec<Node<T>>,
+    index: HashMap<(Option<ThreadId>, u32), usize>,
+}
+
+impl<T> CausalGraph<T> {
+    /// Build the causal graph of a timestamp-ordered sequence of entries, such
+    /// as a `RingBuffer::iter()`.
+    pub fn new<I>(entries: I) -> CausalGraph<T>
+        where I: IntoIterator<Item = Entry<T>>
+    {
+        let mut graph = CausalGraph {
+            nodes: vec![],
+            index: HashMap::new(),
+        };
+
+        for entry in entries {
+            let key = (entry.thread(), entry.id());
+            match entry.kind() {
+                TraceKind::Event | TraceKind::Start => {
+                    graph.index.insert(key, graph.nodes.len());
+                    graph.nodes.push(Node {
+                        entry: entry,
+                        stop: None,
+                        cause: None,
+                        effects: vec![],
+                    });
+                }
+                TraceKind::Stop => {
+                    if let Some(&i) = graph.index.get(&key) {
+                        let node = &mut graph.nodes[i];
+                        if node.entry.kind() == TraceKind::Start && node.stop.is_none() {
+                            node.stop = Some(entry);
+                        }
+                    }
+                }
+                // Dropped entries record entries that never made it into the
+                // trace, so they cannot cause anything.
+                TraceKind::Dropped => {}
+            }
+        }
+
+        for i in 0..graph.nodes.len() {
+            let cause = graph.nodes[i]
+                .entry
+                .why()
+                .and_then(|why| graph.index.get(&why).cloned())
+                // A cause must have begun before its effect, which also rules
+                // out cycles.
+                .filter(|&c| c < i);
+            if let Some(c) = cause {
+                graph.nodes[i].cause = Some(c);
+                graph.nodes[c].effects.push(i);
+            }
+        }
+
+        graph
+    }
+
+    /// Get every node.
+    pub fn nodes(&self) -> &[Node<T>] {
+        &self.nodes
+    }
+
+    /// Get the node with the given index.
+    pub fn node(&self, index: usize) -> &Node<T> {
+        &self.nodes[index]
+    }
+
+    /// Find the index of the node with the given thread and ID.
+    pub fn find(&self, thread: Option<ThreadId>, id: u32) -> Option<usize> {
+        self.index.get(&(thread, id)).cloned()
+    }
+
+    /// Get the indices of the nodes that have no cause in the graph.
+    pub fn roots(&self) -> Vec<usize> {
+        (0..self.nodes.len()).filter(|&i| self.nodes[i].cause.is_none()).collect()
+    }
+
+    /// Get the chain of nodes that caused the given node, from its root cause
+    /// down to and including the node itself.
+    pub fn cause_chain(&self, index: usize) -> Vec<usize> {
+        let mut chain = vec![index];
+        let mut node = &self.nodes[index];
+        while let Some(cause) = node.cause {
+            chain.push(cause);
+            node = &self.nodes[cause];
+        }
+        chain.reverse();
+        chain
+    }
+
+    /// Get the critical path of the causal chains that begin at the given
+    /// node: the path of effects that leads to the last of them to end.
+    ///
+    /// The path starts at the given node and, at each step, follows the effect
+    /// whose own chain of effects ends last.
+    pub fn critical_path(&self, index: usize) -> Vec<usize> {
+        // The time at which the last node caused by each node ends, computed
+        // in reverse order, since effects always come after their causes.
+        let mut finish: Vec<NsSinceEpoch> = self.nodes.iter().map(|n| n.end()).collect();
+        for i in (index..self.nodes.len()).rev() {
+            if let Some(c) = self.nodes[i].cause {
+                if finish[i] > finish[c] {
+                    finish[c] = finish[i];
+                }
+            }
+        }
+
+        let mut path = vec![index];
+        let mut node = &self.nodes[index];
+        while let Some(&next) = node.effects.iter().max_by_key(|&&e| finish[e]) {
+            path.push(next);
+            node = &self.nodes[next];
+        }
+        path
+    }
+
+    /// Write the graph in the Graphviz DOT language, using the given labels for
+    /// tags instead of `Trace::label`.
+    pub fn write_dot_with_labels<W>(&self,
+                                    w: &mut W,
+                                    labels: &HashMap<u32, String>)
+                                    -> io::Result<()>
+        where W: io::Write
+    {
+        self.write_dot_impl(w, |tag| {
+            labels.get(&tag).cloned().unwrap_or_else(|| format!("<tag {}>", tag))
+        })
+    }
+
+    fn write_dot_impl<W, F>(&self, w: &mut W, label: F) -> io::Result<()>
+        where W: io::Write,
+              F: Fn(u32) -> String
+    {
+        try!(writeln!(w, "digraph eep {{"));
+        for (i, node) in self.nodes.iter().enumerate() {
+            let mut text = label(node.entry.tag()).replace('\\', "\\\\").replace('"', "\\\"");
+            if let Some(thread) = node.entry.thread() {
+                text.push_str(&format!("\\nthread {}", thread.0));
+            }
+            if node.stop.is_some() {
+                text.push_str(&format!("\\n{} ns", node.duration()));
+            }
+            let shape = match node.entry.kind() {
+                TraceKind::Start => "box",
+                _ => "ellipse",
+            };
+            try!(writeln!(w, "    n{} [label=\"{}\", shape={}];", i, text, shape));
+        }
+        for (i, node) in self.nodes.iter().enumerate() {
+            for effect in &node.effects {
+                try!(writeln!(w, "    n{} -> n{};", i, effect));
+            }
+        }
+        writeln!(w, "}}")
+    }
+}
+
+impl<T> CausalGraph<T>
+    where T: Trace
+{
+    /// Write the graph in the Graphviz DOT language.
+    ///
+    /// Events are drawn as ellipses and operations as boxes, with an edge from
+    /// each node to every node it caused.
+    pub fn write_dot<W>(&self, w: &mut W) -> io::Result<()>
+        where W: io::Write
+    {
+        self.write_dot_impl(w, |tag| T::label(tag).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring_buffer::{Entry, NsSinceEpoch, TraceKind};
+    use simple_trace::SimpleTrace;
+    use std::collections::HashMap;
+    use traits::ThreadId;
+
+    fn entry(kind: TraceKind,
+             thread: usize,
+             id: u32,
+             why: Option<(usize, u32)>,
+             timestamp: u64)
+             -> Entry<SimpleTrace> {
+        Entry::new(kind,
+                   0,
+                   id,
+                   Some(ThreadId(thread)),
+                   why.map(|(t, id)| (Some(ThreadId(t)), id)),
+                   NsSinceEpoch(timestamp))
+    }
+
+    // Thread 1's event 0 causes thread 1's operation 1 [10, 50] and thread 2's
+    // operation 1 [20, 90]. Thread 2's operation causes event 2 on thread 1.
+    // Thread 1's operation 3 was caused by an evicted trace.
+    fn entries() -> Vec<Entry<SimpleTrace>> {
+        vec![entry(TraceKind::Event, 1, 0, None, 0),
+             entry(TraceKind::Start, 1, 1, Some((1, 0)), 10),
+             entry(TraceKind::Start, 2, 1, Some((1, 0)), 20),
+             entry(TraceKind::Event, 1, 2, Some((2, 1)), 30),
+             entry(TraceKind::Stop, 1, 1, None, 50),
+             entry(TraceKind::Start, 1, 3, Some((3, 9)), 60),
+             entry(TraceKind::Stop, 2, 1, None, 90)]
+    }
+
+    #[test]
+    fn links_across_threads() {
+        let graph = CausalGraph::new(entries());
+        assert_eq!(graph.nodes().len(), 5);
+        assert_eq!(graph.roots(), vec![0, 4]);
+        assert_eq!(graph.node(0).effects(), &[1, 2]);
+        assert_eq!(graph.node(2).effects(), &[3]);
+        assert_eq!(graph.node(2).end(), NsSinceEpoch(90));
+        assert!(graph.node(4).is_missing_cause());
+        assert!(!graph.node(0).is_missing_cause());
+    }
+
+    #[test]
+    fn cause_chain() {
+        let graph = CausalGraph::new(entries());
+        let event = graph.find(Some(ThreadId(1)), 2).unwrap();
+        assert_eq!(graph.cause_chain(event), vec![0, 2, 3]);
+        assert_eq!(graph.cause_chain(0), vec![0]);
+    }
+
+    #[test]
+    fn critical_path() {
+        let graph = CausalGraph::new(entries());
+        assert_eq!(graph.critical_path(0), vec![0, 2, 3]);
+        assert_eq!(graph.critical_path(1), vec![1]);
+    }
+
+    #[test]
+    fn ignores_links_to_later_traces() {
+        let entries = vec![entry(TraceKind::Event, 1, 0, Some((1, 1)), 0),
+                           entry(TraceKind::Event, 1, 1, Some((1, 0)), 10)];
+        let graph = CausalGraph::new(entries);
+        assert_eq!(graph.node(0).cause(), None);
+        assert_eq!(graph.node(1).cause(), Some(0));
+    }
+
+    #[test]
+    fn dot() {
+        let graph = CausalGraph::new(entries());
+        let mut dot = vec![];
+        graph.write_dot(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.starts_with("digraph eep {\n"));
+        assert!(dot.contains("    n2 [label=\"Foo\\nthread 2\\n70 ns\", shape=box];\n"));
+        assert!(dot.contains("    n0 -> n2;\n"));
+        assert!(dot.ends_with("}\n"));
+
+        let mut labels = HashMap::new();
+        labels.insert(0, "say \"hi\"".to_string());
+        let mut dot = vec![];
+        graph.write_dot_with_labels(&mut dot, &labels).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.contains("    n0 [label=\"say \\\"hi\\\"\\nthread 1\", shape=ellipse];\n"));
+    }
+
+    #[test]
+    fn dot_with_stop_before_start() {
+        let entries = vec![entry(TraceKind::Start, 1, 0, None, 50),
+                           entry(TraceKind::Stop, 1, 0, None, 10)];
+        let graph = CausalGraph::new(entries);
+        assert_eq!(graph.node(0).duration(), 0);
+
+        let mut dot = vec![];
+        graph.write_dot(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.contains("\\n0 ns\""));
+    }
+}
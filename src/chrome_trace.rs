@@ -0,0 +1,347 @@
+//! Exporting traces in the Chrome Trace Event Format.
+//!
+//! The resulting JSON can be loaded into `chrome://tracing` or the Perfetto
+//! UI. See the [Trace Event Format] document for details.
+//!
+//! [Trace Event Format]: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+
+extern crate serde;
+
+use ring_buffer::{Entry, TraceKind};
+use std::collections::HashMap;
+use traits::{ThreadId, Trace};
+
+/// A set of `Entry<T>`s that serializes into the Chrome Trace Event Format.
+///
+/// * `TraceKind::Start` and `TraceKind::Stop` entries become `B` and `E`
+///   duration events.
+///
+/// * `TraceKind::Event` entries become thread-scoped instant events.
+///
+/// * `TraceKind::Dropped` entries become instant events too, whose first
+///   argument is the number of dropped entries.
+///
+/// * `Entry::why()` links become flow events from the causing trace to the
+///   caused one, if the causing trace is still present in the entries.
+///
+/// * Each entry's ID and payload arguments become the event's `args`.
+///
+/// ```
+/// extern crate eep;
+/// extern crate serde_json;
+///
+/// use eep::chrome_trace::ChromeTrace;
+/// use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+/// use eep::traits::TraceSink;
+///
+/// # fn main() {
+/// let mut buffer = SimpleTraceBuffer::default();
+/// let id = buffer.trace_start(SimpleTrace::OperationThing, None);
+/// buffer.trace_stop(id, SimpleTrace::OperationThing);
+///
+/// let json = serde_json::to_string(&ChromeTrace::new(buffer.iter())).unwrap();
+/// # let _ = json;
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ChromeTrace<T> {
+    entries: Vec<Entry<T>>,
+}
+
+impl<T> ChromeTrace<T> {
+    /// Construct a new `ChromeTrace` from the given entries, which are expected
+    /// to be in the order they were traced, for example from
+    /// `RingBuffer::iter()`.
+    pub fn new<I>(entries: I) -> ChromeTrace<T>
+        where I: IntoIterator<Item = Entry<T>>
+    {
+        ChromeTrace { entries: entries.into_iter().collect() }
+    }
+
+    /// Get the entries that will be exported.
+    pub fn entries(&self) -> &[Entry<T>] {
+        &self.entries
+    }
+
+    /// Export the entries with the given labels for tags instead of
+    /// `Trace::label`.
+    ///
+    /// This is useful for exporting loaded traces, whose labels were
+    /// serialized along with them.
+    pub fn with_labels<'a>(&'a self, labels: &'a HashMap<u32, String>) -> WithLabels<'a, T> {
+        WithLabels {
+            trace: self,
+            labels: labels,
+        }
+    }
+}
+
+/// A `ChromeTrace` that is exported with the given labels. See
+/// `ChromeTrace::with_labels`.
+#[derive(Debug)]
+pub struct WithLabels<'a, T>
+    where T: 'a
+{
+    trace: &'a ChromeTrace<T>,
+    labels: &'a HashMap<u32, String>,
+}
+
+// Chrome's trace viewer groups threads by process; all of our traces are from
+// the same process.
+const PID: usize = 0;
+
+const CATEGORY: &'static str = "eep";
+
+fn chrome_tid(thread: Option<ThreadId>) -> usize {
+    thread.map_or(0, |t| t.0)
+}
+
+// The Trace Event Format wants timestamps in (possibly fractional)
+// microseconds.
+fn micros(entry_ns: u64) -> f64 {
+    entry_ns as f64 / 1000.0
+}
+
+struct ChromeEvent<'a> {
+    name: &'a str,
+    ph: &'static str,
+    ts: f64,
+    tid: usize,
+    // The entry's own trace ID and payload arguments, for `B`, `E`, and `i`
+    // events.
+    trace: Option<(u32, [u64; 4])>,
+    // The flow ID, for `s` and `f` events.
+    flow_id: Option<usize>,
+}
+
+impl<'a> serde::Serialize for ChromeEvent<'a> {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        struct Args(u32, [u64; 4]);
+
+        impl serde::Serialize for Args {
+            fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+                where S: serde::Serializer
+            {
+                let mut state = try!(serializer.serialize_struct("Args", 2));
+                try!(serializer.serialize_struct_elt(&mut state, "id", self.0));
+                try!(serializer.serialize_struct_elt(&mut state, "args", self.1));
+                serializer.serialize_struct_end(state)
+            }
+        }
+
+        let mut state = try!(serializer.serialize_struct("ChromeEvent", 9));
+        try!(serializer.serialize_struct_elt(&mut state, "name", self.name));
+        try!(serializer.serialize_struct_elt(&mut state, "cat", CATEGORY));
+        try!(serializer.serialize_struct_elt(&mut state, "ph", self.ph));
+        try!(serializer.serialize_struct_elt(&mut state, "ts", self.ts));
+        try!(serializer.serialize_struct_elt(&mut state, "pid", PID));
+        try!(serializer.serialize_struct_elt(&mut state, "tid", self.tid));
+        match self.ph {
+            // Thread-scoped instant event.
+            "i" => try!(serializer.serialize_struct_elt(&mut state, "s", "t")),
+            // Bind the flow's end to the enclosing slice.
+            "f" => try!(serializer.serialize_struct_elt(&mut state, "bp", "e")),
+            _ => {}
+        }
+        if let Some(id) = self.flow_id {
+            try!(serializer.serialize_struct_elt(&mut state, "id", id));
+        }
+        if let Some((id, args)) = self.trace {
+            try!(serializer.serialize_struct_elt(&mut state, "args", Args(id, args)));
+        }
+        serializer.serialize_struct_end(state)
+    }
+}
+
+// The `traceEvents` array of the given entries, labeled by `F`.
+struct Events<'a, T, F>(&'a [Entry<T>], F) where T: 'a;
+
+impl<'a, 'b, T, F> serde::Serialize for Events<'a, T, F>
+    where F: Fn(u32) -> &'b str
+{
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        // Remember where and when each event or operation started, so that we
+        // can draw flow arrows from causes to effects.
+        let mut origins = HashMap::new();
+        for entry in self.0 {
+            if entry.kind() != TraceKind::Stop {
+                origins.insert((entry.thread(), entry.id()), entry);
+            }
+        }
+
+        let mut state = try!(serializer.serialize_seq(None));
+        let mut next_flow_id = 0;
+
+        for entry in self.0 {
+            let name = (self.1)(entry.tag());
+            let ts = micros(entry.timestamp().0);
+            let tid = chrome_tid(entry.thread());
+
+            let ph = match entry.kind() {
+                TraceKind::Event | TraceKind::Dropped => "i",
+                TraceKind::Start => "B",
+                TraceKind::Stop => "E",
+            };
+
+            try!(serializer.serialize_seq_elt(&mut state,
+                                              ChromeEvent {
+                                                  name: name,
+                                                  ph: ph,
+                                                  ts: ts,
+                                                  tid: tid,
+                                                  trace: Some((entry.id(), entry.args())),
+                                                  flow_id: None,
+                                              }));
+
+            let origin = entry.why().and_then(|why| origins.get(&why));
+            if let Some(origin) = origin {
+                let flow_id = next_flow_id;
+                next_flow_id += 1;
+
+                try!(serializer.serialize_seq_elt(&mut state,
+                                                  ChromeEvent {
+                                                      name: name,
+                                                      ph: "s",
+                                                      ts: micros(origin.timestamp().0),
+                                                      tid: chrome_tid(origin.thread()),
+                                                      trace: None,
+                                                      flow_id: Some(flow_id),
+                                                  }));
+                try!(serializer.serialize_seq_elt(&mut state,
+                                                  ChromeEvent {
+                                                      name: name,
+                                                      ph: "f",
+                                                      ts: ts,
+                                                      tid: tid,
+                                                      trace: None,
+                                                      flow_id: Some(flow_id),
+                                                  }));
+            }
+        }
+
+        serializer.serialize_seq_end(state)
+    }
+}
+
+fn serialize_trace<'b, S, T, F>(entries: &[Entry<T>],
+                                label: F,
+                                serializer: &mut S)
+                                -> Result<(), S::Error>
+    where S: serde::Serializer,
+          F: Fn(u32) -> &'b str
+{
+    let mut state = try!(serializer.serialize_struct("ChromeTrace", 2));
+    try!(serializer.serialize_struct_elt(&mut state, "traceEvents", Events(entries, label)));
+    try!(serializer.serialize_struct_elt(&mut state, "displayTimeUnit", "ns"));
+    serializer.serialize_struct_end(state)
+}
+
+impl<T> serde::Serialize for ChromeTrace<T>
+    where T: Trace
+{
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        serialize_trace(&self.entries, T::label, serializer)
+    }
+}
+
+impl<'a, T> serde::Serialize for WithLabels<'a, T> {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        let labels = self.labels;
+        serialize_trace(&self.trace.entries,
+                        |tag| labels.get(&tag).map_or("<unknown>", |label| &label[..]),
+                        serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+
+    use super::*;
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
+    use std::collections::HashMap;
+    use traits::{Trace, TraceSink};
+
+    fn phases(value: &serde_json::Value) -> Vec<String> {
+        value.find("traceEvents")
+            .and_then(|events| events.as_array())
+            .expect("should have traceEvents array")
+            .iter()
+            .map(|event| event.find("ph").and_then(|ph| ph.as_str()).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn start_stop_and_event() {
+        let mut buffer = SimpleTraceBuffer::default();
+        let thing_id = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+        buffer.trace_stop(thing_id, SimpleTrace::OperationThing);
+
+        let serialized = serde_json::to_string(&ChromeTrace::new(buffer.iter()))
+            .expect("should serialize OK");
+        let value: serde_json::Value = serde_json::from_str(&serialized)
+            .expect("should be valid JSON");
+
+        assert_eq!(phases(&value), vec!["B", "i", "E"]);
+
+        let events = value.find("traceEvents").unwrap().as_array().unwrap();
+        assert_eq!(events[0].find("name").unwrap().as_str(), Some("Thing"));
+        assert_eq!(events[1].find("name").unwrap().as_str(), Some("Foo"));
+        assert_eq!(events[1].find("s").unwrap().as_str(), Some("t"));
+        assert_eq!(events[2].find("name").unwrap().as_str(), Some("Thing"));
+        assert_eq!(value.find("displayTimeUnit").unwrap().as_str(), Some("ns"));
+    }
+
+    #[test]
+    fn why_becomes_flow() {
+        let mut buffer = SimpleTraceBuffer::default();
+        let parent = buffer.trace_event(SimpleTrace::FooEvent, None);
+        let child = buffer.trace_start(SimpleTrace::OperationThing, Some(parent));
+        buffer.trace_stop(child, SimpleTrace::OperationThing);
+
+        let value = serde_json::to_value(&ChromeTrace::new(buffer.iter()));
+        assert_eq!(phases(&value), vec!["i", "B", "s", "f", "E"]);
+
+        let events = value.find("traceEvents").unwrap().as_array().unwrap();
+        assert_eq!(events[2].find("ts"), events[0].find("ts"));
+        assert_eq!(events[3].find("ts"), events[1].find("ts"));
+        assert_eq!(events[2].find("id"), events[3].find("id"));
+    }
+
+    #[test]
+    fn with_labels() {
+        let mut buffer = SimpleTraceBuffer::default();
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+        buffer.trace_event(SimpleTrace::OperationThing, None);
+
+        let mut labels = HashMap::new();
+        labels.insert(SimpleTrace::FooEvent.tag(), "Loaded".to_string());
+
+        let trace = ChromeTrace::new(buffer.iter());
+        let value = serde_json::to_value(&trace.with_labels(&labels));
+        let events = value.find("traceEvents").unwrap().as_array().unwrap();
+        assert_eq!(events[0].find("name").unwrap().as_str(), Some("Loaded"));
+        assert_eq!(events[1].find("name").unwrap().as_str(), Some("<unknown>"));
+    }
+
+    #[test]
+    fn why_with_evicted_cause_has_no_flow() {
+        let mut first = SimpleTraceBuffer::default();
+        let parent = first.trace_event(SimpleTrace::FooEvent, None);
+
+        let mut buffer = SimpleTraceBuffer::default();
+        buffer.trace_event(SimpleTrace::FooEvent, Some(parent));
+
+        let value = serde_json::to_value(&ChromeTrace::new(buffer.iter()));
+        assert_eq!(phases(&value), vec!["i"]);
+    }
+}
@@ -0,0 +1,393 @@
+//! Clocks for timestamping trace entries.
+//!
+//! Sinks record the raw readings of a `Clock`, and convert them into wall-clock
+//! `NsSinceEpoch` timestamps with the clock's `TimeBase` when entries are read
+//! back out.
+
+extern crate serde;
+extern crate time;
+
+use ring_buffer::NsSinceEpoch;
+use std::time::Duration;
+
+/// A source of timestamps for trace entries.
+pub trait Clock {
+    /// Read the clock.
+    ///
+    /// The origin of the readings is up to the clock; use `time_base` to map
+    /// them to wall-clock time.
+    fn now(&self) -> u64;
+
+    /// Get the mapping from this clock's readings to wall-clock time.
+    fn time_base(&self) -> TimeBase;
+}
+
+/// A mapping from a clock's readings to wall-clock time: a reading of the
+/// clock, the wall-clock time at which it was taken, and the number of clock
+/// ticks per nanosecond.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TimeBase {
+    reading: u64,
+    wall: NsSinceEpoch,
+    ticks_per_ns: f64,
+}
+
+impl TimeBase {
+    /// Construct a new `TimeBase` for a clock that ticks once per nanosecond,
+    /// in which the clock reading `reading` was taken at the wall-clock time
+    /// `wall`.
+    pub fn new(reading: u64, wall: NsSinceEpoch) -> TimeBase {
+        TimeBase::with_ticks_per_ns(reading, wall, 1.0)
+    }
+
+    /// Construct a new `TimeBase` for a clock that ticks `ticks_per_ns` times
+    /// per nanosecond, in which the clock reading `reading` was taken at the
+    /// wall-clock time `wall`.
+    pub fn with_ticks_per_ns(reading: u64, wall: NsSinceEpoch, ticks_per_ns: f64) -> TimeBase {
+        assert!(ticks_per_ns > 0.0);
+        TimeBase {
+            reading: reading,
+            wall: wall,
+            ticks_per_ns: ticks_per_ns,
+        }
+    }
+
+    /// Get the clock reading of this mapping.
+    pub fn reading(&self) -> u64 {
+        self.reading
+    }
+
+    /// Get the wall-clock time of this mapping.
+    pub fn wall(&self) -> NsSinceEpoch {
+        self.wall
+    }
+
+    /// Get the number of clock ticks per nanosecond.
+    pub fn ticks_per_ns(&self) -> f64 {
+        self.ticks_per_ns
+    }
+
+    /// Convert a number of clock ticks into nanoseconds.
+    pub fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        if self.ticks_per_ns == 1.0 {
+            ticks
+        } else {
+            (ticks as f64 / self.ticks_per_ns).round() as u64
+        }
+    }
+
+    /// Convert a reading of the clock into wall-clock time.
+    pub fn to_wall(&self, reading: u64) -> NsSinceEpoch {
+        let delta = if reading >= self.reading {
+            self.ticks_to_ns(reading - self.reading) as i64
+        } else {
+            -(self.ticks_to_ns(self.reading - reading) as i64)
+        };
+        NsSinceEpoch(self.wall.0.wrapping_add(delta as u64))
+    }
+}
+
+impl serde::Serialize for TimeBase {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        let mut state = try!(serializer.serialize_struct("TimeBase", 3));
+        try!(serializer.serialize_struct_elt(&mut state, "reading", self.reading));
+        try!(serializer.serialize_struct_elt(&mut state, "wall", self.wall));
+        try!(serializer.serialize_struct_elt(&mut state, "ticks_per_ns", self.ticks_per_ns));
+        serializer.serialize_struct_end(state)
+    }
+}
+
+impl serde::Deserialize for TimeBase {
+    fn deserialize<D>(deserializer: &mut D) -> Result<TimeBase, D::Error>
+        where D: serde::Deserializer
+    {
+        struct TimeBaseVisitor;
+
+        impl serde::de::Visitor for TimeBaseVisitor {
+            type Value = TimeBase;
+
+            fn visit_map<V>(&mut self, mut visitor: V) -> Result<TimeBase, V::Error>
+                where V: serde::de::MapVisitor
+            {
+                let mut reading = None;
+                let mut wall = None;
+                let mut ticks_per_ns = None;
+
+                while let Some(key) = try!(visitor.visit_key::<String>()) {
+                    match &key[..] {
+                        "reading" => reading = Some(try!(visitor.visit_value())),
+                        "wall" => wall = Some(try!(visitor.visit_value())),
+                        "ticks_per_ns" => ticks_per_ns = Some(try!(visitor.visit_value())),
+                        _ => {
+                            try!(visitor.visit_value::<serde::de::impls::IgnoredAny>());
+                        }
+                    }
+                }
+                try!(visitor.end());
+
+                let reading = try!(reading.ok_or_else(|| {
+                    serde::de::Error::missing_field("reading")
+                }));
+                let wall = try!(wall.ok_or_else(|| serde::de::Error::missing_field("wall")));
+                let ticks_per_ns: f64 = ticks_per_ns.unwrap_or(1.0);
+                if !(ticks_per_ns > 0.0) {
+                    return Err(serde::de::Error::invalid_value("ticks_per_ns must be positive"));
+                }
+
+                Ok(TimeBase::with_ticks_per_ns(reading, wall, ticks_per_ns))
+            }
+        }
+
+        const FIELDS: &'static [&'static str] = &["reading", "wall", "ticks_per_ns"];
+        deserializer.deserialize_struct("TimeBase", FIELDS, TimeBaseVisitor)
+    }
+}
+
+/// The system's wall clock.
+///
+/// Its readings are `NsSinceEpoch` values, and it can jump forwards or
+/// backwards when the system time is adjusted, for example by NTP.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct WallClock;
+
+impl Clock for WallClock {
+    #[inline(always)]
+    fn now(&self) -> u64 {
+        NsSinceEpoch::now().0
+    }
+
+    fn time_base(&self) -> TimeBase {
+        TimeBase::new(0, NsSinceEpoch(0))
+    }
+}
+
+/// A monotonic clock, with nanosecond readings that never go backwards. This
+/// is `CLOCK_MONOTONIC` on Linux.
+///
+/// The wall-clock time is sampled once, when the `MonotonicClock` is
+/// constructed, and all of its readings are mapped relative to that.
+#[derive(Copy, Clone, Debug)]
+pub struct MonotonicClock {
+    time_base: TimeBase,
+}
+
+impl MonotonicClock {
+    /// Construct a new `MonotonicClock`.
+    pub fn new() -> MonotonicClock {
+        // Sample the wall clock between two monotonic readings to minimize the
+        // error in the mapping.
+        let before = time::precise_time_ns();
+        let wall = NsSinceEpoch::now();
+        let after = time::precise_time_ns();
+        MonotonicClock { time_base: TimeBase::new(before + (after - before) / 2, wall) }
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> MonotonicClock {
+        MonotonicClock::new()
+    }
+}
+
+impl Clock for MonotonicClock {
+    #[inline(always)]
+    fn now(&self) -> u64 {
+        time::precise_time_ns()
+    }
+
+    fn time_base(&self) -> TimeBase {
+        self.time_base
+    }
+}
+
+/// A clock that reads the CPU's time-stamp counter with `rdtsc`.
+///
+/// Reading the time-stamp counter does not enter the kernel, so it is much
+/// cheaper than the other clocks. The price is a calibration period when the
+/// clock is constructed, during which the number of ticks per nanosecond is
+/// measured against the monotonic clock. Calibration assumes an invariant
+/// time-stamp counter, which ticks at a constant rate regardless of frequency
+/// scaling, as it does on all recent x86_64 CPUs.
+///
+/// On architectures other than x86_64, or if calibration fails, for example
+/// because the time-stamp counter went backwards while migrating between
+/// cores, this falls back to the monotonic clock.
+#[derive(Copy, Clone, Debug)]
+pub struct TscClock {
+    time_base: TimeBase,
+    // Whether calibration failed, and readings come from the monotonic clock.
+    monotonic: bool,
+}
+
+impl TscClock {
+    /// Construct a new `TscClock`, calibrating it for 10 milliseconds.
+    pub fn new() -> TscClock {
+        TscClock::with_calibration_period(Duration::from_millis(10))
+    }
+
+    /// Construct a new `TscClock`, calibrating it for the given period. Longer
+    /// periods give more accurate conversions to nanoseconds.
+    pub fn with_calibration_period(period: Duration) -> TscClock {
+        match calibrate_tsc(period) {
+            Some(time_base) => {
+                TscClock {
+                    time_base: time_base,
+                    monotonic: false,
+                }
+            }
+            None => {
+                TscClock {
+                    time_base: MonotonicClock::new().time_base(),
+                    monotonic: true,
+                }
+            }
+        }
+    }
+}
+
+impl Default for TscClock {
+    fn default() -> TscClock {
+        TscClock::new()
+    }
+}
+
+impl Clock for TscClock {
+    #[inline(always)]
+    fn now(&self) -> u64 {
+        if self.monotonic {
+            time::precise_time_ns()
+        } else {
+            rdtsc()
+        }
+    }
+
+    fn time_base(&self) -> TimeBase {
+        self.time_base
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+fn rdtsc() -> u64 {
+    unsafe { ::std::arch::x86_64::_rdtsc() }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+#[inline(always)]
+fn rdtsc() -> u64 {
+    time::precise_time_ns()
+}
+
+// How many times to measure the time-stamp counter's rate before giving up.
+#[cfg(target_arch = "x86_64")]
+const CALIBRATION_ATTEMPTS: usize = 3;
+
+// Measure the time-stamp counter's rate against the monotonic clock, or return
+// `None` if no measurement gives a usable rate.
+#[cfg(target_arch = "x86_64")]
+fn calibrate_tsc(period: Duration) -> Option<TimeBase> {
+    use std::thread;
+
+    for _ in 0..CALIBRATION_ATTEMPTS {
+        let start_ticks = rdtsc();
+        let start_ns = time::precise_time_ns();
+        thread::sleep(period);
+        let end_ticks = rdtsc();
+        let end_ns = time::precise_time_ns();
+
+        // A counter that went backwards gives a rate of zero, and a period
+        // that was too short to measure gives an infinite or NaN rate.
+        let ticks_per_ns = end_ticks.saturating_sub(start_ticks) as f64 /
+                           end_ns.saturating_sub(start_ns) as f64;
+        if !ticks_per_ns.is_finite() || ticks_per_ns <= 0.0 {
+            continue;
+        }
+
+        let before = rdtsc();
+        let wall = NsSinceEpoch::now();
+        let after = rdtsc();
+        let reading = before + after.saturating_sub(before) / 2;
+        return Some(TimeBase::with_ticks_per_ns(reading, wall, ticks_per_ns));
+    }
+    None
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn calibrate_tsc(_period: Duration) -> Option<TimeBase> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring_buffer::NsSinceEpoch;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn time_base_to_wall() {
+        let time_base = TimeBase::new(1_000, NsSinceEpoch(5_000_000));
+        assert_eq!(time_base.to_wall(1_000), NsSinceEpoch(5_000_000));
+        assert_eq!(time_base.to_wall(1_500), NsSinceEpoch(5_000_500));
+        assert_eq!(time_base.to_wall(500), NsSinceEpoch(4_999_500));
+    }
+
+    #[test]
+    fn time_base_to_wall_with_ticks_per_ns() {
+        let time_base = TimeBase::with_ticks_per_ns(1_000, NsSinceEpoch(5_000_000), 2.5);
+        assert_eq!(time_base.ticks_to_ns(2_500), 1_000);
+        assert_eq!(time_base.to_wall(1_000), NsSinceEpoch(5_000_000));
+        assert_eq!(time_base.to_wall(3_500), NsSinceEpoch(5_001_000));
+        assert_eq!(time_base.to_wall(500), NsSinceEpoch(4_999_800));
+    }
+
+    #[test]
+    fn monotonic_clock_does_not_go_backwards() {
+        let clock = MonotonicClock::new();
+        let mut last = clock.now();
+        for _ in 0..1000 {
+            let now = clock.now();
+            assert!(now >= last);
+            last = now;
+        }
+    }
+
+    #[test]
+    fn monotonic_clock_maps_to_wall_time() {
+        let before = NsSinceEpoch::now();
+        let clock = MonotonicClock::new();
+        let wall = clock.time_base().to_wall(clock.now());
+        let after = NsSinceEpoch::now();
+
+        // Allow a generous margin for the system clock being adjusted.
+        let margin = 1_000_000_000;
+        assert!(before.0 - margin <= wall.0 && wall.0 <= after.0 + margin);
+    }
+
+    #[test]
+    fn tsc_clock_measures_elapsed_time() {
+        let clock = TscClock::new();
+        let time_base = clock.time_base();
+        assert!(time_base.ticks_per_ns() > 0.0);
+
+        let start = clock.now();
+        thread::sleep(Duration::from_millis(20));
+        let elapsed = time_base.ticks_to_ns(clock.now() - start);
+
+        assert!(elapsed >= 10_000_000, "elapsed = {}", elapsed);
+        assert!(elapsed < 1_000_000_000, "elapsed = {}", elapsed);
+    }
+
+    #[test]
+    fn tsc_clock_with_zero_calibration_period() {
+        let clock = TscClock::with_calibration_period(Duration::from_secs(0));
+        let time_base = clock.time_base();
+        assert!(time_base.ticks_per_ns().is_finite());
+        assert!(time_base.ticks_per_ns() > 0.0);
+
+        let start = clock.now();
+        assert!(clock.now() >= start);
+    }
+}
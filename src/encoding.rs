@@ -0,0 +1,365 @@
+//! The binary encoding of trace entries.
+//!
+//! This is the layout `RingBuffer` uses to store entries. It depends on neither
+//! host endianness nor pointer width, so entries encoded on one machine can be
+//! decoded by tooling on another.
+//!
+//! Each entry is a single header byte followed by a sequence of [LEB128]
+//! variable-length integers:
+//!
+//! | Field          | Encoding        | Present when            |
+//! |----------------|-----------------|-------------------------|
+//! | header         | one byte        | always                  |
+//! | timestamp      | signed LEB128   | always                  |
+//! | thread         | unsigned LEB128 | `HAS_THREAD` is set     |
+//! | id             | unsigned LEB128 | always                  |
+//! | tag            | unsigned LEB128 | always                  |
+//! | why's thread   | unsigned LEB128 | `HAS_WHY_THREAD` is set |
+//! | why's id       | unsigned LEB128 | `HAS_WHY` is set        |
+//! | arguments      | unsigned LEB128 | one per argument count  |
+//!
+//! The header's low two bits hold the `TraceKind` (`0` for `Event`, `1` for
+//! `Start`, `2` for `Stop`, and `3` for `Dropped`), and the next three bits are
+//! the `HAS_THREAD`, `HAS_WHY`, and `HAS_WHY_THREAD` flags. The top three bits
+//! hold the argument count, from zero to four: the entry's arguments up to and
+//! including its last non-zero one are encoded, and the rest are zero.
+//!
+//! The timestamp is the difference, in the `Clock`'s ticks, from the previous
+//! entry's timestamp, so decoding a sequence of entries requires knowing the
+//! timestamp that the first entry is relative to. The clock's `TimeBase`
+//! converts the resulting raw readings to wall-clock time.
+//!
+//! Any change to this layout must bump `FORMAT_VERSION`.
+//!
+//! [LEB128]: https://en.wikipedia.org/wiki/LEB128
+
+use leb128;
+use ring_buffer::{Entry, NsSinceEpoch, TraceKind};
+use std::error;
+use std::fmt;
+use std::io;
+use traits::ThreadId;
+
+/// The version of the entry encoding described in this module's
+/// documentation.
+///
+/// Version 3 added `TraceKind::Dropped`. It takes the last free value of the
+/// header's two kind bits, so any further kind will need a wider kind field
+/// and another version.
+pub const FORMAT_VERSION: u32 = 3;
+
+/// The mask for the `TraceKind` bits in an entry's header byte.
+pub const KIND_MASK: u8 = 0b0000_0011;
+
+/// The header flag that is set when the entry has a thread.
+pub const HAS_THREAD: u8 = 0b0000_0100;
+
+/// The header flag that is set when the entry has a `why`.
+pub const HAS_WHY: u8 = 0b0000_1000;
+
+/// The header flag that is set when the entry's `why` has a thread.
+pub const HAS_WHY_THREAD: u8 = 0b0001_0000;
+
+/// The mask for the argument count bits in an entry's header byte.
+pub const ARGS_MASK: u8 = 0b1110_0000;
+
+/// The shift of the argument count bits in an entry's header byte.
+pub const ARGS_SHIFT: u32 = 5;
+
+/// The largest number of bytes a single encoded entry may occupy: the header
+/// byte, a ten byte timestamp delta, two ten byte thread IDs, three five byte
+/// `u32`s, and four ten byte arguments.
+pub const MAX_ENCODED_ENTRY_SIZE: usize = 1 + 10 + 10 + 5 + 5 + 10 + 5 + 4 * 10;
+
+/// An error that occurred while decoding an entry.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// There was an underlying IO error, for example the input ended in the
+    /// middle of an entry.
+    Io(io::Error),
+    /// The header byte has invalid flags or an invalid argument count.
+    InvalidHeader(u8),
+    /// An integer field is too large for the type it is decoded into.
+    Overflow,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Io(ref e) => write!(f, "could not read entry: {}", e),
+            DecodeError::InvalidHeader(header) => write!(f, "invalid entry header: {:#x}", header),
+            DecodeError::Overflow => write!(f, "entry field overflowed its type"),
+        }
+    }
+}
+
+impl error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            DecodeError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DecodeError {
+    fn from(e: io::Error) -> DecodeError {
+        DecodeError::Io(e)
+    }
+}
+
+impl From<leb128::read::Error> for DecodeError {
+    fn from(e: leb128::read::Error) -> DecodeError {
+        match e {
+            leb128::read::Error::IoError(e) => DecodeError::Io(e),
+            leb128::read::Error::Overflow => DecodeError::Overflow,
+        }
+    }
+}
+
+/// Encode `entry`, whose timestamp is delta-encoded against `prev_timestamp`,
+/// into `w`.
+///
+/// On success, return the number of bytes written, which is at most
+/// `MAX_ENCODED_ENTRY_SIZE`.
+pub fn encode<T, W>(entry: &Entry<T>,
+                    prev_timestamp: NsSinceEpoch,
+                    w: &mut W)
+                    -> io::Result<usize>
+    where W: io::Write
+{
+    let mut header = entry.kind() as u8;
+    if entry.thread().is_some() {
+        header |= HAS_THREAD;
+    }
+    if let Some((why_thread, _)) = entry.why() {
+        header |= HAS_WHY;
+        if why_thread.is_some() {
+            header |= HAS_WHY_THREAD;
+        }
+    }
+    let args = entry.args();
+    let num_args = args.iter().rposition(|&arg| arg != 0).map_or(0, |i| i + 1);
+    header |= (num_args as u8) << ARGS_SHIFT;
+
+    try!(w.write_all(&[header]));
+    let mut size = 1;
+
+    let delta = entry.timestamp().0.wrapping_sub(prev_timestamp.0) as i64;
+    size += try!(leb128::write::signed(w, delta));
+    if let Some(thread) = entry.thread() {
+        size += try!(leb128::write::unsigned(w, thread.0 as u64));
+    }
+    size += try!(leb128::write::unsigned(w, entry.id() as u64));
+    size += try!(leb128::write::unsigned(w, entry.tag() as u64));
+    if let Some((why_thread, why_id)) = entry.why() {
+        if let Some(why_thread) = why_thread {
+            size += try!(leb128::write::unsigned(w, why_thread.0 as u64));
+        }
+        size += try!(leb128::write::unsigned(w, why_id as u64));
+    }
+    for &arg in &args[..num_args] {
+        size += try!(leb128::write::unsigned(w, arg));
+    }
+
+    debug_assert!(size <= MAX_ENCODED_ENTRY_SIZE);
+    Ok(size)
+}
+
+/// Decode an entry from `r`, whose timestamp was delta-encoded against
+/// `prev_timestamp`.
+pub fn decode<T, R>(r: &mut R, prev_timestamp: NsSinceEpoch) -> Result<Entry<T>, DecodeError>
+    where R: io::Read
+{
+    fn read_u32<R: io::Read>(r: &mut R) -> Result<u32, DecodeError> {
+        let n = try!(leb128::read::unsigned(r));
+        if n > ::std::u32::MAX as u64 {
+            return Err(DecodeError::Overflow);
+        }
+        Ok(n as u32)
+    }
+
+    fn read_thread<R: io::Read>(r: &mut R) -> Result<ThreadId, DecodeError> {
+        let n = try!(leb128::read::unsigned(r));
+        if n > ::std::usize::MAX as u64 {
+            return Err(DecodeError::Overflow);
+        }
+        Ok(ThreadId(n as usize))
+    }
+
+    let mut header = [0];
+    try!(r.read_exact(&mut header));
+    let header = header[0];
+
+    let kind = match header & KIND_MASK {
+        0x0 => TraceKind::Event,
+        0x1 => TraceKind::Start,
+        0x2 => TraceKind::Stop,
+        _ => TraceKind::Dropped,
+    };
+    let num_args = ((header & ARGS_MASK) >> ARGS_SHIFT) as usize;
+    if num_args > 4 || (header & HAS_WHY_THREAD != 0 && header & HAS_WHY == 0) {
+        return Err(DecodeError::InvalidHeader(header));
+    }
+
+    let delta = try!(leb128::read::signed(r));
+    let timestamp = NsSinceEpoch(prev_timestamp.0.wrapping_add(delta as u64));
+
+    let thread = if header & HAS_THREAD != 0 {
+        Some(try!(read_thread(r)))
+    } else {
+        None
+    };
+
+    let id = try!(read_u32(r));
+    let tag = try!(read_u32(r));
+
+    let why = if header & HAS_WHY != 0 {
+        let why_thread = if header & HAS_WHY_THREAD != 0 {
+            Some(try!(read_thread(r)))
+        } else {
+            None
+        };
+        Some((why_thread, try!(read_u32(r))))
+    } else {
+        None
+    };
+
+    let mut args = [0; 4];
+    for arg in &mut args[..num_args] {
+        *arg = try!(leb128::read::unsigned(r));
+    }
+
+    Ok(Entry::new(kind, tag, id, thread, why, timestamp).with_args(args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring_buffer::{Entry, NsSinceEpoch, TraceKind};
+    use simple_trace::SimpleTrace;
+    use traits::ThreadId;
+
+    type SimpleEntry = Entry<SimpleTrace>;
+
+    #[test]
+    fn encoding_is_stable() {
+        let entry = SimpleEntry::new(TraceKind::Start,
+                                     2,
+                                     300,
+                                     Some(ThreadId(1)),
+                                     Some((Some(ThreadId(1)), 7)),
+                                     NsSinceEpoch(1_000_064));
+
+        let mut bytes = vec![];
+        let size = encode(&entry, NsSinceEpoch(1_000_000), &mut bytes).unwrap();
+        assert_eq!(size, bytes.len());
+        let expected = [
+            // Header: `Start | HAS_THREAD | HAS_WHY | HAS_WHY_THREAD`.
+            0b0001_1101,
+            // Timestamp delta of 64, which needs two bytes as signed LEB128.
+            0xc0,
+            0x00,
+            // Thread.
+            0x01,
+            // Id of 300.
+            0xac,
+            0x02,
+            // Tag.
+            0x02,
+            // Why's thread.
+            0x01,
+            // Why's id.
+            0x07,
+        ];
+        assert_eq!(&bytes[..], &expected[..]);
+
+        let decoded: SimpleEntry = decode(&mut &bytes[..], NsSinceEpoch(1_000_000)).unwrap();
+        assert_eq!(decoded, entry);
+    }
+
+    #[test]
+    fn round_trip_extremes() {
+        let entries = vec![
+            SimpleEntry::new(TraceKind::Event, 0, 0, None, None, NsSinceEpoch(0)),
+            SimpleEntry::new(TraceKind::Stop,
+                             ::std::u32::MAX,
+                             ::std::u32::MAX,
+                             Some(ThreadId(::std::usize::MAX)),
+                             Some((Some(ThreadId(::std::usize::MAX)), ::std::u32::MAX)),
+                             NsSinceEpoch(::std::u64::MAX))
+                .with_args([::std::u64::MAX; 4]),
+            SimpleEntry::new(TraceKind::Start, 1, 2, None, Some((None, 3)), NsSinceEpoch(5))
+                .with_args([0, 7, 0, 0]),
+            SimpleEntry::new(TraceKind::Dropped, 1, 0, None, None, NsSinceEpoch(6))
+                .with_args([42, 0, 0, 0]),
+        ];
+
+        let mut bytes = vec![];
+        let mut prev = NsSinceEpoch(0);
+        for entry in &entries {
+            let size = encode(entry, prev, &mut bytes).unwrap();
+            assert!(size <= MAX_ENCODED_ENTRY_SIZE);
+            prev = entry.timestamp();
+        }
+
+        let mut cursor = &bytes[..];
+        let mut prev = NsSinceEpoch(0);
+        for entry in &entries {
+            let decoded: SimpleEntry = decode(&mut cursor, prev).unwrap();
+            assert_eq!(&decoded, entry);
+            prev = decoded.timestamp();
+        }
+        assert!(cursor.is_empty());
+    }
+
+    #[test]
+    fn trailing_zero_args_are_not_encoded() {
+        let entry = SimpleEntry::new(TraceKind::Event, 1, 2, None, None, NsSinceEpoch(0))
+            .with_args([300, 5, 0, 0]);
+
+        let mut bytes = vec![];
+        encode(&entry, NsSinceEpoch(0), &mut bytes).unwrap();
+        let expected = [
+            // Header: `Event` with two arguments.
+            0b0100_0000,
+            // Timestamp delta, id, and tag.
+            0x00,
+            0x02,
+            0x01,
+            // Arguments of 300 and 5.
+            0xac,
+            0x02,
+            0x05,
+        ];
+        assert_eq!(&bytes[..], &expected[..]);
+
+        let decoded: SimpleEntry = decode(&mut &bytes[..], NsSinceEpoch(0)).unwrap();
+        assert_eq!(decoded, entry);
+    }
+
+    #[test]
+    fn invalid_header() {
+        // `HAS_WHY_THREAD` without `HAS_WHY`.
+        let bytes = [0b0001_0000, 0, 0, 0];
+        match decode::<SimpleTrace, _>(&mut &bytes[..], NsSinceEpoch(0)) {
+            Err(DecodeError::InvalidHeader(0b0001_0000)) => {}
+            otherwise => panic!("expected invalid header error, found {:?}", otherwise),
+        }
+
+        let bytes = [0b1010_0000, 0, 0, 0, 0, 0, 0, 0, 0];
+        match decode::<SimpleTrace, _>(&mut &bytes[..], NsSinceEpoch(0)) {
+            Err(DecodeError::InvalidHeader(0b1010_0000)) => {}
+            otherwise => panic!("expected invalid header error, found {:?}", otherwise),
+        }
+    }
+
+    #[test]
+    fn truncated_entry() {
+        let bytes = [0b0000_0000, 0];
+        match decode::<SimpleTrace, _>(&mut &bytes[..], NsSinceEpoch(0)) {
+            Err(DecodeError::Io(_)) => {}
+            otherwise => panic!("expected IO error, found {:?}", otherwise),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! RAII guards for tracing the start and stop of an operation.
+
+use traits::{Trace, TraceSink};
+
+/// Extension methods for every `TraceSink`.
+pub trait TraceSinkExt<T>: TraceSink<T>
+    where T: Trace
+{
+    /// Trace the start of an operation, and return a guard that traces its
+    /// stop when dropped.
+    ///
+    /// The stop is traced even when the operation is cut short by an early
+    /// `return` or `?`.
+    ///
+    /// ```
+    /// use eep::guard::TraceSinkExt;
+    /// use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+    ///
+    /// let mut buffer = SimpleTraceBuffer::default();
+    /// {
+    ///     let mut thing = buffer.span(SimpleTrace::OperationThing, None);
+    ///     thing.event(SimpleTrace::FooEvent);
+    ///     let _another = thing.child(SimpleTrace::OperationAnother);
+    /// }
+    /// assert_eq!(buffer.iter().count(), 5);
+    /// ```
+    fn span(&mut self, trace: T, why: Option<T::Id>) -> SpanGuard<Self, T> {
+        let id = self.trace_start(trace, why);
+        SpanGuard {
+            sink: self,
+            id: id,
+            trace: trace,
+        }
+    }
+}
+
+impl<S, T> TraceSinkExt<T> for S
+    where S: ?Sized + TraceSink<T>,
+          T: Trace
+{
+}
+
+/// A guard for an operation that was started with `TraceSinkExt::span`.
+///
+/// When the guard is dropped, it calls `trace_stop` with the operation's ID and
+/// trace.
+#[derive(Debug)]
+pub struct SpanGuard<'a, S, T>
+    where S: 'a + ?Sized + TraceSink<T>,
+          T: Trace
+{
+    sink: &'a mut S,
+    id: T::Id,
+    trace: T,
+}
+
+impl<'a, S, T> SpanGuard<'a, S, T>
+    where S: 'a + ?Sized + TraceSink<T>,
+          T: Trace
+{
+    /// Get the ID of this guard's operation.
+    pub fn id(&self) -> T::Id {
+        self.id
+    }
+
+    /// Get the trace of this guard's operation.
+    pub fn trace(&self) -> T {
+        self.trace
+    }
+
+    /// Start a child operation caused by this guard's operation.
+    ///
+    /// The child's stop is traced when the returned guard is dropped, which
+    /// must happen before this guard can be used again.
+    pub fn child(&mut self, trace: T) -> SpanGuard<S, T> {
+        let why = Some(self.id);
+        self.sink.span(trace, why)
+    }
+
+    /// Trace a one-off event caused by this guard's operation.
+    pub fn event(&mut self, trace: T) -> T::Id {
+        let why = Some(self.id);
+        self.sink.trace_event(trace, why)
+    }
+
+    /// Get the underlying sink, for tracing things that were not caused by
+    /// this guard's operation.
+    pub fn sink(&mut self) -> &mut S {
+        self.sink
+    }
+}
+
+impl<'a, S, T> Drop for SpanGuard<'a, S, T>
+    where S: 'a + ?Sized + TraceSink<T>,
+          T: Trace
+{
+    fn drop(&mut self) {
+        self.sink.trace_stop(self.id, self.trace);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring_buffer::TraceKind;
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
+    use sink_combinators::ToggleSink;
+    use traits::{Trace, TraceId, TraceSink};
+
+    #[test]
+    fn stops_on_early_return() {
+        fn operation(buffer: &mut SimpleTraceBuffer) -> Result<(), ()> {
+            let _guard = buffer.span(SimpleTrace::OperationThing, None);
+            try!(Err(()));
+            unreachable!()
+        }
+
+        let mut buffer = SimpleTraceBuffer::default();
+        assert!(operation(&mut buffer).is_err());
+
+        let entries: Vec<_> = buffer.iter().collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].kind(), TraceKind::Start);
+        assert_eq!(entries[1].kind(), TraceKind::Stop);
+        assert_eq!(entries[0].id(), entries[1].id());
+        assert_eq!(entries[1].tag(), SimpleTrace::OperationThing.tag());
+    }
+
+    #[test]
+    fn children_are_caused_by_parent() {
+        let mut buffer = SimpleTraceBuffer::default();
+        let parent_id = {
+            let mut parent = buffer.span(SimpleTrace::OperationThing, None);
+            {
+                let _child = parent.child(SimpleTrace::OperationAnother);
+            }
+            parent.event(SimpleTrace::FooEvent);
+            parent.id()
+        };
+        let parent = (parent_id.thread(), parent_id.u32());
+
+        let entries: Vec<_> = buffer.iter().collect();
+        assert_eq!(entries.iter().map(|e| e.kind()).collect::<Vec<_>>(),
+                   vec![TraceKind::Start,
+                        TraceKind::Start,
+                        TraceKind::Stop,
+                        TraceKind::Event,
+                        TraceKind::Stop]);
+        assert_eq!(entries[0].why(), None);
+        assert_eq!(entries[1].why(), Some(parent));
+        assert_eq!(entries[1].label(), "Another");
+        assert_eq!(entries[2].label(), "Another");
+        assert_eq!(entries[3].why(), Some(parent));
+        assert_eq!(entries[4].id(), parent_id.u32());
+    }
+
+    #[test]
+    fn works_through_toggle_sink() {
+        let mut sink = ToggleSink::new_enabled(SimpleTraceBuffer::default());
+        {
+            let _guard = sink.span(SimpleTrace::OperationThing, None);
+        }
+        assert_eq!(sink.as_ref().iter().count(), 2);
+
+        sink.disable();
+        {
+            let mut guard = sink.span(SimpleTrace::OperationThing, None);
+            guard.event(SimpleTrace::FooEvent);
+        }
+        assert_eq!(sink.as_ref().iter().count(), 2);
+    }
+
+    #[test]
+    fn works_through_trait_object() {
+        let mut buffer = SimpleTraceBuffer::default();
+        {
+            let sink: &mut dyn TraceSink<SimpleTrace> = &mut buffer;
+            let _guard = sink.span(SimpleTrace::OperationThing, None);
+        }
+        assert_eq!(buffer.iter().count(), 2);
+    }
+}
@@ -5,7 +5,28 @@
 #![deny(missing_debug_implementations)]
 #![deny(missing_docs)]
 
-// extern crate leb128;
+extern crate leb128;
+
+#[cfg(feature = "derive")]
+extern crate eep_derive;
+
+pub mod analysis;
+
+pub mod causality;
+
+pub mod chrome_trace;
+
+pub mod clock;
+
+pub mod encoding;
+
+pub mod guard;
+
+pub mod loaded_trace;
+
+pub mod mmap;
+
+pub mod registry;
 
 pub mod ring_buffer;
 
@@ -16,6 +37,10 @@ pub mod simple_trace;
 
 pub mod sink_combinators;
 
+pub mod stats;
+
+pub mod stream;
+
 mod threaded_trace_id;
 pub use threaded_trace_id::ThreadedTraceId;
 
@@ -0,0 +1,332 @@
+//! Loading serialized `RingBuffer`s back into typed entries.
+//!
+//! A `RingBuffer<T>` serializes its time base, the labels of its entries'
+//! tags, its counters, and the entries themselves. `LoadedTrace<T>`
+//! deserializes that same structure, so that dumps saved from a running
+//! program can be analyzed offline.
+//!
+//! ```
+//! extern crate eep;
+//! extern crate serde_json;
+//!
+//! use eep::loaded_trace::LoadedTrace;
+//! use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+//! use eep::traits::TraceSink;
+//!
+//! # fn main() {
+//! let mut buffer = SimpleTraceBuffer::default();
+//! let id = buffer.trace_start(SimpleTrace::OperationThing, None);
+//! buffer.trace_stop(id, SimpleTrace::OperationThing);
+//!
+//! let json = serde_json::to_string(&buffer).unwrap();
+//! let loaded: LoadedTrace<SimpleTrace> = serde_json::from_str(&json).unwrap();
+//! assert_eq!(loaded.entries().len(), 2);
+//! assert_eq!(loaded.label(loaded.entries()[0].tag()), Some("Thing"));
+//! # }
+//! ```
+
+extern crate serde;
+
+use clock::TimeBase;
+use ring_buffer::{Counters, Entry};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+/// A trace that was loaded from a serialized `RingBuffer<T>`.
+///
+/// The entries' timestamps are wall-clock times, exactly as they were
+/// serialized.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadedTrace<T> {
+    time_base: Option<TimeBase>,
+    labels: HashMap<u32, String>,
+    sample_rates: HashMap<u32, f64>,
+    counters: Option<Counters>,
+    entries: Vec<Entry<T>>,
+}
+
+impl<T> LoadedTrace<T> {
+    /// Construct a new `LoadedTrace` from its parts, for example to save a
+    /// trace that was filtered or merged from other loaded traces.
+    pub fn new(time_base: Option<TimeBase>,
+               labels: HashMap<u32, String>,
+               entries: Vec<Entry<T>>)
+               -> LoadedTrace<T> {
+        LoadedTrace {
+            time_base: time_base,
+            labels: labels,
+            sample_rates: HashMap::new(),
+            counters: None,
+            entries: entries,
+        }
+    }
+
+    /// Record the rates that tags were sampled at.
+    pub fn with_sample_rates(mut self, sample_rates: HashMap<u32, f64>) -> LoadedTrace<T> {
+        self.sample_rates = sample_rates;
+        self
+    }
+
+    /// Record the counters of the buffer that the entries were captured in.
+    pub fn with_counters(mut self, counters: Counters) -> LoadedTrace<T> {
+        self.counters = Some(counters);
+        self
+    }
+
+    /// Get the time base of the clock that timestamped the entries, if it was
+    /// serialized.
+    pub fn time_base(&self) -> Option<TimeBase> {
+        self.time_base
+    }
+
+    /// Get the labels of the entries' tags.
+    pub fn labels(&self) -> &HashMap<u32, String> {
+        &self.labels
+    }
+
+    /// Get the label of the given tag, if any entry had that tag.
+    pub fn label(&self, tag: u32) -> Option<&str> {
+        self.labels.get(&tag).map(|label| &label[..])
+    }
+
+    /// Get the rates that tags were sampled at. Tags without a rate were not
+    /// sampled.
+    pub fn sample_rates(&self) -> &HashMap<u32, f64> {
+        &self.sample_rates
+    }
+
+    /// Get the fraction of the traces with the given tag that were recorded.
+    /// This is `1.0` if the tag was not sampled.
+    pub fn sample_rate(&self, tag: u32) -> f64 {
+        self.sample_rates.get(&tag).cloned().unwrap_or(1.0)
+    }
+
+    /// Get the counters of the buffer that the entries were captured in, if
+    /// they were serialized. If `Counters::is_truncated` then entries are
+    /// missing from the capture.
+    pub fn counters(&self) -> Option<Counters> {
+        self.counters
+    }
+
+    /// Get the entries, oldest first.
+    pub fn entries(&self) -> &[Entry<T>] {
+        &self.entries
+    }
+
+    /// Take the entries, oldest first.
+    pub fn into_entries(self) -> Vec<Entry<T>> {
+        self.entries
+    }
+}
+
+/// `LoadedTrace` serializes in the same format as `RingBuffer`, so that it can
+/// be loaded again.
+impl<T> serde::Serialize for LoadedTrace<T> {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        // Turn the keys into strings to support JSON.
+        let labels: HashMap<_, _> = self.labels
+            .iter()
+            .map(|(tag, label)| (format!("{}", tag), label))
+            .collect();
+
+        let mut state = try!(serializer.serialize_struct("LoadedTrace", 5));
+        if let Some(time_base) = self.time_base {
+            try!(serializer.serialize_struct_elt(&mut state, "time_base", time_base));
+        }
+        try!(serializer.serialize_struct_elt(&mut state, "labels", labels));
+        if !self.sample_rates.is_empty() {
+            let sample_rates: HashMap<_, _> = self.sample_rates
+                .iter()
+                .map(|(tag, rate)| (format!("{}", tag), rate))
+                .collect();
+            try!(serializer.serialize_struct_elt(&mut state, "sample_rates", sample_rates));
+        }
+        if let Some(counters) = self.counters {
+            try!(serializer.serialize_struct_elt(&mut state, "counters", counters));
+        }
+        try!(serializer.serialize_struct_elt(&mut state, "entries", &self.entries));
+        serializer.serialize_struct_end(state)
+    }
+}
+
+impl<T> serde::Deserialize for LoadedTrace<T> {
+    fn deserialize<D>(deserializer: &mut D) -> Result<LoadedTrace<T>, D::Error>
+        where D: serde::Deserializer
+    {
+        struct LoadedTraceVisitor<T>(PhantomData<T>);
+
+        impl<T> serde::de::Visitor for LoadedTraceVisitor<T> {
+            type Value = LoadedTrace<T>;
+
+            fn visit_map<V>(&mut self, mut visitor: V) -> Result<LoadedTrace<T>, V::Error>
+                where V: serde::de::MapVisitor
+            {
+                let mut time_base = None;
+                let mut labels = None;
+                let mut sample_rates = None;
+                let mut counters = None;
+                let mut entries = None;
+
+                while let Some(key) = try!(visitor.visit_key::<String>()) {
+                    match &key[..] {
+                        "time_base" => time_base = Some(try!(visitor.visit_value())),
+                        "labels" => labels = Some(try!(visitor.visit_value())),
+                        "sample_rates" => sample_rates = Some(try!(visitor.visit_value())),
+                        "counters" => counters = Some(try!(visitor.visit_value())),
+                        "entries" => entries = Some(try!(visitor.visit_value())),
+                        _ => {
+                            try!(visitor.visit_value::<serde::de::impls::IgnoredAny>());
+                        }
+                    }
+                }
+                try!(visitor.end());
+
+                // The labels' and sample rates' keys were turned into strings
+                // to support JSON.
+                fn parse_tags<V, E>(map: HashMap<String, V>) -> Result<HashMap<u32, V>, E>
+                    where E: serde::de::Error
+                {
+                    let mut parsed = HashMap::with_capacity(map.len());
+                    for (tag, value) in map {
+                        let tag = try!(tag.parse()
+                            .map_err(|_| E::invalid_value("map keys must be tags")));
+                        parsed.insert(tag, value);
+                    }
+                    Ok(parsed)
+                }
+
+                let labels: HashMap<String, String> = try!(labels.ok_or_else(|| {
+                    serde::de::Error::missing_field("labels")
+                }));
+                let sample_rates: HashMap<String, f64> = sample_rates.unwrap_or_default();
+
+                let entries = try!(entries.ok_or_else(|| {
+                    serde::de::Error::missing_field("entries")
+                }));
+
+                Ok(LoadedTrace {
+                    time_base: time_base,
+                    labels: try!(parse_tags(labels)),
+                    sample_rates: try!(parse_tags(sample_rates)),
+                    counters: counters,
+                    entries: entries,
+                })
+            }
+        }
+
+        const FIELDS: &'static [&'static str] =
+            &["time_base", "labels", "sample_rates", "counters", "entries"];
+        deserializer.deserialize_struct("LoadedTrace", FIELDS, LoadedTraceVisitor(PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+
+    use super::*;
+    use clock::Clock;
+    use ring_buffer::{Entry, NsSinceEpoch, TraceKind};
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
+    use traits::{ThreadId, Trace, TraceSink};
+
+    #[test]
+    fn round_trip_ring_buffer() {
+        let mut buffer = SimpleTraceBuffer::default();
+        for _ in 0..10 {
+            let event = buffer.trace_event(SimpleTrace::FooEvent, None);
+            let child1 = buffer.trace_start(SimpleTrace::OperationThing, Some(event));
+            let child2 = buffer.trace_start(SimpleTrace::OperationAnother, None);
+            buffer.trace_stop(child2, SimpleTrace::OperationAnother);
+            buffer.trace_stop(child1, SimpleTrace::OperationThing);
+        }
+
+        let json = serde_json::to_string(&buffer).expect("should serialize OK");
+        let loaded: LoadedTrace<SimpleTrace> = serde_json::from_str(&json)
+            .expect("should deserialize OK");
+
+        let entries: Vec<_> = buffer.iter().collect();
+        assert_eq!(loaded.entries(), &entries[..]);
+        assert_eq!(loaded.time_base(), Some(buffer.clock().time_base()));
+        assert_eq!(loaded.counters(), Some(buffer.counters()));
+        assert_eq!(loaded.labels().len(), 3);
+        for entry in loaded.entries() {
+            assert_eq!(loaded.label(entry.tag()), Some(entry.label()));
+        }
+    }
+
+    #[test]
+    fn round_trip_loaded_trace() {
+        let mut labels = HashMap::new();
+        labels.insert(2, "Two".to_string());
+        let entries = vec![Entry::new(TraceKind::Event, 2, 1, None, None, NsSinceEpoch(5))];
+
+        let mut sample_rates = HashMap::new();
+        sample_rates.insert(2, 0.25);
+
+        let trace: LoadedTrace<SimpleTrace> = LoadedTrace::new(None, labels, entries)
+            .with_sample_rates(sample_rates);
+        let json = serde_json::to_string(&trace).expect("should serialize OK");
+        let loaded: LoadedTrace<SimpleTrace> = serde_json::from_str(&json)
+            .expect("should deserialize OK");
+        assert_eq!(loaded, trace);
+        assert_eq!(loaded.sample_rate(2), 0.25);
+        assert_eq!(loaded.sample_rate(3), 1.0);
+    }
+
+    #[test]
+    fn round_trip_sample_rates() {
+        let mut buffer = SimpleTraceBuffer::default();
+        buffer.trace_sample_rate(SimpleTrace::FooEvent.tag(), 0.5);
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+
+        let json = serde_json::to_string(&buffer).expect("should serialize OK");
+        let loaded: LoadedTrace<SimpleTrace> = serde_json::from_str(&json)
+            .expect("should deserialize OK");
+        assert_eq!(loaded.sample_rates(), buffer.sample_rates());
+        assert_eq!(loaded.sample_rate(SimpleTrace::FooEvent.tag()), 0.5);
+    }
+
+    #[test]
+    fn round_trip_entry() {
+        let entry: Entry<SimpleTrace> = Entry::new(TraceKind::Stop,
+                                                   SimpleTrace::OperationThing.tag(),
+                                                   42,
+                                                   Some(ThreadId(3)),
+                                                   Some((None, 7)),
+                                                   NsSinceEpoch(123_456))
+            .with_args([1, 2, 3, 4]);
+
+        let json = serde_json::to_string(&entry).expect("should serialize OK");
+        let loaded: Entry<SimpleTrace> = serde_json::from_str(&json)
+            .expect("should deserialize OK");
+        assert_eq!(loaded, entry);
+    }
+
+    #[test]
+    fn missing_optional_fields() {
+        let json = r#"{
+            "labels": {"0": "Foo"},
+            "entries": [{"id": 1, "tag": 0, "timestamp": 5, "kind": "Event", "extra": [1]}]
+        }"#;
+        let loaded: LoadedTrace<SimpleTrace> = serde_json::from_str(json)
+            .expect("should deserialize OK");
+
+        assert_eq!(loaded.time_base(), None);
+        assert_eq!(loaded.counters(), None);
+        assert_eq!(loaded.label(0), Some("Foo"));
+        assert_eq!(loaded.entries(),
+                   &[Entry::new(TraceKind::Event, 0, 1, None, None, NsSinceEpoch(5))][..]);
+    }
+
+    #[test]
+    fn invalid_kind() {
+        let json = r#"{
+            "labels": {},
+            "entries": [{"id": 1, "tag": 0, "timestamp": 5, "kind": "Nope"}]
+        }"#;
+        assert!(serde_json::from_str::<LoadedTrace<SimpleTrace>>(json).is_err());
+    }
+}
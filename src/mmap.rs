@@ -0,0 +1,791 @@
+//! Ring buffers that live in memory-mapped files, and survive crashes.
+//!
+//! A `RingBuffer` dies with its process, and if the process crashes, the last
+//! moments of history that would explain the crash are lost. An
+//! `MmapRingBuffer` keeps its entries in a memory-mapped file instead. When the
+//! process dies, even from a segfault, the operating system still writes the
+//! file's pages back to disk, and an `MmapReader` can read the entries that
+//! were written right up until the crash.
+//!
+//! The file begins with a fixed size header. Every integer is little-endian:
+//!
+//! | Offset | Field                  | Encoding                     |
+//! |--------|------------------------|------------------------------|
+//! | 0      | magic                  | the four bytes `MAGIC`       |
+//! | 4      | mmap layout version    | `u32`                        |
+//! | 8      | entry format version   | `u32`                        |
+//! | 12     | reserved               | `u32`, zero                  |
+//! | 16     | data capacity          | `u64`                        |
+//! | 24     | labels capacity        | `u64`                        |
+//! | 32     | time base reading      | `u64`                        |
+//! | 40     | time base wall time    | `u64`                        |
+//! | 48     | time base ticks per ns | IEEE 754 `f64` bits          |
+//! | 56     | active state           | `u64`, either `0` or `1`     |
+//! | 64     | state `0`              | four `u64`s, described below |
+//! | 96     | state `1`              | four `u64`s, described below |
+//! | 128    | labels length          | `u64`                        |
+//!
+//! The header is followed by the labels, and then the data. The labels are
+//! each a tag, byte length, and UTF-8 bytes, in unsigned LEB128. The data is a
+//! ring of entries in the `encoding` module's layout, just like a
+//! `RingBuffer`'s.
+//!
+//! The active state says where the valid data is: its `begin` offset, its
+//! `length` in bytes, the timestamp that the entry at `begin` is
+//! delta-encoded against, and the timestamp of the newest entry. The writer
+//! updates the inactive state and then switches the active state over to it,
+//! after writing the data that the new state describes. A crash at any point
+//! leaves the file consistent.
+//!
+//! Any change to this layout must bump `MMAP_VERSION`.
+//!
+//! ```no_run
+//! use eep::mmap::{MmapReader, MmapRingBuffer};
+//! use eep::simple_trace::SimpleTrace;
+//! use eep::traits::TraceSink;
+//!
+//! // In the traced process:
+//! let mut buffer = MmapRingBuffer::create("trace.eepm", 1024 * 1024).unwrap();
+//! buffer.trace_event(SimpleTrace::FooEvent, None);
+//!
+//! // After it crashed, in another process:
+//! let reader = MmapReader::<SimpleTrace>::open("trace.eepm").unwrap();
+//! let trace = reader.into_loaded_trace().unwrap();
+//! ```
+
+extern crate memmap;
+
+use clock::{Clock, MonotonicClock, TimeBase};
+use encoding::{self, DecodeError, MAX_ENCODED_ENTRY_SIZE};
+use leb128;
+use loaded_trace::LoadedTrace;
+use ring_buffer::{Entry, NsSinceEpoch, TraceKind};
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::atomic::{self, Ordering};
+use traits::{Trace, TraceId, TraceSink, TraceSinkWithId};
+
+/// The bytes that every memory-mapped ring buffer file begins with.
+pub const MAGIC: &'static [u8; 4] = b"eepm";
+
+/// The version of the file layout described in this module's documentation.
+pub const MMAP_VERSION: u32 = 1;
+
+/// The number of bytes reserved for labels in a new file.
+pub const DEFAULT_LABELS_CAPACITY: usize = 4096;
+
+const CAPACITY: usize = 16;
+const LABELS_CAPACITY: usize = 24;
+const TIME_BASE: usize = 32;
+const ACTIVE: usize = 56;
+const STATES: usize = 64;
+const STATE_SIZE: usize = 32;
+const LABELS_LENGTH: usize = 128;
+const HEADER_SIZE: usize = 136;
+
+// Where the valid data in the ring is. See the module documentation.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+struct State {
+    begin: u64,
+    length: u64,
+    begin_timestamp: u64,
+    end_timestamp: u64,
+}
+
+/// A `TraceSink` that keeps its most recent entries in a memory-mapped file,
+/// which outlives the process.
+///
+/// Like a `RingBuffer`, it evicts its oldest entries to make room for new
+/// ones. Sample rates are not recorded.
+#[derive(Debug)]
+pub struct MmapRingBuffer<T, C = MonotonicClock> {
+    map: memmap::MmapMut,
+    capacity: usize,
+    labels_capacity: usize,
+    state: State,
+    labeled: HashSet<u32>,
+    clock: C,
+    phantom: PhantomData<T>,
+}
+
+impl<T> MmapRingBuffer<T> {
+    /// Create a file at `path`, replacing any existing file, and map a ring
+    /// buffer with `capacity` bytes of data into it. Entries are timestamped
+    /// with a `MonotonicClock`.
+    ///
+    /// Returns an `InvalidInput` error if `capacity` is smaller than
+    /// `encoding::MAX_ENCODED_ENTRY_SIZE`, since the buffer could not hold
+    /// every entry.
+    pub fn create<P>(path: P, capacity: usize) -> io::Result<MmapRingBuffer<T>>
+        where P: AsRef<Path>
+    {
+        Self::create_with_clock(path, capacity, MonotonicClock::new())
+    }
+}
+
+impl<T, C> MmapRingBuffer<T, C>
+    where C: Clock
+{
+    /// Create a file at `path`, replacing any existing file, and map a ring
+    /// buffer with `capacity` bytes of data into it. Entries are timestamped
+    /// with the given clock.
+    ///
+    /// Returns an `InvalidInput` error under the same conditions as `create`.
+    pub fn create_with_clock<P>(path: P,
+                                capacity: usize,
+                                clock: C)
+                                -> io::Result<MmapRingBuffer<T, C>>
+        where P: AsRef<Path>
+    {
+        if capacity < MAX_ENCODED_ENTRY_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("a ring buffer of {} bytes cannot hold entries \
+                                               of up to {} bytes",
+                                              capacity,
+                                              MAX_ENCODED_ENTRY_SIZE)));
+        }
+        let labels_capacity = DEFAULT_LABELS_CAPACITY;
+
+        let file = try!(OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path));
+        try!(file.set_len((HEADER_SIZE + labels_capacity + capacity) as u64));
+        // Safe as long as nothing else truncates the file while it is mapped.
+        let mut map = try!(unsafe { memmap::MmapMut::map_mut(&file) });
+
+        let time_base = clock.time_base();
+        let state = State {
+            begin_timestamp: time_base.reading(),
+            end_timestamp: time_base.reading(),
+            ..State::default()
+        };
+
+        map[..4].copy_from_slice(MAGIC);
+        write_u32(&mut map, 4, MMAP_VERSION);
+        write_u32(&mut map, 8, encoding::FORMAT_VERSION);
+        write_u64(&mut map, CAPACITY, capacity as u64);
+        write_u64(&mut map, LABELS_CAPACITY, labels_capacity as u64);
+        write_u64(&mut map, TIME_BASE, time_base.reading());
+        write_u64(&mut map, TIME_BASE + 8, time_base.wall().0);
+        write_u64(&mut map, TIME_BASE + 16, time_base.ticks_per_ns().to_bits());
+        write_state(&mut map, 0, &state);
+        write_u64(&mut map, ACTIVE, 0);
+
+        Ok(MmapRingBuffer {
+            map: map,
+            capacity: capacity,
+            labels_capacity: labels_capacity,
+            state: state,
+            labeled: HashSet::new(),
+            clock: clock,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Get the clock that timestamps this buffer's entries.
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Ask the operating system to write the file's pages back to disk now,
+    /// and wait until it has. This is only needed to survive the whole machine
+    /// going down; the pages are written back after the process exits or
+    /// crashes anyway.
+    pub fn flush(&self) -> io::Result<()> {
+        self.map.flush()
+    }
+
+    fn write(&mut self, entry: &Entry<T>) {
+        let mut bytes = [0; MAX_ENCODED_ENTRY_SIZE];
+        let new_data_len =
+            encoding::encode(entry, NsSinceEpoch(self.state.end_timestamp), &mut &mut bytes[..])
+                .expect("MAX_ENCODED_ENTRY_SIZE should be large enough for any entry");
+        let capacity = self.capacity;
+
+        if capacity - (self.state.length as usize) < new_data_len {
+            while capacity - (self.state.length as usize) < new_data_len {
+                let begin = self.state.begin as usize;
+                let (evicted, size) = read_at::<T>(self.data(), begin, self.state.begin_timestamp);
+                self.state.begin = ((begin + size) % capacity) as u64;
+                self.state.length -= size as u64;
+                self.state.begin_timestamp = evicted.timestamp().0;
+            }
+            // The evicted entries must be out of the active state before the
+            // new entry overwrites them.
+            self.commit();
+        }
+
+        let end = ((self.state.begin + self.state.length) as usize) % capacity;
+        {
+            let data = self.data_mut();
+            if end + new_data_len > capacity {
+                let middle = capacity - end;
+                data[end..capacity].copy_from_slice(&bytes[..middle]);
+                data[0..new_data_len - middle].copy_from_slice(&bytes[middle..new_data_len]);
+            } else {
+                data[end..end + new_data_len].copy_from_slice(&bytes[..new_data_len]);
+            }
+        }
+
+        self.state.length += new_data_len as u64;
+        self.state.end_timestamp = entry.timestamp().0;
+        self.commit();
+    }
+
+    // Write the current state to the inactive slot, and then make it active.
+    fn commit(&mut self) {
+        let inactive = 1 - read_u64(&self.map, ACTIVE) as usize;
+        let state = self.state;
+        write_state(&mut self.map, inactive, &state);
+        // A crash can happen between any two writes, so they must reach the
+        // map in program order.
+        atomic::compiler_fence(Ordering::SeqCst);
+        write_u64(&mut self.map, ACTIVE, inactive as u64);
+        atomic::compiler_fence(Ordering::SeqCst);
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.map[HEADER_SIZE + self.labels_capacity..]
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.map[HEADER_SIZE + self.labels_capacity..]
+    }
+}
+
+impl<T, C> MmapRingBuffer<T, C>
+    where T: Trace,
+          C: Clock
+{
+    // Append the tag's label to the labels, if it is not already there and
+    // there is room for it.
+    fn label(&mut self, tag: u32) {
+        if !self.labeled.insert(tag) {
+            return;
+        }
+
+        let label = T::label(tag).as_bytes();
+        let mut bytes = vec![];
+        leb128::write::unsigned(&mut bytes, tag as u64).unwrap();
+        leb128::write::unsigned(&mut bytes, label.len() as u64).unwrap();
+        bytes.extend_from_slice(label);
+
+        let length = read_u64(&self.map, LABELS_LENGTH) as usize;
+        if length + bytes.len() > self.labels_capacity {
+            return;
+        }
+        self.map[HEADER_SIZE + length..HEADER_SIZE + length + bytes.len()]
+            .copy_from_slice(&bytes);
+        atomic::compiler_fence(Ordering::SeqCst);
+        write_u64(&mut self.map, LABELS_LENGTH, (length + bytes.len()) as u64);
+        atomic::compiler_fence(Ordering::SeqCst);
+    }
+
+    fn trace(&mut self, kind: TraceKind, id: T::Id, trace: T, why: Option<T::Id>) {
+        let tag = trace.tag();
+        self.label(tag);
+        let entry = Entry::new(kind,
+                               tag,
+                               id.u32(),
+                               id.thread(),
+                               why.map(|id| (id.thread(), id.u32())),
+                               NsSinceEpoch(self.clock.now()))
+            .with_args(trace.args());
+        self.write(&entry);
+    }
+}
+
+impl<T, C> TraceSink<T> for MmapRingBuffer<T, C>
+    where T: Trace,
+          C: Clock
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.trace_event_with_id(id, trace, why);
+        id
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.trace_start_with_id(id, trace, why);
+        id
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        self.trace(TraceKind::Stop, id, trace, None);
+    }
+
+    fn trace_dropped(&mut self, tag: u32, count: u64) {
+        self.label(tag);
+        let entry = Entry::new(TraceKind::Dropped,
+                               tag,
+                               0,
+                               None,
+                               None,
+                               NsSinceEpoch(self.clock.now()))
+            .with_args([count, 0, 0, 0]);
+        self.write(&entry);
+    }
+}
+
+impl<T, C> TraceSinkWithId<T> for MmapRingBuffer<T, C>
+    where T: Trace,
+          C: Clock
+{
+    fn trace_event_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        self.trace(TraceKind::Event, id, trace, why);
+    }
+
+    fn trace_start_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        self.trace(TraceKind::Start, id, trace, why);
+    }
+}
+
+/// An error that occurred while reading a memory-mapped ring buffer file.
+#[derive(Debug)]
+pub enum MmapError {
+    /// There was an underlying IO error, for example the file is shorter than
+    /// its header says.
+    Io(io::Error),
+    /// The file does not begin with `MAGIC`.
+    InvalidMagic,
+    /// The file's layout or entry format version is not supported.
+    UnsupportedVersion(u32),
+    /// The time base's ticks per nanosecond is not positive.
+    InvalidTimeBase,
+    /// A label is not valid UTF-8, or its tag does not fit in a `u32`.
+    InvalidLabel,
+    /// The active state does not describe data within the ring.
+    InvalidState,
+    /// An entry could not be decoded.
+    Decode(DecodeError),
+}
+
+impl fmt::Display for MmapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MmapError::Io(ref e) => write!(f, "could not read ring buffer file: {}", e),
+            MmapError::InvalidMagic => write!(f, "not an eep ring buffer file"),
+            MmapError::UnsupportedVersion(v) => {
+                write!(f, "unsupported ring buffer file version: {}", v)
+            }
+            MmapError::InvalidTimeBase => write!(f, "invalid time base"),
+            MmapError::InvalidLabel => write!(f, "invalid label"),
+            MmapError::InvalidState => write!(f, "invalid ring buffer state"),
+            MmapError::Decode(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for MmapError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            MmapError::Io(ref e) => Some(e),
+            MmapError::Decode(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MmapError {
+    fn from(e: io::Error) -> MmapError {
+        MmapError::Io(e)
+    }
+}
+
+impl From<DecodeError> for MmapError {
+    fn from(e: DecodeError) -> MmapError {
+        MmapError::Decode(e)
+    }
+}
+
+/// Reads the entries in a file that an `MmapRingBuffer` wrote, oldest first.
+///
+/// The file is read as of when the reader is constructed, whether or not the
+/// process that wrote it is still running. Entries' timestamps are converted
+/// to wall-clock time with the file's time base. Iteration stops after the
+/// first error.
+#[derive(Debug)]
+pub struct MmapReader<T> {
+    time_base: TimeBase,
+    labels: HashMap<u32, String>,
+    data: io::Cursor<Vec<u8>>,
+    prev_timestamp: u64,
+    done: bool,
+    phantom: PhantomData<T>,
+}
+
+impl<T> MmapReader<T> {
+    /// Read the file at `path`.
+    pub fn open<P>(path: P) -> Result<MmapReader<T>, MmapError>
+        where P: AsRef<Path>
+    {
+        let mut bytes = vec![];
+        try!(try!(File::open(path)).read_to_end(&mut bytes));
+        MmapReader::from_bytes(&bytes)
+    }
+
+    /// Read a file's contents.
+    pub fn from_bytes(bytes: &[u8]) -> Result<MmapReader<T>, MmapError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(MmapError::Io(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                    "file ended in the header")));
+        }
+        if &bytes[..4] != MAGIC {
+            return Err(MmapError::InvalidMagic);
+        }
+        for &(offset, supported) in &[(4, MMAP_VERSION), (8, encoding::FORMAT_VERSION)] {
+            let version = read_u32(bytes, offset);
+            if version != supported {
+                return Err(MmapError::UnsupportedVersion(version));
+            }
+        }
+
+        let capacity = read_u64(bytes, CAPACITY) as usize;
+        let labels_capacity = read_u64(bytes, LABELS_CAPACITY) as usize;
+        let data_start = HEADER_SIZE + labels_capacity;
+        if labels_capacity > bytes.len() || capacity > bytes.len() ||
+           data_start + capacity > bytes.len() {
+            return Err(MmapError::Io(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                    "file ended in the ring buffer")));
+        }
+
+        let ticks_per_ns = f64::from_bits(read_u64(bytes, TIME_BASE + 16));
+        if !(ticks_per_ns > 0.0) {
+            return Err(MmapError::InvalidTimeBase);
+        }
+        let time_base = TimeBase::with_ticks_per_ns(read_u64(bytes, TIME_BASE),
+                                                    NsSinceEpoch(read_u64(bytes, TIME_BASE + 8)),
+                                                    ticks_per_ns);
+
+        let labels_length = read_u64(bytes, LABELS_LENGTH) as usize;
+        if labels_length > labels_capacity {
+            return Err(MmapError::InvalidLabel);
+        }
+        let labels = try!(read_labels(&bytes[HEADER_SIZE..HEADER_SIZE + labels_length]));
+
+        let active = read_u64(bytes, ACTIVE);
+        if active > 1 {
+            return Err(MmapError::InvalidState);
+        }
+        let state = read_state(bytes, active as usize);
+        if state.begin as usize >= capacity || state.length as usize > capacity {
+            return Err(MmapError::InvalidState);
+        }
+
+        // Copy the valid data out of the ring, so that it is contiguous.
+        let ring = &bytes[data_start..data_start + capacity];
+        let (begin, length) = (state.begin as usize, state.length as usize);
+        let mut data = Vec::with_capacity(length);
+        if begin + length > capacity {
+            data.extend_from_slice(&ring[begin..]);
+            data.extend_from_slice(&ring[..begin + length - capacity]);
+        } else {
+            data.extend_from_slice(&ring[begin..begin + length]);
+        }
+
+        Ok(MmapReader {
+            time_base: time_base,
+            labels: labels,
+            data: io::Cursor::new(data),
+            prev_timestamp: state.begin_timestamp,
+            done: false,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Get the time base of the clock that timestamped the entries.
+    pub fn time_base(&self) -> TimeBase {
+        self.time_base
+    }
+
+    /// Get the labels of the entries' tags.
+    pub fn labels(&self) -> &HashMap<u32, String> {
+        &self.labels
+    }
+
+    /// Read the rest of the entries into a `LoadedTrace`.
+    pub fn into_loaded_trace(mut self) -> Result<LoadedTrace<T>, MmapError> {
+        let mut entries = vec![];
+        for entry in self.by_ref() {
+            entries.push(try!(entry));
+        }
+        Ok(LoadedTrace::new(Some(self.time_base), self.labels, entries))
+    }
+}
+
+impl<T> Iterator for MmapReader<T> {
+    type Item = Result<Entry<T>, MmapError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.data.position() == self.data.get_ref().len() as u64 {
+            return None;
+        }
+
+        match encoding::decode::<T, _>(&mut self.data, NsSinceEpoch(self.prev_timestamp)) {
+            Ok(entry) => {
+                self.prev_timestamp = entry.timestamp().0;
+                let wall = self.time_base.to_wall(entry.timestamp().0);
+                Some(Ok(Entry::new(entry.kind(),
+                                   entry.tag(),
+                                   entry.id(),
+                                   entry.thread(),
+                                   entry.why(),
+                                   wall)
+                    .with_args(entry.args())))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e.into()))
+            }
+        }
+    }
+}
+
+fn read_labels(mut bytes: &[u8]) -> Result<HashMap<u32, String>, MmapError> {
+    let mut labels = HashMap::new();
+    while !bytes.is_empty() {
+        let tag = try!(leb128::read::unsigned(&mut bytes).map_err(|_| MmapError::InvalidLabel));
+        let len = try!(leb128::read::unsigned(&mut bytes).map_err(|_| MmapError::InvalidLabel));
+        if tag > ::std::u32::MAX as u64 || len > bytes.len() as u64 {
+            return Err(MmapError::InvalidLabel);
+        }
+        let (label, rest) = bytes.split_at(len as usize);
+        let label = try!(String::from_utf8(label.to_vec()).map_err(|_| MmapError::InvalidLabel));
+        labels.insert(tag as u32, label);
+        bytes = rest;
+    }
+    Ok(labels)
+}
+
+// Decode the entry starting at `idx` in the ring `data`, whose timestamp is
+// relative to `timestamp`. Returns the entry and its encoded size.
+fn read_at<T>(data: &[u8], idx: usize, timestamp: u64) -> (Entry<T>, usize) {
+    let capacity = data.len();
+    let mut bytes = [0; MAX_ENCODED_ENTRY_SIZE];
+
+    if idx + MAX_ENCODED_ENTRY_SIZE > capacity {
+        let middle = capacity - idx;
+        bytes[..middle].copy_from_slice(&data[idx..]);
+        bytes[middle..].copy_from_slice(&data[..MAX_ENCODED_ENTRY_SIZE - middle]);
+    } else {
+        bytes.copy_from_slice(&data[idx..idx + MAX_ENCODED_ENTRY_SIZE]);
+    }
+
+    let mut cursor = &bytes[..];
+    let entry = encoding::decode(&mut cursor, NsSinceEpoch(timestamp))
+        .expect("ring buffer should contain valid entries");
+    (entry, MAX_ENCODED_ENTRY_SIZE - cursor.len())
+}
+
+fn read_state(bytes: &[u8], slot: usize) -> State {
+    let offset = STATES + slot * STATE_SIZE;
+    State {
+        begin: read_u64(bytes, offset),
+        length: read_u64(bytes, offset + 8),
+        begin_timestamp: read_u64(bytes, offset + 16),
+        end_timestamp: read_u64(bytes, offset + 24),
+    }
+}
+
+fn write_state(bytes: &mut [u8], slot: usize, state: &State) {
+    let offset = STATES + slot * STATE_SIZE;
+    write_u64(bytes, offset, state.begin);
+    write_u64(bytes, offset + 8, state.length);
+    write_u64(bytes, offset + 16, state.begin_timestamp);
+    write_u64(bytes, offset + 24, state.end_timestamp);
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    bytes[offset..offset + 4]
+        .iter()
+        .enumerate()
+        .fold(0, |n, (i, &byte)| n | (byte as u32) << (8 * i))
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, n: u32) {
+    for (i, byte) in bytes[offset..offset + 4].iter_mut().enumerate() {
+        *byte = (n >> (8 * i)) as u8;
+    }
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    bytes[offset..offset + 8]
+        .iter()
+        .enumerate()
+        .fold(0, |n, (i, &byte)| n | (byte as u64) << (8 * i))
+}
+
+fn write_u64(bytes: &mut [u8], offset: usize, n: u64) {
+    for (i, byte) in bytes[offset..offset + 8].iter_mut().enumerate() {
+        *byte = (n >> (8 * i)) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clock::WallClock;
+    use simple_trace::SimpleTrace;
+    use std::env;
+    use std::fs;
+    use std::mem;
+    use std::path::PathBuf;
+    use std::process;
+    use traits::{Trace, TraceSink};
+
+    // A path for the named test's file, which is removed when it is dropped.
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> TempPath {
+            TempPath(env::temp_dir().join(format!("eep-mmap-{}-{}.eepm", name, process::id())))
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn trace_some<S: TraceSink<SimpleTrace>>(sink: &mut S, n: usize) {
+        for _ in 0..n {
+            let event = sink.trace_event(SimpleTrace::FooEvent, None);
+            let id = sink.trace_start(SimpleTrace::OperationThing, Some(event));
+            sink.trace_stop(id, SimpleTrace::OperationThing);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = TempPath::new("round-trip");
+        let mut buffer = MmapRingBuffer::create_with_clock(&path.0, 4096, WallClock).unwrap();
+        trace_some(&mut buffer, 10);
+        buffer.flush().unwrap();
+
+        let reader = MmapReader::<SimpleTrace>::open(&path.0).unwrap();
+        assert_eq!(reader.time_base(), WallClock.time_base());
+        let trace = reader.into_loaded_trace().unwrap();
+        assert_eq!(trace.entries().len(), 30);
+        assert_eq!(trace.labels().len(), 2);
+        assert_eq!(trace.label(SimpleTrace::FooEvent.tag()), Some("Foo"));
+        for pair in trace.entries().windows(2) {
+            assert!(pair[0].timestamp() <= pair[1].timestamp());
+        }
+        assert_eq!(trace.entries()[1].why(), Some((None, trace.entries()[0].id())));
+    }
+
+    #[test]
+    fn with_roll_over() {
+        let path = TempPath::new("roll-over");
+        let mut buffer = MmapRingBuffer::create_with_clock(&path.0, 100, WallClock).unwrap();
+        trace_some(&mut buffer, 100);
+
+        let trace = MmapReader::<SimpleTrace>::open(&path.0)
+            .unwrap()
+            .into_loaded_trace()
+            .unwrap();
+        assert!(trace.entries().len() > 1);
+        assert!(trace.entries().len() < 300);
+        let last = trace.entries().last().unwrap();
+        assert_eq!(last.kind(), TraceKind::Stop);
+        assert_eq!(last.tag(), SimpleTrace::OperationThing.tag());
+    }
+
+    #[test]
+    fn survives_without_drop() {
+        let path = TempPath::new("no-drop");
+        let mut buffer = MmapRingBuffer::create_with_clock(&path.0, 4096, WallClock).unwrap();
+        trace_some(&mut buffer, 3);
+        // Never unmapped nor flushed, as if the process crashed.
+        mem::forget(buffer);
+
+        let trace = MmapReader::<SimpleTrace>::open(&path.0)
+            .unwrap()
+            .into_loaded_trace()
+            .unwrap();
+        assert_eq!(trace.entries().len(), 9);
+    }
+
+    #[test]
+    fn inactive_state_is_ignored() {
+        let path = TempPath::new("inactive");
+        {
+            let mut buffer = MmapRingBuffer::create_with_clock(&path.0, 4096, WallClock)
+                .unwrap();
+            trace_some(&mut buffer, 2);
+        }
+
+        // Scribble over the inactive state, as if a crash interrupted the
+        // write of the next one.
+        let mut bytes = fs::read(&path.0).unwrap();
+        let inactive = 1 - read_u64(&bytes, ACTIVE) as usize;
+        for byte in &mut bytes[STATES + inactive * STATE_SIZE..][..STATE_SIZE] {
+            *byte = 0xff;
+        }
+
+        let trace = MmapReader::<SimpleTrace>::from_bytes(&bytes)
+            .unwrap()
+            .into_loaded_trace()
+            .unwrap();
+        assert_eq!(trace.entries().len(), 6);
+    }
+
+    #[test]
+    fn too_small() {
+        let path = TempPath::new("too-small");
+        let error = MmapRingBuffer::<SimpleTrace, _>::create_with_clock(&path.0, 10, WallClock)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn invalid_files() {
+        let path = TempPath::new("invalid");
+        {
+            let mut buffer = MmapRingBuffer::create_with_clock(&path.0, 4096, WallClock)
+                .unwrap();
+            trace_some(&mut buffer, 1);
+        }
+        let bytes = fs::read(&path.0).unwrap();
+
+        match MmapReader::<SimpleTrace>::from_bytes(&bytes[..HEADER_SIZE - 1]) {
+            Err(MmapError::Io(_)) => {}
+            otherwise => panic!("expected an IO error, got {:?}", otherwise),
+        }
+        match MmapReader::<SimpleTrace>::from_bytes(&bytes[..bytes.len() - 1]) {
+            Err(MmapError::Io(_)) => {}
+            otherwise => panic!("expected an IO error, got {:?}", otherwise),
+        }
+
+        let mut invalid = bytes.clone();
+        invalid[0] = b'x';
+        match MmapReader::<SimpleTrace>::from_bytes(&invalid) {
+            Err(MmapError::InvalidMagic) => {}
+            otherwise => panic!("expected invalid magic, got {:?}", otherwise),
+        }
+
+        let mut invalid = bytes.clone();
+        write_u32(&mut invalid, 4, MMAP_VERSION + 1);
+        match MmapReader::<SimpleTrace>::from_bytes(&invalid) {
+            Err(MmapError::UnsupportedVersion(v)) => assert_eq!(v, MMAP_VERSION + 1),
+            otherwise => panic!("expected an unsupported version, got {:?}", otherwise),
+        }
+
+        let mut invalid = bytes.clone();
+        write_u64(&mut invalid, ACTIVE, 2);
+        match MmapReader::<SimpleTrace>::from_bytes(&invalid) {
+            Err(MmapError::InvalidState) => {}
+            otherwise => panic!("expected an invalid state, got {:?}", otherwise),
+        }
+    }
+}
@@ -0,0 +1,578 @@
+//! Per-thread ring buffers and a registry for collecting them into a single
+//! timeline.
+//!
+//! Each thread traces into its own `RingBuffer<T>` through a `ThreadBuffer<T>`,
+//! so threads never contend with each other while tracing. The buffers are
+//! registered in a `BufferRegistry<T>`, which can later collect every thread's
+//! entries and merge them into one timestamp-ordered timeline.
+//!
+//! Each process has a global registry for every trace type, which is created
+//! on first use. A `ThreadLocalBuffer<T>` traces into the calling thread's
+//! buffer in that registry, registering one the first time the thread traces:
+//!
+//! ```
+//! use eep::registry::{BufferRegistry, ThreadLocalBuffer};
+//! use eep::simple_trace::SimpleTrace;
+//! use eep::traits::TraceSink;
+//! use std::thread;
+//!
+//! thread::spawn(|| {
+//!     let mut buffer = ThreadLocalBuffer::new();
+//!     let id = buffer.trace_start(SimpleTrace::OperationThing, None);
+//!     buffer.trace_stop(id, SimpleTrace::OperationThing);
+//! }).join().unwrap();
+//!
+//! assert_eq!(BufferRegistry::<SimpleTrace>::global().merged().count(), 2);
+//! ```
+//!
+//! Registries can also be created and shared explicitly:
+//!
+//! ```
+//! use eep::registry::BufferRegistry;
+//! use eep::simple_trace::SimpleTrace;
+//! use eep::traits::TraceSink;
+//! use std::sync::Arc;
+//! use std::thread;
+//!
+//! let registry = Arc::new(BufferRegistry::new(4096));
+//!
+//! let workers: Vec<_> = (0..4).map(|_| {
+//!     let registry = registry.clone();
+//!     thread::spawn(move || {
+//!         let mut buffer = registry.register();
+//!         let id = buffer.trace_start(SimpleTrace::OperationThing, None);
+//!         buffer.trace_stop(id, SimpleTrace::OperationThing);
+//!     })
+//! }).collect();
+//!
+//! for worker in workers {
+//!     worker.join().unwrap();
+//! }
+//!
+//! assert_eq!(registry.merged().count(), 8);
+//! ```
+
+use clock::MonotonicClock;
+use ring_buffer::{Entry, RingBuffer};
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::vec;
+use traits::{Trace, TraceId, TraceSink, TraceSinkWithId};
+
+/// The capacity of each thread's buffer in a global registry, until it is
+/// changed with `BufferRegistry::resize`.
+pub const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+// The global registry for each trace type, keyed by the type's `TypeId`. Each
+// value is an `Arc<BufferRegistry<T>>`.
+static GLOBAL_REGISTRIES: Mutex<Vec<(TypeId, Box<dyn Any + Send + Sync>)>> =
+    Mutex::new(Vec::new());
+
+// The calling thread's buffer in each global registry, keyed by the trace
+// type's `TypeId`. Each value is a `ThreadBuffer<T>`.
+thread_local!(static THREAD_BUFFERS: RefCell<Vec<(TypeId, Box<dyn Any>)>> =
+    RefCell::new(vec![]));
+
+/// A registry of every thread's `RingBuffer<T>`.
+///
+/// Buffers stay registered after their thread exits, so that its history can
+/// still be collected. Every buffer shares the registry's clock, so that their
+/// timestamps can be compared with each other.
+#[derive(Debug)]
+pub struct BufferRegistry<T> {
+    capacity: AtomicUsize,
+    clock: MonotonicClock,
+    buffers: Mutex<Vec<Arc<Mutex<RingBuffer<T>>>>>,
+}
+
+impl<T> BufferRegistry<T> {
+    /// Construct a new, empty `BufferRegistry` whose thread buffers each have
+    /// the given capacity.
+    pub fn new(capacity: usize) -> BufferRegistry<T> {
+        BufferRegistry {
+            capacity: AtomicUsize::new(capacity),
+            clock: MonotonicClock::new(),
+            buffers: Mutex::new(vec![]),
+        }
+    }
+
+    /// Create a new buffer for the calling thread and register it.
+    pub fn register(&self) -> ThreadBuffer<T> {
+        let capacity = self.capacity.load(Ordering::SeqCst);
+        let buffer = Arc::new(Mutex::new(RingBuffer::with_clock(capacity, self.clock)));
+        self.buffers.lock().unwrap().push(buffer.clone());
+        ThreadBuffer(buffer)
+    }
+
+    /// Change the capacity of every registered buffer, and of buffers that are
+    /// registered later, keeping their most recent entries. See
+    /// `RingBuffer::resize`.
+    pub fn resize(&self, capacity: usize) {
+        let buffers = self.buffers.lock().unwrap();
+        self.capacity.store(capacity, Ordering::SeqCst);
+        for buffer in buffers.iter() {
+            buffer.lock().unwrap().resize(capacity);
+        }
+    }
+
+    /// Get the number of registered buffers.
+    pub fn len(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+
+    /// Return `true` if there are no registered buffers, `false` otherwise.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove every registered buffer's entries and merge them into a single
+    /// timestamp-ordered timeline.
+    ///
+    /// Unlike `merged`, the entries are removed from the buffers, so a
+    /// background thread can call this periodically to harvest every entry
+    /// exactly once. Each buffer is only locked while its entries are copied
+    /// out of it.
+    pub fn drain(&self) -> Merge<T, vec::IntoIter<Entry<T>>> {
+        let buffers = self.buffers.lock().unwrap();
+        let snapshots: Vec<_> = buffers.iter()
+            .map(|b| {
+                let mut buffer = b.lock().unwrap();
+                let snapshot = buffer.snapshot();
+                buffer.clear();
+                snapshot
+            })
+            .collect();
+        merge(snapshots.into_iter().map(|s| s.iter().collect::<Vec<_>>().into_iter()))
+    }
+}
+
+impl<T> BufferRegistry<T>
+    where T: 'static + Send
+{
+    /// Get the process-wide registry for `T`, creating it with a capacity of
+    /// `DEFAULT_CAPACITY` the first time it is requested.
+    pub fn global() -> Arc<BufferRegistry<T>> {
+        let mut registries = GLOBAL_REGISTRIES.lock().unwrap();
+        let type_id = TypeId::of::<T>();
+        if let Some(registry) = registries.iter().find(|r| r.0 == type_id) {
+            return registry.1.downcast_ref::<Arc<BufferRegistry<T>>>().unwrap().clone();
+        }
+
+        let registry = Arc::new(BufferRegistry::new(DEFAULT_CAPACITY));
+        registries.push((type_id, Box::new(registry.clone())));
+        registry
+    }
+}
+
+impl<T> BufferRegistry<T>
+    where T: Clone
+{
+    /// Get a copy of every registered buffer.
+    ///
+    /// Each buffer is briefly locked while it is copied, so its thread will
+    /// only block if it traces at that same moment.
+    pub fn collect(&self) -> Vec<RingBuffer<T>> {
+        let buffers = self.buffers.lock().unwrap();
+        buffers.iter().map(|b| b.lock().unwrap().clone()).collect()
+    }
+
+    /// Collect every registered buffer's entries and merge them into a single
+    /// timestamp-ordered timeline.
+    pub fn merged(&self) -> Merge<T, vec::IntoIter<Entry<T>>> {
+        merge(self.collect().iter().map(|b| b.iter().collect::<Vec<_>>().into_iter()))
+    }
+}
+
+/// A thread's handle to its registered `RingBuffer<T>`.
+///
+/// Obtain one with `BufferRegistry::register`.
+///
+/// The buffer is behind a mutex that each trace locks, so that the registry
+/// can read it from other threads. Only its own thread traces into it, so the
+/// lock is uncontended unless `collect`, `merged`, `drain` or `resize` is
+/// running on the registry at that moment. Then the trace blocks until that
+/// call has finished copying, clearing or resizing this thread's buffer.
+#[derive(Debug)]
+pub struct ThreadBuffer<T>(Arc<Mutex<RingBuffer<T>>>);
+
+/// Call `f` with the calling thread's buffer in the global registry for `T`,
+/// registering a buffer for the thread the first time it is called.
+///
+/// Returns `None`, without calling `f`, if the thread is exiting and its
+/// buffers have already been destroyed, for example when called from another
+/// thread-local's destructor.
+///
+/// See `BufferRegistry::global`.
+pub fn with_thread_buffer<T, F, R>(f: F) -> Option<R>
+    where T: 'static + Send,
+          F: FnOnce(&mut ThreadBuffer<T>) -> R
+{
+    // Clone the handle out of the thread-local list, so that `f` can itself
+    // call `with_thread_buffer`.
+    let buffer = THREAD_BUFFERS.try_with(|buffers| {
+        let mut buffers = buffers.borrow_mut();
+        let type_id = TypeId::of::<T>();
+        if let Some(buffer) = buffers.iter().find(|b| b.0 == type_id) {
+            let buffer = buffer.1.downcast_ref::<ThreadBuffer<T>>().unwrap();
+            return ThreadBuffer(buffer.0.clone());
+        }
+
+        let buffer = BufferRegistry::<T>::global().register();
+        buffers.push((type_id, Box::new(ThreadBuffer(buffer.0.clone()))));
+        buffer
+    });
+    buffer.ok().map(|mut buffer| f(&mut buffer))
+}
+
+/// A `TraceSink` that traces into the calling thread's buffer in the global
+/// registry for `T`.
+///
+/// It holds no state of its own, so it can be constructed wherever it is
+/// needed. Traces made while the thread is exiting, after its buffers have
+/// been destroyed, are skipped. See `with_thread_buffer`.
+#[derive(Debug)]
+pub struct ThreadLocalBuffer<T>(PhantomData<T>);
+
+impl<T> ThreadLocalBuffer<T> {
+    /// Construct a new `ThreadLocalBuffer`.
+    pub fn new() -> ThreadLocalBuffer<T> {
+        ThreadLocalBuffer(PhantomData)
+    }
+}
+
+impl<T> Default for ThreadLocalBuffer<T> {
+    fn default() -> ThreadLocalBuffer<T> {
+        ThreadLocalBuffer::new()
+    }
+}
+
+impl<T> TraceSink<T> for ThreadLocalBuffer<T>
+    where T: 'static + Trace + Send
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.trace_event_with_id(id, trace, why);
+        id
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.trace_start_with_id(id, trace, why);
+        id
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        with_thread_buffer(|b: &mut ThreadBuffer<T>| b.trace_stop(id, trace));
+    }
+
+    fn trace_sample_rate(&mut self, tag: u32, rate: f64) {
+        with_thread_buffer(|b: &mut ThreadBuffer<T>| b.trace_sample_rate(tag, rate));
+    }
+
+    fn trace_dropped(&mut self, tag: u32, count: u64) {
+        with_thread_buffer(|b: &mut ThreadBuffer<T>| b.trace_dropped(tag, count));
+    }
+}
+
+impl<T> TraceSinkWithId<T> for ThreadLocalBuffer<T>
+    where T: 'static + Trace + Send
+{
+    fn trace_event_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        with_thread_buffer(|b: &mut ThreadBuffer<T>| b.trace_event_with_id(id, trace, why));
+    }
+
+    fn trace_start_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        with_thread_buffer(|b: &mut ThreadBuffer<T>| b.trace_start_with_id(id, trace, why));
+    }
+}
+
+impl<T> TraceSink<T> for ThreadBuffer<T>
+    where T: Trace
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.trace_event_with_id(id, trace, why);
+        id
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.trace_start_with_id(id, trace, why);
+        id
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        self.0.lock().unwrap().trace_stop(id, trace)
+    }
+
+    fn trace_sample_rate(&mut self, tag: u32, rate: f64) {
+        self.0.lock().unwrap().trace_sample_rate(tag, rate)
+    }
+
+    fn trace_dropped(&mut self, tag: u32, count: u64) {
+        self.0.lock().unwrap().trace_dropped(tag, count)
+    }
+}
+
+impl<T> TraceSinkWithId<T> for ThreadBuffer<T>
+    where T: Trace
+{
+    fn trace_event_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        self.0.lock().unwrap().trace_event_with_id(id, trace, why)
+    }
+
+    fn trace_start_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        self.0.lock().unwrap().trace_start_with_id(id, trace, why)
+    }
+}
+
+/// Merge several timestamp-ordered sequences of entries into a single
+/// timestamp-ordered sequence.
+///
+/// Entries with equal timestamps are yielded in the order of the sequences
+/// they came from.
+pub fn merge<T, I, J>(entries: J) -> Merge<T, I>
+    where I: Iterator<Item = Entry<T>>,
+          J: IntoIterator<Item = I>
+{
+    Merge {
+        heads: entries.into_iter()
+            .map(|mut iter| (iter.next(), iter))
+            .collect(),
+    }
+}
+
+/// An iterator over the merge of several timestamp-ordered sequences of
+/// entries.
+///
+/// See `merge`.
+#[derive(Clone, Debug)]
+pub struct Merge<T, I> {
+    // The next entry of each sequence, and the rest of the sequence.
+    heads: Vec<(Option<Entry<T>>, I)>,
+}
+
+impl<T, I> Iterator for Merge<T, I>
+    where I: Iterator<Item = Entry<T>>
+{
+    type Item = Entry<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut earliest: Option<usize> = None;
+        for (i, &(ref head, _)) in self.heads.iter().enumerate() {
+            if let Some(ref entry) = *head {
+                earliest = match earliest {
+                    Some(e) if self.heads[e].0.as_ref().unwrap().timestamp() <=
+                               entry.timestamp() => Some(e),
+                    _ => Some(i),
+                };
+            }
+        }
+
+        earliest.and_then(|i| {
+            let (ref mut head, ref mut iter) = self.heads[i];
+            let next = iter.next();
+            ::std::mem::replace(head, next)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring_buffer::{Entry, NsSinceEpoch, TraceKind};
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use threaded_trace_id::ThreadedTraceId;
+    use traits::{Trace, TraceId, TraceSink};
+
+    #[derive(Copy, Clone, Debug)]
+    struct Work;
+
+    impl Trace for Work {
+        type Id = ThreadedTraceId;
+
+        fn label(_: u32) -> &'static str {
+            "Work"
+        }
+
+        fn tag(&self) -> u32 {
+            0
+        }
+    }
+
+    fn entry(timestamp: u64, id: u32) -> Entry<Work> {
+        Entry::new(TraceKind::Event, 0, id, None, None, NsSinceEpoch(timestamp))
+    }
+
+    #[test]
+    fn merge_is_timestamp_ordered() {
+        let a = vec![entry(1, 0), entry(4, 1), entry(5, 2)];
+        let b = vec![];
+        let c = vec![entry(2, 3), entry(4, 4), entry(6, 5)];
+
+        let merged: Vec<_> = merge(vec![a.into_iter(), b.into_iter(), c.into_iter()])
+            .map(|e| e.id())
+            .collect();
+        assert_eq!(merged, vec![0, 3, 1, 4, 2, 5]);
+    }
+
+    #[test]
+    fn collects_every_thread() {
+        let registry = Arc::new(BufferRegistry::new(4096));
+        // Keep every worker alive until they have all traced, so that their
+        // thread IDs are distinct.
+        let barrier = Arc::new(Barrier::new(4));
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let registry = registry.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let mut buffer = registry.register();
+                    for _ in 0..10 {
+                        let id = buffer.trace_start(Work, None);
+                        buffer.trace_stop(id, Work);
+                    }
+                    barrier.wait();
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        assert_eq!(registry.len(), 4);
+
+        let merged: Vec<_> = registry.merged().collect();
+        assert_eq!(merged.len(), 4 * 20);
+        for pair in merged.windows(2) {
+            assert!(pair[0].timestamp() <= pair[1].timestamp());
+        }
+
+        let mut threads: Vec<_> = merged.iter().map(|e| e.thread().unwrap().0).collect();
+        threads.sort();
+        threads.dedup();
+        assert_eq!(threads.len(), 4);
+    }
+
+    // Only used by `registers_each_thread_globally`, so that no other test
+    // shares its global registry.
+    #[derive(Copy, Clone, Debug)]
+    struct GlobalWork;
+
+    impl Trace for GlobalWork {
+        type Id = ThreadedTraceId;
+
+        fn label(_: u32) -> &'static str {
+            "GlobalWork"
+        }
+
+        fn tag(&self) -> u32 {
+            0
+        }
+    }
+
+    #[test]
+    fn registers_each_thread_globally() {
+        let barrier = Arc::new(Barrier::new(4));
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let mut buffer = ThreadLocalBuffer::new();
+                    for _ in 0..10 {
+                        let id = buffer.trace_start(GlobalWork, None);
+                        ThreadLocalBuffer::new().trace_stop(id, GlobalWork);
+                    }
+                    barrier.wait();
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        let registry = BufferRegistry::<GlobalWork>::global();
+        assert_eq!(registry.len(), 4);
+        assert!(registry.collect().iter().all(|b| b.capacity() == DEFAULT_CAPACITY));
+
+        let merged: Vec<_> = registry.merged().collect();
+        assert_eq!(merged.len(), 4 * 20);
+        let mut threads: Vec<_> = merged.iter().map(|e| e.thread().unwrap().0).collect();
+        threads.sort();
+        threads.dedup();
+        assert_eq!(threads.len(), 4);
+    }
+
+    // Traces an event when it is dropped.
+    struct TraceOnDrop;
+
+    impl Drop for TraceOnDrop {
+        fn drop(&mut self) {
+            ThreadLocalBuffer::new().trace_event(Work, None);
+        }
+    }
+
+    thread_local!(static TRACE_ON_DROP: TraceOnDrop = TraceOnDrop);
+
+    #[test]
+    fn traces_from_thread_local_destructors() {
+        thread::spawn(|| {
+                // Initialize `TRACE_ON_DROP` before the thread's buffers, so
+                // that it is destroyed after them.
+                TRACE_ON_DROP.with(|_| {});
+                ThreadLocalBuffer::new().trace_event(Work, None);
+            })
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn drains_every_entry_once() {
+        let registry = BufferRegistry::new(4096);
+        let mut a = registry.register();
+        let mut b = registry.register();
+
+        a.trace_event(Work, None);
+        b.trace_event(Work, None);
+        assert_eq!(registry.drain().count(), 2);
+        assert_eq!(registry.drain().count(), 0);
+
+        let id = a.trace_event(Work, None);
+        let drained: Vec<_> = registry.drain().collect();
+        assert_eq!(drained.len(), 1);
+        assert_eq!(drained[0].id(), id.u32());
+    }
+
+    #[test]
+    fn resizes_every_buffer() {
+        let registry = BufferRegistry::new(4096);
+        let mut a = registry.register();
+        for _ in 0..1000 {
+            a.trace_event(Work, None);
+        }
+        let before: Vec<_> = registry.merged().map(|e| (e.id(), e.timestamp())).collect();
+
+        registry.resize(64 * 1024);
+        let mut b = registry.register();
+        for _ in 0..1000 {
+            a.trace_event(Work, None);
+            b.trace_event(Work, None);
+        }
+
+        let buffers = registry.collect();
+        assert!(buffers.iter().all(|b| b.capacity() == 64 * 1024));
+        let kept: Vec<_> = buffers[0]
+            .iter()
+            .take(before.len())
+            .map(|e| (e.id(), e.timestamp()))
+            .collect();
+        assert_eq!(kept, before);
+        assert_eq!(registry.merged().count(), before.len() + 2000);
+    }
+}
@@ -3,15 +3,27 @@
 extern crate serde;
 extern crate time;
 
-use std::cell::UnsafeCell;
-use std::collections::HashMap;
+use clock::{Clock, MonotonicClock, TimeBase};
+use encoding::{self, MAX_ENCODED_ENTRY_SIZE};
+use std::collections::{HashMap, VecDeque};
+use std::cmp;
+use std::fmt;
 use std::marker::PhantomData;
-use std::mem;
-use traits::{ThreadId, Trace, TraceId, TraceSink};
+use std::ops;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{self, AtomicU64, Ordering};
+use traits::{ThreadId, Trace, TraceId, TraceSink, TraceSinkWithId};
 
 /// TODO FITZGEN
+///
+/// Entries are timestamped with the `Clock` `C`. Their raw readings are stored
+/// in the buffer and mapped to wall-clock time with the clock's `TimeBase` as
+/// they are read back out.
+///
+/// When the buffer is full, its `OverflowPolicy` decides what happens to new
+/// entries, and its `Counters` record how many entries were lost.
 #[derive(Clone, Debug)]
-pub struct RingBuffer<T> {
+pub struct RingBuffer<T, C = MonotonicClock> {
     // The data itself.
     data: Vec<u8>,
 
@@ -21,9 +33,224 @@ pub struct RingBuffer<T> {
     // The number of bytes in the ring buffer that are valid.
     length: usize,
 
+    // Entries' timestamps are raw clock readings, delta-encoded against the
+    // previous entry's timestamp. This is the timestamp that the entry at
+    // `begin` is relative to, i.e. the timestamp of the last entry that was
+    // evicted.
+    begin_timestamp: u64,
+
+    // The timestamp of the most recently written entry, which the next entry
+    // will be relative to.
+    end_timestamp: u64,
+
+    clock: C,
+
+    // The rates that tags were sampled at, if they were sampled.
+    sample_rates: HashMap<u32, f64>,
+
+    // What to do with new entries when the buffer is full.
+    overflow: OverflowPolicy<T>,
+
+    counters: Counters,
+
+    // Every entry has a logical position: the number of bytes that were
+    // written before it. This is the logical position of the end of the data.
+    end_pos: u64,
+
+    // The sequence number of the entry at `begin`. The next entry's sequence
+    // number is `counters.written`.
+    begin_seq: u64,
+
+    // A sparse index of the entries, for seeking without decoding every entry
+    // from `begin`. There is a checkpoint at least every `INDEX_INTERVAL`
+    // bytes, ordered by position, and every checkpoint is within the valid
+    // data.
+    index: VecDeque<Checkpoint>,
+
     phantom: PhantomData<T>,
 }
 
+// The number of bytes between checkpoints in a `RingBuffer`'s index.
+const INDEX_INTERVAL: u64 = 1024;
+
+// Where to start decoding entries in a `RingBuffer`.
+#[derive(Copy, Clone, Debug)]
+struct Checkpoint {
+    // The entry's logical position.
+    pos: u64,
+    // The entry's sequence number.
+    seq: u64,
+    // The raw timestamp that the entry is delta-encoded against.
+    prev_timestamp: u64,
+    // The entry's raw timestamp.
+    timestamp: u64,
+}
+
+/// What a `RingBuffer` does with a new entry when it is full.
+pub enum OverflowPolicy<T> {
+    /// Evict the oldest entries to make room for the new one. This is the
+    /// default.
+    OverwriteOldest,
+
+    /// Drop the new entry, keeping the oldest entries.
+    DropNewest,
+
+    /// Pass all of the buffered entries, oldest first, to the callback and
+    /// clear the buffer to make room for the new entry. Nothing is lost, as
+    /// long as the callback keeps the entries.
+    ///
+    /// The callback is locked while it runs, and clones of the policy share
+    /// it, so the callback must not trace into any buffer that uses this
+    /// policy: that would deadlock. If the callback panics, later flushes still
+    /// call it.
+    Flush(Arc<Mutex<dyn FnMut(Vec<Entry<T>>) + Send>>),
+}
+
+impl<T> OverflowPolicy<T> {
+    /// Construct a `Flush` policy that passes full buffers' entries to the
+    /// given callback.
+    pub fn flush<F>(callback: F) -> OverflowPolicy<T>
+        where F: 'static + FnMut(Vec<Entry<T>>) + Send
+    {
+        OverflowPolicy::Flush(Arc::new(Mutex::new(callback)))
+    }
+}
+
+impl<T> Default for OverflowPolicy<T> {
+    fn default() -> OverflowPolicy<T> {
+        OverflowPolicy::OverwriteOldest
+    }
+}
+
+/// Clones of a `Flush` policy share its callback.
+impl<T> Clone for OverflowPolicy<T> {
+    fn clone(&self) -> OverflowPolicy<T> {
+        match *self {
+            OverflowPolicy::OverwriteOldest => OverflowPolicy::OverwriteOldest,
+            OverflowPolicy::DropNewest => OverflowPolicy::DropNewest,
+            OverflowPolicy::Flush(ref callback) => OverflowPolicy::Flush(callback.clone()),
+        }
+    }
+}
+
+impl<T> fmt::Debug for OverflowPolicy<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OverflowPolicy::OverwriteOldest => write!(f, "OverwriteOldest"),
+            OverflowPolicy::DropNewest => write!(f, "DropNewest"),
+            OverflowPolicy::Flush(_) => write!(f, "Flush(..)"),
+        }
+    }
+}
+
+/// Counts of the entries that were written to a `RingBuffer`, and of those
+/// that are no longer in it.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Counters {
+    written: u64,
+    evicted: u64,
+    dropped: u64,
+    flushed: u64,
+}
+
+impl Counters {
+    /// Get the total number of entries that were written into the buffer.
+    /// This does not include dropped entries.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Get the number of entries that were evicted to make room for newer
+    /// ones, under `OverflowPolicy::OverwriteOldest`.
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    /// Get the number of new entries that were dropped because the buffer was
+    /// full, under `OverflowPolicy::DropNewest`.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Get the number of entries that were passed to the callback of
+    /// `OverflowPolicy::Flush`.
+    pub fn flushed(&self) -> u64 {
+        self.flushed
+    }
+
+    /// Does the buffer hold fewer entries than it was given, because some were
+    /// evicted, dropped or flushed?
+    pub fn is_truncated(&self) -> bool {
+        self.evicted > 0 || self.dropped > 0 || self.flushed > 0
+    }
+}
+
+/// Adding counters sums each of them, for example to describe traces that
+/// were merged together.
+impl ops::Add for Counters {
+    type Output = Counters;
+
+    fn add(self, other: Counters) -> Counters {
+        Counters {
+            written: self.written + other.written,
+            evicted: self.evicted + other.evicted,
+            dropped: self.dropped + other.dropped,
+            flushed: self.flushed + other.flushed,
+        }
+    }
+}
+
+impl serde::Serialize for Counters {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        let mut state = try!(serializer.serialize_struct("Counters", 4));
+        try!(serializer.serialize_struct_elt(&mut state, "written", self.written));
+        try!(serializer.serialize_struct_elt(&mut state, "evicted", self.evicted));
+        try!(serializer.serialize_struct_elt(&mut state, "dropped", self.dropped));
+        try!(serializer.serialize_struct_elt(&mut state, "flushed", self.flushed));
+        serializer.serialize_struct_end(state)
+    }
+}
+
+impl serde::Deserialize for Counters {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Counters, D::Error>
+        where D: serde::Deserializer
+    {
+        struct CountersVisitor;
+
+        impl serde::de::Visitor for CountersVisitor {
+            type Value = Counters;
+
+            fn visit_map<V>(&mut self, mut visitor: V) -> Result<Counters, V::Error>
+                where V: serde::de::MapVisitor
+            {
+                let mut counters = Counters::default();
+
+                while let Some(key) = try!(visitor.visit_key::<String>()) {
+                    match &key[..] {
+                        "written" => counters.written = try!(visitor.visit_value()),
+                        "evicted" => counters.evicted = try!(visitor.visit_value()),
+                        "dropped" => counters.dropped = try!(visitor.visit_value()),
+                        "flushed" => counters.flushed = try!(visitor.visit_value()),
+                        _ => {
+                            try!(visitor.visit_value::<serde::de::impls::IgnoredAny>());
+                        }
+                    }
+                }
+                try!(visitor.end());
+
+                Ok(counters)
+            }
+        }
+
+        const FIELDS: &'static [&'static str] = &["written", "evicted", "dropped", "flushed"];
+        deserializer.deserialize_struct("Counters", FIELDS, CountersVisitor)
+    }
+}
+
+/// The default buffer holds 4096 bytes of entries. Use `RingBuffer::resize` to
+/// grow it later without losing them.
 impl<T> Default for RingBuffer<T> {
     fn default() -> RingBuffer<T> {
         Self::new(4096)
@@ -31,27 +258,246 @@ impl<T> Default for RingBuffer<T> {
 }
 
 impl<T> RingBuffer<T> {
-    /// Construct a new `RingBuffer` with the given capacity.
+    /// Construct a new `RingBuffer` with the given capacity, whose entries are
+    /// timestamped with a `MonotonicClock`.
     pub fn new(capacity: usize) -> RingBuffer<T> {
-        assert!(capacity > Entry::<T>::size());
+        Self::with_clock(capacity, MonotonicClock::new())
+    }
+}
+
+impl<T, C> RingBuffer<T, C>
+    where C: Clock
+{
+    /// Construct a new `RingBuffer` with the given capacity, whose entries are
+    /// timestamped with the given clock.
+    pub fn with_clock(capacity: usize, clock: C) -> RingBuffer<T, C> {
+        assert!(capacity >= MAX_ENCODED_ENTRY_SIZE);
         RingBuffer {
             data: vec![0; capacity],
             begin: 0,
             length: 0,
+            begin_timestamp: 0,
+            end_timestamp: 0,
+            clock: clock,
+            sample_rates: HashMap::new(),
+            overflow: OverflowPolicy::OverwriteOldest,
+            counters: Counters::default(),
+            end_pos: 0,
+            begin_seq: 0,
+            index: VecDeque::new(),
             phantom: PhantomData,
         }
     }
 
+    /// Set what happens to new entries when this buffer is full.
+    pub fn with_overflow_policy(mut self, overflow: OverflowPolicy<T>) -> RingBuffer<T, C> {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Get what happens to new entries when this buffer is full.
+    pub fn overflow_policy(&self) -> &OverflowPolicy<T> {
+        &self.overflow
+    }
+
+    /// Get the counts of entries that were written to this buffer, and of
+    /// those that were lost when it was full.
+    pub fn counters(&self) -> Counters {
+        self.counters
+    }
+
+    /// Get the clock that timestamps this buffer's entries.
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Get the rates that tags were sampled at, as recorded with
+    /// `TraceSink::trace_sample_rate`. Tags without a rate were not sampled.
+    pub fn sample_rates(&self) -> &HashMap<u32, f64> {
+        &self.sample_rates
+    }
+
     /// Iterate over the `Entry<T>` in this `RingBuffer<T>`.
-    pub fn iter(&self) -> RingBufferIter<T> {
-        RingBufferIter(if self.length == 0 {
-            RingBufferIterState::Empty
-        } else {
-            RingBufferIterState::NonEmpty {
-                buffer: self,
-                idx: self.begin,
+    pub fn iter(&self) -> RingBufferIter<T, C> {
+        RingBufferIter {
+            buffer: self,
+            idx: self.begin,
+            remaining: self.length,
+            timestamp: self.begin_timestamp,
+            time_base: self.clock.time_base(),
+        }
+    }
+
+    /// Iterate over the entries whose timestamps are at or after `start`, and
+    /// before `end`, oldest first.
+    ///
+    /// This seeks to `start` with a binary search rather than decoding every
+    /// older entry, which relies on the entries being in timestamp order. They
+    /// are, unless the clock went backwards, as a `WallClock` can.
+    pub fn iter_range(&self, start: NsSinceEpoch, end: NsSinceEpoch) -> RingBufferRange<T, C> {
+        let time_base = self.clock.time_base();
+        let i = partition_point(&self.index,
+                                |c| time_base.to_wall(c.timestamp) < start);
+        let mut iter = self.iter_from(self.checkpoint_before(i));
+
+        loop {
+            let before = iter.clone();
+            match iter.next() {
+                Some(ref entry) if entry.timestamp < start => {}
+                _ => {
+                    iter = before;
+                    break;
+                }
             }
-        })
+        }
+
+        RingBufferRange {
+            iter: iter,
+            end: end,
+        }
+    }
+
+    /// Iterate over the `Entry<T>` in this `RingBuffer<T>`, newest first.
+    pub fn iter_rev(&self) -> RingBufferRevIter<T, C> {
+        RingBufferRevIter {
+            buffer: self,
+            segment: vec![],
+            segment_begin: self.end_pos,
+        }
+    }
+
+    /// Iterate over the newest `n` entries in this `RingBuffer<T>`, or all of
+    /// them if there are fewer, oldest first.
+    pub fn last_n(&self, n: usize) -> RingBufferIter<T, C> {
+        let n = cmp::min(n, self.len()) as u64;
+        let seq = self.counters.written - n;
+        let i = partition_point(&self.index, |c| c.seq <= seq);
+        let checkpoint = self.checkpoint_before(i);
+
+        let mut iter = self.iter_from(checkpoint);
+        for _ in checkpoint.seq..seq {
+            iter.next();
+        }
+        iter
+    }
+
+    // Iterate from the given checkpoint to the end of the data.
+    fn iter_from(&self, checkpoint: Checkpoint) -> RingBufferIter<T, C> {
+        RingBufferIter {
+            buffer: self,
+            idx: self.physical(checkpoint.pos),
+            remaining: (self.end_pos - checkpoint.pos) as usize,
+            timestamp: checkpoint.prev_timestamp,
+            time_base: self.clock.time_base(),
+        }
+    }
+
+    /// Copy the entries currently in this buffer into an owned `Snapshot<T>`.
+    ///
+    /// The entries are copied in their encoded form, so this is cheap enough to
+    /// do while the buffer's thread waits on a lock.
+    pub fn snapshot(&self) -> Snapshot<T> {
+        let capacity = self.data.len();
+        let mut data = Vec::with_capacity(self.length);
+        if self.begin + self.length > capacity {
+            data.extend_from_slice(&self.data[self.begin..]);
+            data.extend_from_slice(&self.data[..self.begin + self.length - capacity]);
+        } else {
+            data.extend_from_slice(&self.data[self.begin..self.begin + self.length]);
+        }
+
+        Snapshot {
+            data: data,
+            begin_timestamp: self.begin_timestamp,
+            time_base: self.clock.time_base(),
+            sample_rates: self.sample_rates.clone(),
+            counters: self.counters,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Remove the entries from this buffer, oldest first.
+    ///
+    /// Each entry is removed as it is yielded. If the iterator is dropped
+    /// early, the entries it did not yield stay in the buffer, so they can be
+    /// drained later without reporting any entry twice.
+    pub fn drain(&mut self) -> Drain<T, C> {
+        let time_base = self.clock.time_base();
+        Drain {
+            buffer: self,
+            time_base: time_base,
+        }
+    }
+}
+
+impl<T, C> RingBuffer<T, C> {
+    /// Get the number of entries in this buffer.
+    pub fn len(&self) -> usize {
+        (self.counters.written - self.begin_seq) as usize
+    }
+
+    /// Return `true` if this buffer has no entries, `false` otherwise.
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Get the capacity of this buffer, in bytes.
+    pub fn capacity(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Change the capacity of this buffer, keeping its most recent entries in
+    /// order.
+    ///
+    /// When shrinking, the oldest entries that no longer fit are evicted, and
+    /// counted in `Counters::evicted`. Panics if `capacity` is less than
+    /// `MAX_ENCODED_ENTRY_SIZE`.
+    pub fn resize(&mut self, capacity: usize) {
+        assert!(capacity >= MAX_ENCODED_ENTRY_SIZE);
+        while self.length > capacity {
+            self.evict_oldest();
+            self.counters.evicted += 1;
+        }
+
+        // Copy the data to the front of the new buffer, unwrapping it if it
+        // wrapped around the end of the old one.
+        let mut data = vec![0; capacity];
+        let first = cmp::min(self.length, self.data.len() - self.begin);
+        data[..first].copy_from_slice(&self.data[self.begin..self.begin + first]);
+        data[first..self.length].copy_from_slice(&self.data[..self.length - first]);
+        self.data = data;
+        self.begin = 0;
+    }
+
+    /// Remove every entry from this buffer.
+    ///
+    /// The buffer's `Counters` are totals over its whole lifetime, so they are
+    /// not reset.
+    pub fn clear(&mut self) {
+        self.length = 0;
+        self.begin_timestamp = self.end_timestamp;
+        self.begin_seq = self.counters.written;
+        self.index.clear();
+    }
+
+    // Get the index in `data` of the given logical position.
+    fn physical(&self, pos: u64) -> usize {
+        let begin_pos = self.end_pos - self.length as u64;
+        (self.begin + (pos - begin_pos) as usize) % self.data.len()
+    }
+
+    // Get the `i`th checkpoint in the index, or, if `i` is zero, the start of
+    // the data, which comes before any of them.
+    fn checkpoint_before(&self, i: usize) -> Checkpoint {
+        if i > 0 {
+            return self.index[i - 1];
+        }
+        Checkpoint {
+            pos: self.end_pos - self.length as u64,
+            seq: self.begin_seq,
+            prev_timestamp: self.begin_timestamp,
+            timestamp: self.begin_timestamp,
+        }
     }
 
     #[inline(always)]
@@ -59,16 +505,80 @@ impl<T> RingBuffer<T> {
         (self.begin + self.length) % self.data.len()
     }
 
-    fn write(&mut self, data: &[u8]) {
-        let end = self.end();
-        let new_data_len = data.len();
+    // Decode the entry starting at `idx`, whose timestamp is relative to
+    // `timestamp`. Returns the entry and its encoded size.
+    fn read_at(&self, idx: usize, timestamp: u64) -> (Entry<T>, usize) {
+        let capacity = self.data.len();
+        let mut bytes = [0; MAX_ENCODED_ENTRY_SIZE];
+
+        if idx + MAX_ENCODED_ENTRY_SIZE > capacity {
+            // The entry might be split across the end of the buffer and wrap
+            // around to the front of it again.
+            let middle = capacity - idx;
+            bytes[..middle].copy_from_slice(&self.data[idx..]);
+            bytes[middle..].copy_from_slice(&self.data[..MAX_ENCODED_ENTRY_SIZE - middle]);
+        } else {
+            bytes.copy_from_slice(&self.data[idx..idx + MAX_ENCODED_ENTRY_SIZE]);
+        }
+
+        let mut cursor = &bytes[..];
+        let entry = encoding::decode(&mut cursor, NsSinceEpoch(timestamp))
+            .expect("ring buffer should contain valid entries");
+        (entry, MAX_ENCODED_ENTRY_SIZE - cursor.len())
+    }
+
+    // Remove the oldest entry and return it, with its raw timestamp.
+    fn evict_oldest(&mut self) -> Entry<T> {
+        debug_assert!(self.length > 0);
+        let (entry, size) = self.read_at(self.begin, self.begin_timestamp);
+        self.begin = (self.begin + size) % self.data.len();
+        self.length -= size;
+        self.begin_timestamp = entry.timestamp.0;
+        self.begin_seq += 1;
+
+        let begin_pos = self.end_pos - self.length as u64;
+        while self.index.front().map_or(false, |c| c.pos < begin_pos) {
+            self.index.pop_front();
+        }
+        entry
+    }
+}
+
+impl<T, C> RingBuffer<T, C>
+    where C: Clock
+{
+    fn write(&mut self, entry: &Entry<T>) {
+        let mut bytes = [0; MAX_ENCODED_ENTRY_SIZE];
+        let new_data_len =
+            encoding::encode(entry, NsSinceEpoch(self.end_timestamp), &mut &mut bytes[..])
+                .expect("MAX_ENCODED_ENTRY_SIZE should be large enough for any entry");
+        let data = &bytes[..new_data_len];
         let capacity = self.data.len();
 
-        if capacity - self.length < Entry::<T>::size() {
-            self.begin = (self.begin + Entry::<T>::size()) % capacity;
-            self.length -= Entry::<T>::size();
+        if capacity - self.length < new_data_len {
+            match self.overflow {
+                OverflowPolicy::OverwriteOldest => {
+                    while capacity - self.length < new_data_len {
+                        self.evict_oldest();
+                        self.counters.evicted += 1;
+                    }
+                }
+                OverflowPolicy::DropNewest => {
+                    self.counters.dropped += 1;
+                    return;
+                }
+                OverflowPolicy::Flush(ref callback) => {
+                    let callback = callback.clone();
+                    let entries: Vec<_> = self.iter().collect();
+                    self.counters.flushed += entries.len() as u64;
+                    self.clear();
+                    let mut callback = callback.lock().unwrap_or_else(|e| e.into_inner());
+                    (*callback)(entries);
+                }
+            }
         }
 
+        let end = self.end();
         if end + new_data_len > capacity {
             let middle = capacity - end;
             self.data[end..capacity].copy_from_slice(&data[..middle]);
@@ -77,109 +587,177 @@ impl<T> RingBuffer<T> {
             self.data[end..end + new_data_len].copy_from_slice(data);
         }
 
-        self.length += Entry::<T>::size();
+        if self.index.back().map_or(true, |c| self.end_pos - c.pos >= INDEX_INTERVAL) {
+            self.index.push_back(Checkpoint {
+                pos: self.end_pos,
+                seq: self.counters.written,
+                prev_timestamp: self.end_timestamp,
+                timestamp: entry.timestamp.0,
+            });
+        }
+
+        self.length += new_data_len;
+        self.end_pos += new_data_len as u64;
+        self.end_timestamp = entry.timestamp.0;
+        self.counters.written += 1;
         debug_assert!(self.length <= capacity);
     }
 }
 
-impl<T> TraceSink<T> for RingBuffer<T>
-    where T: Trace
+impl<T, C> TraceSink<T> for RingBuffer<T, C>
+    where T: Trace,
+          C: Clock
 {
     fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
         let id = T::Id::new_id();
+        self.trace_event_with_id(id, trace, why);
+        id
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.trace_start_with_id(id, trace, why);
+        id
+    }
 
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
         let entry: Entry<T> = Entry {
-            why: why.map(|id| (id.thread(), id.u32())),
+            why: None,
             thread: id.thread(),
-            timestamp: NsSinceEpoch::now(),
+            timestamp: NsSinceEpoch(self.clock.now()),
             id: id.u32(),
             tag: trace.tag(),
-            kind: TraceKind::Event,
+            args: trace.args(),
+            kind: TraceKind::Stop,
             phantom: PhantomData,
         };
 
-        let entry: [u8; 65] = unsafe { mem::transmute(entry) };
         self.write(&entry);
+    }
 
-        id
+    fn trace_sample_rate(&mut self, tag: u32, rate: f64) {
+        self.sample_rates.insert(tag, rate);
     }
 
-    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
-        let id = T::Id::new_id();
+    fn trace_dropped(&mut self, tag: u32, count: u64) {
+        let entry: Entry<T> = Entry {
+            why: None,
+            thread: None,
+            timestamp: NsSinceEpoch(self.clock.now()),
+            id: 0,
+            tag: tag,
+            args: [count, 0, 0, 0],
+            kind: TraceKind::Dropped,
+            phantom: PhantomData,
+        };
+
+        self.write(&entry);
+    }
+}
 
+impl<T, C> TraceSinkWithId<T> for RingBuffer<T, C>
+    where T: Trace,
+          C: Clock
+{
+    fn trace_event_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
         let entry: Entry<T> = Entry {
             why: why.map(|id| (id.thread(), id.u32())),
             thread: id.thread(),
-            timestamp: NsSinceEpoch::now(),
+            timestamp: NsSinceEpoch(self.clock.now()),
             id: id.u32(),
             tag: trace.tag(),
-            kind: TraceKind::Start,
+            args: trace.args(),
+            kind: TraceKind::Event,
             phantom: PhantomData,
         };
 
-        let entry: [u8; 65] = unsafe { mem::transmute(entry) };
         self.write(&entry);
-
-        id
     }
 
-    fn trace_stop(&mut self, id: T::Id, trace: T) {
+    fn trace_start_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
         let entry: Entry<T> = Entry {
-            why: None,
+            why: why.map(|id| (id.thread(), id.u32())),
             thread: id.thread(),
-            timestamp: NsSinceEpoch::now(),
+            timestamp: NsSinceEpoch(self.clock.now()),
             id: id.u32(),
             tag: trace.tag(),
-            kind: TraceKind::Stop,
+            args: trace.args(),
+            kind: TraceKind::Start,
             phantom: PhantomData,
         };
 
-        let entry: [u8; 65] = unsafe { mem::transmute(entry) };
         self.write(&entry);
     }
 }
 
-impl<T> serde::Serialize for RingBuffer<T>
-    where T: Trace
+impl<T, C> serde::Serialize for RingBuffer<T, C>
+    where T: Trace,
+          C: Clock
 {
     fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
         where S: serde::Serializer
     {
-        // Build up all the entries' labels in a map keyed by T's tag, serialize
-        // that, and then serialize the individual entries.
+        serialize_buffer(serializer,
+                         "RingBuffer",
+                         self.clock.time_base(),
+                         &self.sample_rates,
+                         self.counters,
+                         self.iter())
+    }
+}
 
-        struct Entries<'a, T>(&'a RingBuffer<T>) where T: 'a + Trace;
+// Serialize a buffer's time base, the labels of its entries' tags, its sample
+// rates and counters, and then its entries.
+fn serialize_buffer<S, T, I>(serializer: &mut S,
+                             name: &'static str,
+                             time_base: TimeBase,
+                             sample_rates: &HashMap<u32, f64>,
+                             counters: Counters,
+                             entries: I)
+                             -> Result<(), S::Error>
+    where S: serde::Serializer,
+          T: Trace,
+          I: Clone + Iterator<Item = Entry<T>>
+{
+    struct Entries<I>(I);
 
-        impl<'a, T> serde::Serialize for Entries<'a, T>
-            where T: Trace
+    impl<T, I> serde::Serialize for Entries<I>
+        where I: Clone + Iterator<Item = Entry<T>>
+    {
+        fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+            where S: serde::Serializer
         {
-            fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
-                where S: serde::Serializer
-            {
-                let mut state = try!(serializer.serialize_seq(None));
-                for entry in self.0.iter() {
-                    try!(serializer.serialize_seq_elt(&mut state, entry));
-                }
-                serializer.serialize_seq_end(state)
+            let mut state = try!(serializer.serialize_seq(None));
+            for entry in self.0.clone() {
+                try!(serializer.serialize_seq_elt(&mut state, entry));
             }
+            serializer.serialize_seq_end(state)
         }
+    }
 
-        let mut labels = HashMap::new();
-        for entry in self.iter() {
-            let tag = entry.tag();
-            // Turn the key into a string to support JSON.
-            labels.insert(format!("{}", tag), T::label(tag));
-        }
+    let mut labels = HashMap::new();
+    for entry in entries.clone() {
+        let tag = entry.tag();
+        // Turn the key into a string to support JSON.
+        labels.insert(format!("{}", tag), T::label(tag));
+    }
 
-        let mut state = try!(serializer.serialize_struct("RingBuffer", 2));
-        try!(serializer.serialize_struct_elt(&mut state, "labels", labels));
-        try!(serializer.serialize_struct_elt(&mut state, "entries", Entries(self)));
-        serializer.serialize_struct_end(state)
+    let mut state = try!(serializer.serialize_struct(name, 5));
+    try!(serializer.serialize_struct_elt(&mut state, "time_base", time_base));
+    try!(serializer.serialize_struct_elt(&mut state, "labels", labels));
+    try!(serializer.serialize_struct_elt(&mut state, "counters", counters));
+    if !sample_rates.is_empty() {
+        let sample_rates: HashMap<_, _> = sample_rates.iter()
+            .map(|(tag, rate)| (format!("{}", tag), rate))
+            .collect();
+        try!(serializer.serialize_struct_elt(&mut state, "sample_rates", sample_rates));
     }
+    try!(serializer.serialize_struct_elt(&mut state, "entries", Entries(entries)));
+    serializer.serialize_struct_end(state)
 }
 
 /// Nanoseconds since the epoch.
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct NsSinceEpoch(pub u64);
 
 impl NsSinceEpoch {
@@ -201,6 +779,14 @@ impl serde::Serialize for NsSinceEpoch {
     }
 }
 
+impl serde::Deserialize for NsSinceEpoch {
+    fn deserialize<D>(deserializer: &mut D) -> Result<NsSinceEpoch, D::Error>
+        where D: serde::Deserializer
+    {
+        u64::deserialize(deserializer).map(NsSinceEpoch)
+    }
+}
+
 /// The kind of trace an entry represents.
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -211,6 +797,10 @@ pub enum TraceKind {
     Start = 0x1,
     /// The end of some operation.
     Stop = 0x2,
+    /// A record that entries with this entry's tag were dropped, for example
+    /// by `sink_combinators::RateLimitSink`. The entry's first argument is the
+    /// number of dropped entries.
+    Dropped = 0x3,
 }
 
 impl serde::Serialize for TraceKind {
@@ -221,18 +811,49 @@ impl serde::Serialize for TraceKind {
             TraceKind::Event => serializer.serialize_unit_variant("TraceKind", 0, "Event"),
             TraceKind::Start => serializer.serialize_unit_variant("TraceKind", 1, "Start"),
             TraceKind::Stop => serializer.serialize_unit_variant("TraceKind", 2, "Stop"),
+            TraceKind::Dropped => serializer.serialize_unit_variant("TraceKind", 3, "Dropped"),
+        }
+    }
+}
+
+impl serde::Deserialize for TraceKind {
+    fn deserialize<D>(deserializer: &mut D) -> Result<TraceKind, D::Error>
+        where D: serde::Deserializer
+    {
+        struct KindVisitor;
+
+        impl serde::de::EnumVisitor for KindVisitor {
+            type Value = TraceKind;
+
+            fn visit<V>(&mut self, mut visitor: V) -> Result<TraceKind, V::Error>
+                where V: serde::de::VariantVisitor
+            {
+                let variant: String = try!(visitor.visit_variant());
+                let kind = match &variant[..] {
+                    "Event" => TraceKind::Event,
+                    "Start" => TraceKind::Start,
+                    "Stop" => TraceKind::Stop,
+                    "Dropped" => TraceKind::Dropped,
+                    _ => return Err(serde::de::Error::unknown_variant(&variant)),
+                };
+                try!(visitor.visit_unit());
+                Ok(kind)
+            }
         }
+
+        const VARIANTS: &'static [&'static str] = &["Event", "Start", "Stop", "Dropped"];
+        deserializer.deserialize_enum("TraceKind", VARIANTS, KindVisitor)
     }
 }
 
 /// An `Entry<T>` is a single trace, why it happened, on which thread, and when.
-#[repr(packed)]
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct Entry<T> {
     why: Option<(Option<ThreadId>, u32)>,
     thread: Option<ThreadId>,
     id: u32,
     tag: u32,
+    args: [u64; 4],
     timestamp: NsSinceEpoch,
     kind: TraceKind,
     phantom: PhantomData<T>,
@@ -248,11 +869,45 @@ impl<T> Entry<T>
 }
 
 impl<T> Entry<T> {
+    /// Construct a new entry from its parts.
+    ///
+    /// This is useful for tools that produce entries from sources other than a
+    /// `TraceSink`, such as decoding them from bytes.
+    pub fn new(kind: TraceKind,
+               tag: u32,
+               id: u32,
+               thread: Option<ThreadId>,
+               why: Option<(Option<ThreadId>, u32)>,
+               timestamp: NsSinceEpoch)
+               -> Entry<T> {
+        Entry {
+            why: why,
+            thread: thread,
+            id: id,
+            tag: tag,
+            args: [0; 4],
+            timestamp: timestamp,
+            kind: kind,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Set this entry's payload arguments.
+    pub fn with_args(mut self, args: [u64; 4]) -> Entry<T> {
+        self.args = args;
+        self
+    }
+
     /// Get the tag for this trace entry.
     pub fn tag(&self) -> u32 {
         self.tag
     }
 
+    /// Get the payload arguments of this entry's trace. See `Trace::args`.
+    pub fn args(&self) -> [u64; 4] {
+        self.args
+    }
+
     /// Get the kind of trace this entry represents.
     pub fn kind(&self) -> TraceKind {
         self.kind
@@ -278,106 +933,836 @@ impl<T> Entry<T> {
     pub fn why(&self) -> Option<(Option<ThreadId>, u32)> {
         self.why
     }
-
-    fn size() -> usize {
-        mem::size_of::<Self>()
-    }
 }
 
 impl<T> serde::Serialize for Entry<T> {
     fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
         where S: serde::Serializer
     {
-        let mut state = try!(serializer.serialize_struct("Entry", 6));
+        let mut state = try!(serializer.serialize_struct("Entry", 7));
         try!(serializer.serialize_struct_elt(&mut state, "why", &self.why));
         try!(serializer.serialize_struct_elt(&mut state, "thread", &self.thread));
         try!(serializer.serialize_struct_elt(&mut state, "id", self.id));
         try!(serializer.serialize_struct_elt(&mut state, "tag", self.tag));
+        try!(serializer.serialize_struct_elt(&mut state, "args", self.args));
         try!(serializer.serialize_struct_elt(&mut state, "timestamp", self.timestamp));
         try!(serializer.serialize_struct_elt(&mut state, "kind", self.kind));
         serializer.serialize_struct_end(state)
     }
 }
 
-#[derive(Clone, Debug)]
-enum RingBufferIterState<'a, T>
-    where T: 'a
-{
-    Empty,
-    NonEmpty {
-        buffer: &'a RingBuffer<T>,
-        idx: usize,
-    },
+impl<T> serde::Deserialize for Entry<T> {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Entry<T>, D::Error>
+        where D: serde::Deserializer
+    {
+        struct EntryVisitor<T>(PhantomData<T>);
+
+        impl<T> serde::de::Visitor for EntryVisitor<T> {
+            type Value = Entry<T>;
+
+            fn visit_map<V>(&mut self, mut visitor: V) -> Result<Entry<T>, V::Error>
+                where V: serde::de::MapVisitor
+            {
+                let mut why = None;
+                let mut thread = None;
+                let mut id = None;
+                let mut tag = None;
+                let mut args = None;
+                let mut timestamp = None;
+                let mut kind = None;
+
+                while let Some(key) = try!(visitor.visit_key::<String>()) {
+                    match &key[..] {
+                        "why" => why = Some(try!(visitor.visit_value())),
+                        "thread" => thread = Some(try!(visitor.visit_value())),
+                        "id" => id = Some(try!(visitor.visit_value())),
+                        "tag" => tag = Some(try!(visitor.visit_value())),
+                        "args" => args = Some(try!(visitor.visit_value())),
+                        "timestamp" => timestamp = Some(try!(visitor.visit_value())),
+                        "kind" => kind = Some(try!(visitor.visit_value())),
+                        _ => {
+                            try!(visitor.visit_value::<serde::de::impls::IgnoredAny>());
+                        }
+                    }
+                }
+                try!(visitor.end());
+
+                let id = try!(id.ok_or_else(|| serde::de::Error::missing_field("id")));
+                let tag = try!(tag.ok_or_else(|| serde::de::Error::missing_field("tag")));
+                let timestamp = try!(timestamp.ok_or_else(|| {
+                    serde::de::Error::missing_field("timestamp")
+                }));
+                let kind = try!(kind.ok_or_else(|| serde::de::Error::missing_field("kind")));
+
+                Ok(Entry::new(kind,
+                              tag,
+                              id,
+                              thread.unwrap_or(None),
+                              why.unwrap_or(None),
+                              timestamp)
+                    .with_args(args.unwrap_or([0; 4])))
+            }
+        }
+
+        const FIELDS: &'static [&'static str] =
+            &["why", "thread", "id", "tag", "args", "timestamp", "kind"];
+        deserializer.deserialize_struct("Entry", FIELDS, EntryVisitor(PhantomData))
+    }
 }
 
 /// An iterator over `Entry<T>`s in a `RingBuffer<T>`.
-#[derive(Clone, Debug)]
-pub struct RingBufferIter<'a, T>(RingBufferIterState<'a, T>) where T: 'a;
+#[derive(Debug)]
+pub struct RingBufferIter<'a, T, C = MonotonicClock>
+    where T: 'a,
+          C: 'a
+{
+    buffer: &'a RingBuffer<T, C>,
+    // The index of the next entry in the buffer.
+    idx: usize,
+    // The number of bytes left to decode.
+    remaining: usize,
+    // The raw timestamp of the last decoded entry.
+    timestamp: u64,
+    time_base: TimeBase,
+}
+
+impl<'a, T, C> Clone for RingBufferIter<'a, T, C> {
+    fn clone(&self) -> RingBufferIter<'a, T, C> {
+        RingBufferIter {
+            buffer: self.buffer,
+            idx: self.idx,
+            remaining: self.remaining,
+            timestamp: self.timestamp,
+            time_base: self.time_base,
+        }
+    }
+}
 
-impl<'a, T> Iterator for RingBufferIter<'a, T> {
+impl<'a, T, C> Iterator for RingBufferIter<'a, T, C> {
     type Item = Entry<T>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let (next_state, result) = match self.0 {
-            RingBufferIterState::Empty => return None,
-            RingBufferIterState::NonEmpty { ref buffer, idx } => {
-                let entry: Entry<T> = unsafe {
-                    if idx + Entry::<T>::size() > buffer.data.len() {
-                        // The entry is split across the end of the buffer and
-                        // wraps around to the front of it again.
-                        let entry: UnsafeCell<Entry<T>> = mem::uninitialized();
-                        let mut entry: [u8; 65] = mem::transmute(entry);
-                        let middle = buffer.data.len() - idx;
-                        entry[..middle].copy_from_slice(&buffer.data[idx..]);
-                        entry[middle..]
-                            .copy_from_slice(&buffer.data[..Entry::<T>::size() - middle]);
-                        mem::transmute(entry)
-                    } else {
-                        // The entry is in one contiguous block in the buffer.
-                        let entry: UnsafeCell<Entry<T>> = mem::uninitialized();
-                        let mut entry: [u8; 65] = mem::transmute(entry);
-                        entry.copy_from_slice(&buffer.data[idx..idx + Entry::<T>::size()]);
-                        mem::transmute(entry)
-                    }
-                };
+        if self.remaining == 0 {
+            return None;
+        }
 
-                let next_idx = (idx + Entry::<T>::size()) % buffer.data.len();
-                let next_state = if next_idx == buffer.end() {
-                    RingBufferIterState::Empty
-                } else {
-                    RingBufferIterState::NonEmpty {
-                        buffer: buffer,
-                        idx: next_idx,
-                    }
-                };
+        let (mut entry, size) = self.buffer.read_at(self.idx, self.timestamp);
+        debug_assert!(size <= self.remaining);
 
-                (next_state, Some(entry))
-            }
-        };
+        self.idx = (self.idx + size) % self.buffer.data.len();
+        self.remaining -= size;
+        self.timestamp = entry.timestamp.0;
 
-        mem::replace(&mut self.0, next_state);
-        result
+        entry.timestamp = self.time_base.to_wall(entry.timestamp.0);
+        Some(entry)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    extern crate serde_json;
+/// An iterator over the `Entry<T>`s in a `RingBuffer<T>` within a time range,
+/// from `RingBuffer::iter_range`.
+#[derive(Debug)]
+pub struct RingBufferRange<'a, T, C = MonotonicClock>
+    where T: 'a,
+          C: 'a
+{
+    iter: RingBufferIter<'a, T, C>,
+    end: NsSinceEpoch,
+}
 
-    use super::*;
-    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
-    use traits::{Trace, TraceSink};
+impl<'a, T, C> Clone for RingBufferRange<'a, T, C> {
+    fn clone(&self) -> RingBufferRange<'a, T, C> {
+        RingBufferRange {
+            iter: self.iter.clone(),
+            end: self.end,
+        }
+    }
+}
 
-    type SimpleEntry = Entry<SimpleTrace>;
+impl<'a, T, C> Iterator for RingBufferRange<'a, T, C> {
+    type Item = Entry<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.iter.next() {
+            Some(entry) if entry.timestamp < self.end => Some(entry),
+            _ => {
+                self.iter.remaining = 0;
+                None
+            }
+        }
+    }
+}
+
+/// An iterator over `Entry<T>`s in a `RingBuffer<T>`, newest first, from
+/// `RingBuffer::iter_rev`.
+///
+/// Entries can only be decoded forwards, so this decodes the entries between
+/// two of the buffer's index checkpoints at a time, and yields them in reverse.
+#[derive(Debug)]
+pub struct RingBufferRevIter<'a, T, C = MonotonicClock>
+    where T: 'a,
+          C: 'a
+{
+    buffer: &'a RingBuffer<T, C>,
+    // Decoded entries that have yet to be yielded, oldest first.
+    segment: Vec<Entry<T>>,
+    // The logical position of the first entry in `segment`.
+    segment_begin: u64,
+}
+
+impl<'a, T, C> Iterator for RingBufferRevIter<'a, T, C>
+    where C: Clock
+{
+    type Item = Entry<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.segment.pop() {
+            return Some(entry);
+        }
+
+        let buffer = self.buffer;
+        if self.segment_begin == buffer.end_pos - buffer.length as u64 {
+            return None;
+        }
+
+        let segment_end = self.segment_begin;
+        let i = partition_point(&buffer.index, |c| c.pos < segment_end);
+        let checkpoint = buffer.checkpoint_before(i);
+        let mut iter = buffer.iter_from(checkpoint);
+        iter.remaining = (segment_end - checkpoint.pos) as usize;
+
+        self.segment.extend(iter);
+        self.segment_begin = checkpoint.pos;
+        self.segment.pop()
+    }
+}
+
+// Get the number of leading checkpoints for which `pred` holds, like
+// `slice::partition_point`.
+fn partition_point<P>(index: &VecDeque<Checkpoint>, pred: P) -> usize
+    where P: Fn(&Checkpoint) -> bool
+{
+    let (mut low, mut high) = (0, index.len());
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if pred(&index[mid]) {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
+/// An owned copy of the entries that were in a `RingBuffer<T>`, from
+/// `RingBuffer::snapshot`.
+///
+/// A `Snapshot<T>` serializes in the same format as a `RingBuffer<T>`.
+#[derive(Clone, Debug)]
+pub struct Snapshot<T> {
+    // The valid data, copied out of the ring so that it is contiguous.
+    data: Vec<u8>,
+    begin_timestamp: u64,
+    time_base: TimeBase,
+    sample_rates: HashMap<u32, f64>,
+    counters: Counters,
+    phantom: PhantomData<T>,
+}
+
+impl<T> Snapshot<T> {
+    /// Iterate over the `Entry<T>` in this snapshot, oldest first.
+    pub fn iter(&self) -> SnapshotIter<T> {
+        SnapshotIter {
+            data: &self.data,
+            timestamp: self.begin_timestamp,
+            time_base: self.time_base,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Does this snapshot have no entries?
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Get the time base of the clock that timestamped the entries.
+    pub fn time_base(&self) -> TimeBase {
+        self.time_base
+    }
+
+    /// Get the rates that tags were sampled at, as of the snapshot.
+    pub fn sample_rates(&self) -> &HashMap<u32, f64> {
+        &self.sample_rates
+    }
+
+    /// Get the buffer's counters, as of the snapshot.
+    pub fn counters(&self) -> Counters {
+        self.counters
+    }
+}
+
+impl<T> serde::Serialize for Snapshot<T>
+    where T: Trace
+{
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        serialize_buffer(serializer,
+                         "Snapshot",
+                         self.time_base,
+                         &self.sample_rates,
+                         self.counters,
+                         self.iter())
+    }
+}
+
+/// An iterator over `Entry<T>`s in a `Snapshot<T>`.
+#[derive(Debug)]
+pub struct SnapshotIter<'a, T> {
+    data: &'a [u8],
+    // The raw timestamp of the last decoded entry.
+    timestamp: u64,
+    time_base: TimeBase,
+    phantom: PhantomData<T>,
+}
+
+impl<'a, T> Clone for SnapshotIter<'a, T> {
+    fn clone(&self) -> SnapshotIter<'a, T> {
+        SnapshotIter {
+            data: self.data,
+            timestamp: self.timestamp,
+            time_base: self.time_base,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<'a, T> Iterator for SnapshotIter<'a, T> {
+    type Item = Entry<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let mut entry = encoding::decode(&mut self.data, NsSinceEpoch(self.timestamp))
+            .expect("snapshot should contain valid entries");
+        self.timestamp = entry.timestamp.0;
+
+        entry.timestamp = self.time_base.to_wall(entry.timestamp.0);
+        Some(entry)
+    }
+}
+
+/// An iterator that removes `Entry<T>`s from a `RingBuffer<T>`, from
+/// `RingBuffer::drain`.
+#[derive(Debug)]
+pub struct Drain<'a, T, C = MonotonicClock>
+    where T: 'a,
+          C: 'a
+{
+    buffer: &'a mut RingBuffer<T, C>,
+    time_base: TimeBase,
+}
+
+impl<'a, T, C> Iterator for Drain<'a, T, C> {
+    type Item = Entry<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.length == 0 {
+            return None;
+        }
+
+        let mut entry = self.buffer.evict_oldest();
+        entry.timestamp = self.time_base.to_wall(entry.timestamp.0);
+        Some(entry)
+    }
+}
+
+/// A fixed-capacity ring buffer that many threads can trace into concurrently,
+/// through a shared reference, without locking.
+///
+/// Unlike `RingBuffer`, whose entries are variable-length, every entry in an
+/// `AtomicRingBuffer` occupies one fixed-size slot. Writers atomically reserve
+/// the next slot and publish the entry with a per-slot sequence number, so
+/// readers can tell complete entries apart from ones that are in the middle of
+/// being written or overwritten.
+///
+/// Writers never wait for each other. If a writer reaches a slot while the
+/// writer from a full lap around the buffer earlier is still writing it, for
+/// example because that thread was preempted, the newer entry is dropped.
+///
+/// `TraceSink` is implemented for `&AtomicRingBuffer<T>`, so each thread can
+/// trace through its own shared reference:
+///
+/// ```
+/// use eep::ring_buffer::AtomicRingBuffer;
+/// use eep::simple_trace::SimpleTrace;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let buffer = Arc::new(AtomicRingBuffer::new(1024));
+///
+/// let threads: Vec<_> = (0..4).map(|_| {
+///     let buffer = buffer.clone();
+///     thread::spawn(move || {
+///         let id = buffer.trace_start(SimpleTrace::OperationThing, None);
+///         buffer.trace_stop(id, SimpleTrace::OperationThing);
+///     })
+/// }).collect();
+///
+/// for t in threads {
+///     t.join().unwrap();
+/// }
+///
+/// assert_eq!(buffer.iter().count(), 8);
+/// ```
+#[derive(Debug)]
+pub struct AtomicRingBuffer<T, C = MonotonicClock> {
+    slots: Vec<Slot>,
+
+    // The number of slots that have ever been reserved. The `n`th entry is
+    // written into slot `n % slots.len()`.
+    reserved: AtomicU64,
+
+    clock: C,
+
+    phantom: PhantomData<T>,
+}
+
+// Slot words: the timestamp, the thread, why's thread, the id and tag, why's
+// id and the `encoding` header byte, and the four arguments.
+const SLOT_WORDS: usize = 9;
+
+#[derive(Debug)]
+struct Slot {
+    // A sequence lock: `2 * n + 1` while the `n`th entry is being written into
+    // this slot, and `2 * n + 2` once it has been written.
+    seq: AtomicU64,
+    words: [AtomicU64; SLOT_WORDS],
+}
+
+impl Slot {
+    fn new() -> Slot {
+        Slot {
+            seq: AtomicU64::new(0),
+            words: [AtomicU64::new(0),
+                    AtomicU64::new(0),
+                    AtomicU64::new(0),
+                    AtomicU64::new(0),
+                    AtomicU64::new(0),
+                    AtomicU64::new(0),
+                    AtomicU64::new(0),
+                    AtomicU64::new(0),
+                    AtomicU64::new(0)],
+        }
+    }
+}
+
+impl<T> Default for AtomicRingBuffer<T> {
+    fn default() -> AtomicRingBuffer<T> {
+        Self::new(64)
+    }
+}
+
+impl<T> AtomicRingBuffer<T> {
+    /// Construct a new `AtomicRingBuffer` that holds up to `capacity` entries,
+    /// which are timestamped with a `MonotonicClock`.
+    pub fn new(capacity: usize) -> AtomicRingBuffer<T> {
+        Self::with_clock(capacity, MonotonicClock::new())
+    }
+}
+
+impl<T, C> AtomicRingBuffer<T, C>
+    where C: Clock
+{
+    /// Construct a new `AtomicRingBuffer` that holds up to `capacity` entries,
+    /// which are timestamped with the given clock.
+    pub fn with_clock(capacity: usize, clock: C) -> AtomicRingBuffer<T, C> {
+        assert!(capacity > 0);
+        AtomicRingBuffer {
+            slots: (0..capacity).map(|_| Slot::new()).collect(),
+            reserved: AtomicU64::new(0),
+            clock: clock,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Get the clock that timestamps this buffer's entries.
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Get the maximum number of entries this buffer holds.
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Iterate over the `Entry<T>`s in this `AtomicRingBuffer<T>`.
+    ///
+    /// The iterator covers the entries that had been reserved when it was
+    /// created. Entries that are still being written, or that get overwritten
+    /// by newer ones before the iterator reaches them, are skipped; every
+    /// yielded entry is exactly as it was traced.
+    pub fn iter(&self) -> AtomicRingBufferIter<T, C> {
+        let end = self.reserved.load(Ordering::Acquire);
+        AtomicRingBufferIter {
+            buffer: self,
+            next: end.saturating_sub(self.slots.len() as u64),
+            end: end,
+            time_base: self.clock.time_base(),
+        }
+    }
+
+    fn write(&self, entry: &Entry<T>) {
+        let n = self.reserved.fetch_add(1, Ordering::Relaxed);
+        let slot = &self.slots[(n % self.slots.len() as u64) as usize];
+        let writing = 2 * n + 1;
+
+        // Claim the slot. If a writer from a later lap around the buffer
+        // already claimed it, our entry is already stale and we drop it. If a
+        // writer from an earlier lap is still busy with it, we drop our entry
+        // rather than wait for them to finish.
+        loop {
+            let seq = slot.seq.load(Ordering::Relaxed);
+            if seq >= writing || seq % 2 == 1 {
+                return;
+            }
+            if slot.seq
+                .compare_exchange_weak(seq, writing, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok() {
+                break;
+            }
+        }
+        atomic::fence(Ordering::Release);
+
+        let mut header = entry.kind as u64;
+        let mut thread = 0;
+        let mut why_thread = 0;
+        let mut why_id = 0;
+        if let Some(t) = entry.thread {
+            header |= encoding::HAS_THREAD as u64;
+            thread = t.0 as u64;
+        }
+        if let Some((t, id)) = entry.why {
+            header |= encoding::HAS_WHY as u64;
+            why_id = id as u64;
+            if let Some(t) = t {
+                header |= encoding::HAS_WHY_THREAD as u64;
+                why_thread = t.0 as u64;
+            }
+        }
+
+        slot.words[0].store(entry.timestamp.0, Ordering::Relaxed);
+        slot.words[1].store(thread, Ordering::Relaxed);
+        slot.words[2].store(why_thread, Ordering::Relaxed);
+        slot.words[3].store(entry.id as u64 | (entry.tag as u64) << 32, Ordering::Relaxed);
+        slot.words[4].store(why_id | header << 32, Ordering::Relaxed);
+        for (word, &arg) in slot.words[5..].iter().zip(entry.args.iter()) {
+            word.store(arg, Ordering::Relaxed);
+        }
+
+        slot.seq.store(writing + 1, Ordering::Release);
+    }
+
+    // Read the `n`th entry, if it is still in its slot and not being written.
+    fn read(&self, n: u64) -> Option<Entry<T>> {
+        let slot = &self.slots[(n % self.slots.len() as u64) as usize];
+        let written = 2 * n + 2;
+
+        if slot.seq.load(Ordering::Acquire) != written {
+            return None;
+        }
+
+        let timestamp = slot.words[0].load(Ordering::Relaxed);
+        let thread = slot.words[1].load(Ordering::Relaxed);
+        let why_thread = slot.words[2].load(Ordering::Relaxed);
+        let id_and_tag = slot.words[3].load(Ordering::Relaxed);
+        let why_id_and_header = slot.words[4].load(Ordering::Relaxed);
+        let mut args = [0; 4];
+        for (arg, word) in args.iter_mut().zip(slot.words[5..].iter()) {
+            *arg = word.load(Ordering::Relaxed);
+        }
+
+        atomic::fence(Ordering::Acquire);
+        if slot.seq.load(Ordering::Relaxed) != written {
+            return None;
+        }
+
+        let header = (why_id_and_header >> 32) as u8;
+        let kind = match header & encoding::KIND_MASK {
+            0x0 => TraceKind::Event,
+            0x1 => TraceKind::Start,
+            0x2 => TraceKind::Stop,
+            _ => TraceKind::Dropped,
+        };
+        let thread = if header & encoding::HAS_THREAD != 0 {
+            Some(ThreadId(thread as usize))
+        } else {
+            None
+        };
+        let why = if header & encoding::HAS_WHY != 0 {
+            let why_thread = if header & encoding::HAS_WHY_THREAD != 0 {
+                Some(ThreadId(why_thread as usize))
+            } else {
+                None
+            };
+            Some((why_thread, why_id_and_header as u32))
+        } else {
+            None
+        };
+
+        Some(Entry::new(kind,
+                        (id_and_tag >> 32) as u32,
+                        id_and_tag as u32,
+                        thread,
+                        why,
+                        NsSinceEpoch(timestamp))
+            .with_args(args))
+    }
+}
+
+impl<T, C> AtomicRingBuffer<T, C>
+    where T: Trace,
+          C: Clock
+{
+    /// Trace a one-off event.
+    ///
+    /// This is `TraceSink::trace_event`, but through a shared reference.
+    pub fn trace_event(&self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.trace_event_with_id(id, trace, why);
+        id
+    }
+
+    /// Trace a one-off event with the given `id`.
+    ///
+    /// This is `TraceSink::trace_event_with_id`, but through a shared
+    /// reference.
+    pub fn trace_event_with_id(&self, id: T::Id, trace: T, why: Option<T::Id>) {
+        self.write(&Entry::new(TraceKind::Event,
+                               trace.tag(),
+                               id.u32(),
+                               id.thread(),
+                               why.map(|id| (id.thread(), id.u32())),
+                               NsSinceEpoch(self.clock.now()))
+            .with_args(trace.args()));
+    }
+
+    /// Trace the start of an operation.
+    ///
+    /// This is `TraceSink::trace_start`, but through a shared reference.
+    pub fn trace_start(&self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.trace_start_with_id(id, trace, why);
+        id
+    }
+
+    /// Trace the start of an operation with the given `id`.
+    ///
+    /// This is `TraceSink::trace_start_with_id`, but through a shared
+    /// reference.
+    pub fn trace_start_with_id(&self, id: T::Id, trace: T, why: Option<T::Id>) {
+        self.write(&Entry::new(TraceKind::Start,
+                               trace.tag(),
+                               id.u32(),
+                               id.thread(),
+                               why.map(|id| (id.thread(), id.u32())),
+                               NsSinceEpoch(self.clock.now()))
+            .with_args(trace.args()));
+    }
+
+    /// Trace the end of the operation with the given `id`.
+    ///
+    /// This is `TraceSink::trace_stop`, but through a shared reference.
+    pub fn trace_stop(&self, id: T::Id, trace: T) {
+        self.write(&Entry::new(TraceKind::Stop,
+                               trace.tag(),
+                               id.u32(),
+                               id.thread(),
+                               None,
+                               NsSinceEpoch(self.clock.now()))
+            .with_args(trace.args()));
+    }
+
+    /// Record that `count` traces with the given tag were dropped.
+    ///
+    /// This is `TraceSink::trace_dropped`, but through a shared reference.
+    pub fn trace_dropped(&self, tag: u32, count: u64) {
+        self.write(&Entry::new(TraceKind::Dropped,
+                               tag,
+                               0,
+                               None,
+                               None,
+                               NsSinceEpoch(self.clock.now()))
+            .with_args([count, 0, 0, 0]));
+    }
+}
+
+impl<'a, T, C> TraceSink<T> for &'a AtomicRingBuffer<T, C>
+    where T: Trace,
+          C: Clock
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.trace_event_with_id(id, trace, why);
+        id
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.trace_start_with_id(id, trace, why);
+        id
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        AtomicRingBuffer::trace_stop(*self, id, trace)
+    }
+
+    fn trace_dropped(&mut self, tag: u32, count: u64) {
+        AtomicRingBuffer::trace_dropped(*self, tag, count)
+    }
+}
+
+impl<'a, T, C> TraceSinkWithId<T> for &'a AtomicRingBuffer<T, C>
+    where T: Trace,
+          C: Clock
+{
+    fn trace_event_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        AtomicRingBuffer::trace_event_with_id(*self, id, trace, why)
+    }
+
+    fn trace_start_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        AtomicRingBuffer::trace_start_with_id(*self, id, trace, why)
+    }
+}
+
+impl<T, C> TraceSink<T> for AtomicRingBuffer<T, C>
+    where T: Trace,
+          C: Clock
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.trace_event_with_id(id, trace, why);
+        id
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.trace_start_with_id(id, trace, why);
+        id
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        AtomicRingBuffer::trace_stop(self, id, trace)
+    }
+
+    fn trace_dropped(&mut self, tag: u32, count: u64) {
+        AtomicRingBuffer::trace_dropped(self, tag, count)
+    }
+}
+
+impl<T, C> TraceSinkWithId<T> for AtomicRingBuffer<T, C>
+    where T: Trace,
+          C: Clock
+{
+    fn trace_event_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        AtomicRingBuffer::trace_event_with_id(self, id, trace, why)
+    }
+
+    fn trace_start_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        AtomicRingBuffer::trace_start_with_id(self, id, trace, why)
+    }
+}
+
+/// An iterator over `Entry<T>`s in an `AtomicRingBuffer<T>`.
+#[derive(Clone, Debug)]
+pub struct AtomicRingBufferIter<'a, T, C = MonotonicClock>
+    where T: 'a,
+          C: 'a
+{
+    buffer: &'a AtomicRingBuffer<T, C>,
+    next: u64,
+    end: u64,
+    time_base: TimeBase,
+}
+
+impl<'a, T, C> Iterator for AtomicRingBufferIter<'a, T, C>
+    where C: Clock
+{
+    type Item = Entry<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.next < self.end {
+            let n = self.next;
+            self.next += 1;
+            if let Some(mut entry) = self.buffer.read(n) {
+                entry.timestamp = self.time_base.to_wall(entry.timestamp.0);
+                return Some(entry);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+
+    use super::*;
+    use clock::WallClock;
+    use std::sync::atomic::AtomicUsize;
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
+    use traits::{Trace, TraceId, TraceSink};
+
+    // A buffer whose raw timestamps are wall-clock times, so that the
+    // timestamps written by `write_entries` are read back out unchanged.
+    type WallClockBuffer = RingBuffer<SimpleTrace, WallClock>;
+
+    // Write `n` entries with deterministic timestamps, so that each entry is
+    // encoded in exactly five bytes: header, a two byte timestamp delta, id,
+    // and tag.
+    fn write_entries(buffer: &mut WallClockBuffer, n: u32) {
+        const ENTRY_SIZE: usize = 5;
+        for i in 0..n {
+            let length = buffer.length;
+            buffer.write(&Entry {
+                why: None,
+                thread: None,
+                id: i,
+                tag: i % 3,
+                args: [0; 4],
+                timestamp: NsSinceEpoch(1000 * (i as u64 + 1)),
+                kind: TraceKind::Event,
+                phantom: PhantomData,
+            });
+            assert!(buffer.length == length + ENTRY_SIZE || buffer.length == length);
+        }
+    }
+
+    fn assert_entries(buffer: &WallClockBuffer, ids: ::std::ops::Range<u32>) {
+        let entries: Vec<_> = buffer.iter().collect();
+        println!("entries = {:#?}", entries);
+        assert_eq!(entries.len(), ids.len());
+        for (entry, i) in entries.iter().zip(ids) {
+            assert_eq!(entry.id(), i);
+            assert_eq!(entry.tag(), i % 3);
+            assert_eq!(entry.timestamp(), NsSinceEpoch(1000 * (i as u64 + 1)));
+        }
+    }
 
     #[test]
-    fn trace_entry_has_right_size() {
-        assert_eq!(SimpleEntry::size(), 65);
+    fn typical_entry_is_small() {
+        let mut buffer = SimpleTraceBuffer::default();
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+        let length = buffer.length;
+        let id = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_stop(id, SimpleTrace::OperationThing);
+        assert!(buffer.length - length <= 2 * 15);
     }
 
     #[test]
     fn no_roll_over() {
-        let mut buffer = SimpleTraceBuffer::new(100 * SimpleEntry::size());
+        let mut buffer = SimpleTraceBuffer::new(100 * MAX_ENCODED_ENTRY_SIZE);
         buffer.trace_event(SimpleTrace::FooEvent, None);
         let thing_id = buffer.trace_start(SimpleTrace::OperationThing, None);
         let another_id = buffer.trace_start(SimpleTrace::OperationAnother, None);
@@ -422,84 +1807,299 @@ mod tests {
 
     #[test]
     fn with_roll_over() {
-        let mut buffer = SimpleTraceBuffer::new(5 * SimpleEntry::size());
-        buffer.trace_event(SimpleTrace::FooEvent, None);
-        let thing_id = buffer.trace_start(SimpleTrace::OperationThing, None);
-        let another_id = buffer.trace_start(SimpleTrace::OperationAnother, None);
-        buffer.trace_event(SimpleTrace::FooEvent, None);
-        buffer.trace_stop(thing_id, SimpleTrace::OperationThing);
-        buffer.trace_stop(another_id, SimpleTrace::OperationAnother);
+        let mut buffer = WallClockBuffer::with_clock(100, WallClock);
+        write_entries(&mut buffer, 22);
+        println!("buffer = {:#?}", buffer);
+        assert_entries(&buffer, 2..22);
+    }
 
+    #[test]
+    fn with_roll_over_and_does_not_divide_evenly() {
+        let mut buffer = WallClockBuffer::with_clock(97, WallClock);
+        write_entries(&mut buffer, 22);
         println!("buffer = {:#?}", buffer);
+        assert_entries(&buffer, 3..22);
+    }
 
-        let mut iter = buffer.iter();
+    #[test]
+    fn overwrite_oldest_counts_evictions() {
+        let mut buffer = WallClockBuffer::with_clock(100, WallClock);
+        write_entries(&mut buffer, 22);
+        let counters = buffer.counters();
+        assert_eq!(counters.written(), 22);
+        assert_eq!(counters.evicted(), 2);
+        assert_eq!(counters.dropped(), 0);
+        assert!(counters.is_truncated());
+    }
 
-        let entry = iter.next().unwrap();
-        println!("entry = {:#?}", entry);
-        assert_eq!(entry.tag(), SimpleTrace::OperationThing.tag());
-        assert_eq!(entry.kind(), TraceKind::Start);
-        assert_eq!(entry.label(), "Thing");
+    #[test]
+    fn drop_newest() {
+        let mut buffer = WallClockBuffer::with_clock(100, WallClock)
+            .with_overflow_policy(OverflowPolicy::DropNewest);
+        write_entries(&mut buffer, 22);
+        assert_entries(&buffer, 0..20);
+
+        let counters = buffer.counters();
+        assert_eq!(counters.written(), 20);
+        assert_eq!(counters.evicted(), 0);
+        assert_eq!(counters.dropped(), 2);
+        assert!(counters.is_truncated());
+    }
 
-        let entry = iter.next().unwrap();
-        println!("entry = {:#?}", entry);
-        assert_eq!(entry.tag(), SimpleTrace::OperationAnother.tag());
-        assert_eq!(entry.kind(), TraceKind::Start);
-        assert_eq!(entry.label(), "Another");
+    #[test]
+    fn flush_to_callback() {
+        let flushed = Arc::new(Mutex::new(vec![]));
+        let mut buffer = {
+            let flushed = flushed.clone();
+            SimpleTraceBuffer::new(10 * MAX_ENCODED_ENTRY_SIZE)
+                .with_overflow_policy(OverflowPolicy::flush(move |entries| {
+                    flushed.lock().unwrap().extend(entries);
+                }))
+        };
 
-        let entry = iter.next().unwrap();
-        println!("entry = {:#?}", entry);
-        assert_eq!(entry.tag(), SimpleTrace::FooEvent.tag());
-        assert_eq!(entry.kind(), TraceKind::Event);
-        assert_eq!(entry.label(), "Foo");
+        let ids: Vec<_> = (0..500)
+            .map(|_| buffer.trace_event(SimpleTrace::FooEvent, None))
+            .collect();
 
-        let entry = iter.next().unwrap();
-        println!("entry = {:#?}", entry);
-        assert_eq!(entry.tag(), SimpleTrace::OperationThing.tag());
-        assert_eq!(entry.kind(), TraceKind::Stop);
-        assert_eq!(entry.label(), "Thing");
+        let flushed = flushed.lock().unwrap();
+        let entries: Vec<_> = flushed.iter().cloned().chain(buffer.iter()).collect();
+        assert_eq!(entries.len(), ids.len());
+        for (entry, id) in entries.iter().zip(ids) {
+            assert_eq!(entry.id(), id.u32());
+        }
+        for pair in entries.windows(2) {
+            assert!(pair[0].timestamp() <= pair[1].timestamp());
+        }
 
-        let entry = iter.next().unwrap();
-        println!("entry = {:#?}", entry);
-        assert_eq!(entry.tag(), SimpleTrace::OperationAnother.tag());
-        assert_eq!(entry.kind(), TraceKind::Stop);
-        assert_eq!(entry.label(), "Another");
+        let counters = buffer.counters();
+        assert_eq!(counters.written(), 500);
+        assert_eq!(counters.flushed(), flushed.len() as u64);
+        assert_eq!(counters.evicted() + counters.dropped(), 0);
+        assert!(counters.is_truncated());
+    }
 
-        assert_eq!(iter.next(), None);
+    #[test]
+    fn flush_after_callback_panics() {
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let mut buffer = {
+            let flushes = flushes.clone();
+            SimpleTraceBuffer::new(10 * MAX_ENCODED_ENTRY_SIZE)
+                .with_overflow_policy(OverflowPolicy::flush(move |_| {
+                    if flushes.fetch_add(1, Ordering::SeqCst) == 0 {
+                        panic!("first flush");
+                    }
+                }))
+        };
+
+        let result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            for _ in 0..500 {
+                buffer.trace_event(SimpleTrace::FooEvent, None);
+            }
+        }));
+        assert!(result.is_err());
+
+        for _ in 0..500 {
+            buffer.trace_event(SimpleTrace::FooEvent, None);
+        }
+        assert!(flushes.load(Ordering::SeqCst) > 1);
     }
 
     #[test]
-    fn with_roll_over_and_does_not_divide_evenly() {
-        let mut buffer = SimpleTraceBuffer::new(3 * SimpleEntry::size() + 1);
-        buffer.trace_event(SimpleTrace::FooEvent, None);
-        let thing_id = buffer.trace_start(SimpleTrace::OperationThing, None);
-        let another_id = buffer.trace_start(SimpleTrace::OperationAnother, None);
+    fn snapshot() {
+        let mut buffer = WallClockBuffer::with_clock(97, WallClock);
+        write_entries(&mut buffer, 22);
+        let snapshot = buffer.snapshot();
+        assert_eq!(snapshot.iter().collect::<Vec<_>>(),
+                   buffer.iter().collect::<Vec<_>>());
+        assert_eq!(snapshot.counters(), buffer.counters());
+        assert_eq!(serde_json::to_value(&snapshot), serde_json::to_value(&buffer));
+
+        buffer.clear();
+        assert!(buffer.snapshot().is_empty());
+        assert_eq!(snapshot.iter().count(), 19);
+    }
+
+    #[test]
+    fn drain() {
+        let mut buffer = WallClockBuffer::with_clock(97, WallClock);
+        write_entries(&mut buffer, 22);
+
+        let drained: Vec<_> = buffer.drain().take(4).map(|e| e.id()).collect();
+        assert_eq!(drained, vec![3, 4, 5, 6]);
+        assert_entries(&buffer, 7..22);
+
+        assert_eq!(buffer.drain().count(), 15);
+        assert_eq!(buffer.iter().count(), 0);
+        assert_eq!(buffer.counters().written(), 22);
+
+        // New entries are still timestamped correctly after a drain.
+        let id = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_stop(id, SimpleTrace::OperationThing);
+        let entries: Vec<_> = buffer.drain().collect();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].timestamp() <= entries[1].timestamp());
+        assert!(entries[0].timestamp() >= NsSinceEpoch(22_000));
+    }
+
+    #[test]
+    fn clear() {
+        let mut buffer = WallClockBuffer::with_clock(100, WallClock);
+        write_entries(&mut buffer, 22);
+        buffer.clear();
+        assert_eq!(buffer.iter().count(), 0);
+        assert_eq!(buffer.counters().written(), 22);
+        assert_eq!(buffer.counters().evicted(), 2);
+
         buffer.trace_event(SimpleTrace::FooEvent, None);
-        buffer.trace_stop(thing_id, SimpleTrace::OperationThing);
-        buffer.trace_stop(another_id, SimpleTrace::OperationAnother);
+        let entries: Vec<_> = buffer.iter().collect();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].timestamp() > NsSinceEpoch(22_000));
+    }
 
-        println!("buffer = {:#?}", buffer);
+    // Write `n` entries like `write_entries` does, except that they have too
+    // many ids to all be encoded in the same number of bytes. Returns the id of
+    // the oldest entry left in the buffer.
+    fn write_many_entries(buffer: &mut WallClockBuffer, n: u32) -> u32 {
+        for i in 0..n {
+            buffer.write(&Entry::new(TraceKind::Event,
+                                     i % 3,
+                                     i,
+                                     None,
+                                     None,
+                                     NsSinceEpoch(1000 * (i as u64 + 1))));
+        }
+        buffer.iter().next().map_or(n, |e| e.id())
+    }
 
-        let mut iter = buffer.iter();
+    #[test]
+    fn index_stays_sparse() {
+        let mut buffer = WallClockBuffer::with_clock(10_000, WallClock);
+        let first = write_many_entries(&mut buffer, 5000);
+        assert!(first > 0);
+        assert_eq!(buffer.len(), (5000 - first) as usize);
+        assert!(buffer.index.len() <= 10_000 / INDEX_INTERVAL as usize + 1);
+        assert!(buffer.index.iter().all(|c| c.seq >= first as u64));
+    }
 
-        let entry = iter.next().unwrap();
-        println!("entry = {:#?}", entry);
-        assert_eq!(entry.tag(), SimpleTrace::FooEvent.tag());
-        assert_eq!(entry.kind(), TraceKind::Event);
-        assert_eq!(entry.label(), "Foo");
+    #[test]
+    fn iter_rev() {
+        let mut buffer = WallClockBuffer::with_clock(10_000, WallClock);
+        assert_eq!(buffer.iter_rev().count(), 0);
+
+        let first = write_many_entries(&mut buffer, 5000);
+        let mut entries: Vec<_> = buffer.iter().collect();
+        entries.reverse();
+        assert_eq!(buffer.iter_rev().collect::<Vec<_>>(), entries);
+
+        buffer.drain().take(7).count();
+        assert_eq!(buffer.iter_rev().count(), entries.len() - 7);
+        assert_eq!(buffer.iter_rev().last().map(|e| e.id()), Some(first + 7));
+    }
 
-        let entry = iter.next().unwrap();
-        println!("entry = {:#?}", entry);
-        assert_eq!(entry.tag(), SimpleTrace::OperationThing.tag());
-        assert_eq!(entry.kind(), TraceKind::Stop);
-        assert_eq!(entry.label(), "Thing");
+    #[test]
+    fn last_n() {
+        let mut buffer = WallClockBuffer::with_clock(10_000, WallClock);
+        write_many_entries(&mut buffer, 5000);
+
+        let ids: Vec<_> = buffer.last_n(10).map(|e| e.id()).collect();
+        assert_eq!(ids, (4990..5000).collect::<Vec<_>>());
+        assert_eq!(buffer.last_n(0).count(), 0);
+        assert_eq!(buffer.last_n(1_000_000).collect::<Vec<_>>(),
+                   buffer.iter().collect::<Vec<_>>());
+
+        buffer.clear();
+        assert_eq!(buffer.last_n(10).count(), 0);
+    }
 
-        let entry = iter.next().unwrap();
-        println!("entry = {:#?}", entry);
-        assert_eq!(entry.tag(), SimpleTrace::OperationAnother.tag());
-        assert_eq!(entry.kind(), TraceKind::Stop);
-        assert_eq!(entry.label(), "Another");
+    #[test]
+    fn iter_range() {
+        let mut buffer = WallClockBuffer::with_clock(10_000, WallClock);
+        let first = write_many_entries(&mut buffer, 5000) as u64;
+        let timestamp = |id: u64| NsSinceEpoch(1000 * (id + 1));
+
+        let ids: Vec<_> = buffer.iter_range(timestamp(4500), timestamp(4600))
+            .map(|e| e.id())
+            .collect();
+        assert_eq!(ids, (4500..4600).collect::<Vec<_>>());
+
+        // Bounds between entries' timestamps.
+        let ids: Vec<_> = buffer.iter_range(NsSinceEpoch(timestamp(4800).0 - 1),
+                        NsSinceEpoch(timestamp(4802).0 + 1))
+            .map(|e| e.id())
+            .collect();
+        assert_eq!(ids, vec![4800, 4801, 4802]);
+
+        // Ranges that start before the oldest entry, or end after the newest.
+        assert_eq!(buffer.iter_range(NsSinceEpoch(0), timestamp(first + 2)).count(), 2);
+        assert_eq!(buffer.iter_range(timestamp(4998), NsSinceEpoch(u64::max_value())).count(),
+                   2);
+        assert_eq!(buffer.iter_range(timestamp(10_000), timestamp(20_000)).count(), 0);
+        assert_eq!(buffer.iter_range(timestamp(4600), timestamp(4500)).count(), 0);
+    }
 
-        assert_eq!(iter.next(), None);
+    #[test]
+    fn resize_grow() {
+        let mut buffer = WallClockBuffer::with_clock(97, WallClock);
+        write_entries(&mut buffer, 22);
+        buffer.resize(200);
+        assert_eq!(buffer.capacity(), 200);
+        assert_entries(&buffer, 3..22);
+
+        for _ in 0..10 {
+            buffer.trace_event(SimpleTrace::FooEvent, None);
+        }
+        assert_eq!(buffer.len(), 29);
+        assert_eq!(buffer.iter_rev().count(), 29);
+        assert!(buffer.last_n(10).all(|e| e.tag() == SimpleTrace::FooEvent.tag()));
+        assert_eq!(buffer.last_n(11).next().map(|e| e.id()), Some(21));
+    }
+
+    #[test]
+    fn resize_shrink() {
+        let mut buffer = WallClockBuffer::with_clock(10_000, WallClock);
+        let first = write_many_entries(&mut buffer, 5000);
+        let entries: Vec<_> = buffer.iter().collect();
+        let evicted = buffer.counters().evicted();
+
+        buffer.resize(1000);
+        let kept: Vec<_> = buffer.iter().collect();
+        assert!(kept.len() < entries.len());
+        assert_eq!(&kept[..], &entries[entries.len() - kept.len()..]);
+        assert_eq!(buffer.counters().evicted() - evicted,
+                   (entries.len() - kept.len()) as u64);
+        assert!(first < kept[0].id());
+
+        let ids: Vec<_> = buffer.iter_range(NsSinceEpoch(1000 * 4991),
+                        NsSinceEpoch(u64::max_value()))
+            .map(|e| e.id())
+            .collect();
+        assert_eq!(ids, (4990..5000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    #[should_panic]
+    fn resize_too_small() {
+        let mut buffer = SimpleTraceBuffer::default();
+        buffer.resize(MAX_ENCODED_ENTRY_SIZE - 1);
+    }
+
+    #[test]
+    fn with_roll_over_of_real_traces() {
+        let mut buffer = SimpleTraceBuffer::new(MAX_ENCODED_ENTRY_SIZE);
+        for _ in 0..100 {
+            let id = buffer.trace_start(SimpleTrace::OperationThing, None);
+            buffer.trace_stop(id, SimpleTrace::OperationThing);
+        }
+
+        let entries: Vec<_> = buffer.iter().collect();
+        assert!(entries.len() > 1);
+        for pair in entries.windows(2) {
+            assert!(pair[0].kind() != pair[1].kind());
+            assert!(pair[0].timestamp() <= pair[1].timestamp());
+        }
+        for entry in entries {
+            assert_eq!(entry.label(), "Thing");
+        }
     }
 
     #[test]
@@ -550,9 +2150,66 @@ mod tests {
         assert_eq!(entry.why(), None);
     }
 
+    #[derive(Copy, Clone, Debug)]
+    struct Decoded(u64);
+
+    impl Trace for Decoded {
+        type Id = ::simple_trace::SimpleTraceId;
+
+        fn label(_: u32) -> &'static str {
+            "Decoded"
+        }
+
+        fn tag(&self) -> u32 {
+            0
+        }
+
+        fn args(&self) -> [u64; 4] {
+            [self.0, 0, 0, 1 << 40]
+        }
+    }
+
+    #[test]
+    fn args() {
+        let mut buffer = RingBuffer::new(10 * MAX_ENCODED_ENTRY_SIZE);
+        buffer.trace_event(Decoded(1024), None);
+        let id = buffer.trace_start(Decoded(0), None);
+        buffer.trace_stop(id, Decoded(7));
+
+        let args: Vec<_> = buffer.iter().map(|e| e.args()).collect();
+        assert_eq!(args, vec![[1024, 0, 0, 1 << 40], [0, 0, 0, 1 << 40], [7, 0, 0, 1 << 40]]);
+
+        let entry = buffer.iter().next().unwrap();
+        let serialized = serde_json::to_string(&entry).expect("should serialize OK");
+        assert!(serialized.contains("\"args\":[1024,0,0,1099511627776]"));
+    }
+
+    #[test]
+    fn atomic_args() {
+        let buffer = AtomicRingBuffer::new(4);
+        buffer.trace_event(Decoded(1024), None);
+        let entry = buffer.iter().next().unwrap();
+        assert_eq!(entry.args(), [1024, 0, 0, 1 << 40]);
+    }
+
+    #[test]
+    fn dropped() {
+        let mut buffer = SimpleTraceBuffer::default();
+        TraceSink::<SimpleTrace>::trace_dropped(&mut buffer, 7, 99);
+        let atomic = AtomicRingBuffer::<SimpleTrace>::new(4);
+        atomic.trace_dropped(7, 99);
+
+        for entry in buffer.iter().chain(atomic.iter()) {
+            assert_eq!(entry.kind(), TraceKind::Dropped);
+            assert_eq!(entry.tag(), 7);
+            assert_eq!(entry.args(), [99, 0, 0, 0]);
+        }
+        assert_eq!(buffer.iter().count() + atomic.iter().count(), 2);
+    }
+
     #[test]
     fn serialize_entry() {
-        let mut buffer = SimpleTraceBuffer::new(2 * SimpleEntry::size());
+        let mut buffer = SimpleTraceBuffer::new(2 * MAX_ENCODED_ENTRY_SIZE);
         buffer.trace_event(SimpleTrace::FooEvent, None);
         let entry = buffer.iter().next().unwrap();
 
@@ -564,7 +2221,7 @@ mod tests {
 
     #[test]
     fn serialize_ring_buffer() {
-        let mut buffer = SimpleTraceBuffer::new(10 * SimpleEntry::size());
+        let mut buffer = SimpleTraceBuffer::new(10 * MAX_ENCODED_ENTRY_SIZE);
 
         for _ in 0..10 {
             let event = buffer.trace_event(SimpleTrace::FooEvent, None);
@@ -578,5 +2235,94 @@ mod tests {
 
         println!("");
         println!("serialized = {}", serialized);
+        assert!(serialized.contains("\"counters\""));
+    }
+
+    #[test]
+    fn atomic_no_roll_over() {
+        let buffer = AtomicRingBuffer::new(100);
+        let parent = buffer.trace_event(SimpleTrace::FooEvent, None);
+        let thing_id = buffer.trace_start(SimpleTrace::OperationThing, Some(parent));
+        buffer.trace_stop(thing_id, SimpleTrace::OperationThing);
+
+        let mut iter = buffer.iter();
+
+        let entry = iter.next().unwrap();
+        assert_eq!(entry.kind(), TraceKind::Event);
+        assert_eq!(entry.label(), "Foo");
+        assert_eq!(entry.why(), None);
+        let parent = (entry.thread(), entry.id());
+
+        let entry = iter.next().unwrap();
+        assert_eq!(entry.kind(), TraceKind::Start);
+        assert_eq!(entry.label(), "Thing");
+        assert_eq!(entry.why(), Some(parent));
+        let start = entry;
+
+        let entry = iter.next().unwrap();
+        assert_eq!(entry.kind(), TraceKind::Stop);
+        assert_eq!(entry.label(), "Thing");
+        assert_eq!(entry.id(), start.id());
+        assert!(entry.timestamp().0 >= start.timestamp().0);
+
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn atomic_with_roll_over() {
+        let mut buffer = AtomicRingBuffer::new(4);
+        let ids: Vec<_> = (0..10)
+            .map(|_| TraceSink::trace_event(&mut buffer, SimpleTrace::FooEvent, None).u32())
+            .collect();
+
+        let entries: Vec<_> = buffer.iter().map(|e| e.id()).collect();
+        assert_eq!(entries, &ids[6..]);
+    }
+
+    #[test]
+    fn atomic_from_many_threads() {
+        use std::collections::HashSet;
+        use std::sync::Arc;
+        use std::thread;
+        use threaded_trace_id::ThreadedTraceId;
+
+        #[derive(Copy, Clone, Debug)]
+        struct Threaded;
+
+        impl Trace for Threaded {
+            type Id = ThreadedTraceId;
+
+            fn label(_: u32) -> &'static str {
+                "Threaded"
+            }
+
+            fn tag(&self) -> u32 {
+                0
+            }
+        }
+
+        let buffer = Arc::new(AtomicRingBuffer::new(4 * 1000));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let buffer = buffer.clone();
+                thread::spawn(move || {
+                    let mut sink = &*buffer;
+                    for _ in 0..1000 {
+                        TraceSink::trace_event(&mut sink, Threaded, None);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let mut seen = HashSet::new();
+        for entry in buffer.iter() {
+            assert_eq!(entry.kind(), TraceKind::Event);
+            assert!(entry.thread().is_some());
+            assert!(seen.insert((entry.thread(), entry.id())));
+        }
+        assert_eq!(seen.len(), 4 * 1000);
     }
 }
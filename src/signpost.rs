@@ -1,7 +1,7 @@
 extern crate signpost;
 
 use std::marker::PhantomData;
-use traits::{Trace, TraceId, TraceSink};
+use traits::{Trace, TraceId, TraceSink, TraceSinkWithId};
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct Signpost<T>(PhantomData<T>);
@@ -12,23 +12,42 @@ impl<T> Signpost<T> {
     }
 }
 
-static EMPTY_ARGS: [usize; 4] = [0, 0, 0, 0];
+fn args<T>(trace: &T) -> [usize; 4]
+    where T: Trace
+{
+    let args = trace.args();
+    [args[0] as usize, args[1] as usize, args[2] as usize, args[3] as usize]
+}
 
 impl<T> TraceSink<T> for Signpost<T>
     where T: Trace
 {
-    fn trace_event(&mut self, trace: T, _why: Option<T::Id>) -> T::Id {
-        signpost::trace(trace.tag(), &EMPTY_ARGS);
-        T::Id::new_id()
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.trace_event_with_id(id, trace, why);
+        id
     }
 
-    fn trace_start(&mut self, trace: T, _why: Option<T::Id>) -> T::Id {
-        signpost::start(trace.tag(), &EMPTY_ARGS);
-        T::Id::new_id()
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.trace_start_with_id(id, trace, why);
+        id
     }
 
     fn trace_stop(&mut self, _id: T::Id, trace: T) {
-        signpost::end(trace.tag(), &EMPTY_ARGS);
+        signpost::end(trace.tag(), &args(&trace));
+    }
+}
+
+impl<T> TraceSinkWithId<T> for Signpost<T>
+    where T: Trace
+{
+    fn trace_event_with_id(&mut self, _id: T::Id, trace: T, _why: Option<T::Id>) {
+        signpost::trace(trace.tag(), &args(&trace));
+    }
+
+    fn trace_start_with_id(&mut self, _id: T::Id, trace: T, _why: Option<T::Id>) {
+        signpost::start(trace.tag(), &args(&trace));
     }
 }
 
@@ -1,8 +1,14 @@
 //! Combinators for building up complex `TraceSink` implementations from simple
 //! parts.
 
-use std::sync::atomic::{AtomicBool, Ordering};
-use traits::{Trace, TraceId, TraceSink};
+use clock::{Clock, MonotonicClock};
+use ring_buffer::NsSinceEpoch;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use traits::{ThreadId, Trace, TraceId, TraceSink, TraceSinkWithId};
 
 /// A wrapper around another `TraceSink` that adds dynamically enabling or
 /// disabling tracing.
@@ -88,13 +94,772 @@ impl<S, T> TraceSink<T> for ToggleSink<S>
             self.sink.trace_stop(id, trace);
         }
     }
+
+    fn trace_sample_rate(&mut self, tag: u32, rate: f64) {
+        self.sink.trace_sample_rate(tag, rate);
+    }
+
+    fn trace_dropped(&mut self, tag: u32, count: u64) {
+        if self.is_enabled() {
+            self.sink.trace_dropped(tag, count);
+        }
+    }
+}
+
+impl<S, T> TraceSinkWithId<T> for ToggleSink<S>
+    where S: TraceSinkWithId<T>,
+          T: Trace
+{
+    fn trace_event_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        if self.is_enabled() {
+            self.sink.trace_event_with_id(id, trace, why);
+        }
+    }
+
+    fn trace_start_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        if self.is_enabled() {
+            self.sink.trace_start_with_id(id, trace, why);
+        }
+    }
+}
+
+const BITS: usize = 8 * ::std::mem::size_of::<usize>();
+
+/// A wrapper around another `TraceSink` that adds dynamically enabling or
+/// disabling tracing of individual tags.
+///
+/// The enabled tags are kept in an atomic bitset with room for `num_tags`
+/// tags, so they can be changed through a shared reference, for example from
+/// another thread. Tags at or above `num_tags` can only be enabled or disabled
+/// all at once, with `enable_all` and `disable_all`.
+///
+/// Traces with disabled tags are not passed through to the underlying sink,
+/// but `trace_event` and `trace_start` still return new IDs for them. A stop is
+/// passed through exactly when its start was, so that spans stay paired even
+/// if their tag is enabled or disabled while they are running.
+///
+/// ```
+/// use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+/// use eep::sink_combinators::FilterSink;
+/// use eep::traits::{Trace, TraceSink};
+///
+/// let mut sink = FilterSink::new_disabled(SimpleTraceBuffer::default(), 3);
+/// sink.enable(SimpleTrace::OperationThing.tag());
+///
+/// let id = sink.trace_start(SimpleTrace::OperationThing, None);
+/// sink.trace_event(SimpleTrace::FooEvent, Some(id));
+/// sink.trace_stop(id, SimpleTrace::OperationThing);
+///
+/// assert_eq!(sink.as_ref().iter().count(), 2);
+/// ```
+#[derive(Debug)]
+pub struct FilterSink<S> {
+    enabled: Vec<AtomicUsize>,
+    others: AtomicBool,
+    num_tags: u32,
+    sink: S,
+    // The starts that were passed through, whose stops should be too.
+    live: HashSet<(Option<ThreadId>, u32)>,
+}
+
+impl<S> FilterSink<S> {
+    /// Construct a new `FilterSink` with the given `sink`, and room in its
+    /// bitset for tags below `num_tags`, that initially has every tag enabled.
+    pub fn new_enabled(sink: S, num_tags: u32) -> FilterSink<S> {
+        FilterSink::new(sink, num_tags, true)
+    }
+
+    /// Construct a new `FilterSink` with the given `sink`, and room in its
+    /// bitset for tags below `num_tags`, that initially has every tag disabled.
+    pub fn new_disabled(sink: S, num_tags: u32) -> FilterSink<S> {
+        FilterSink::new(sink, num_tags, false)
+    }
+
+    fn new(sink: S, num_tags: u32, enabled: bool) -> FilterSink<S> {
+        let words = (num_tags as usize + BITS - 1) / BITS;
+        let word = if enabled { !0 } else { 0 };
+        FilterSink {
+            enabled: (0..words).map(|_| AtomicUsize::new(word)).collect(),
+            others: AtomicBool::new(enabled),
+            num_tags: num_tags,
+            sink: sink,
+            live: HashSet::new(),
+        }
+    }
+
+    /// Enable tracing of the given tag.
+    ///
+    /// Panics if `tag` is not below this sink's `num_tags`.
+    pub fn enable(&self, tag: u32) {
+        let (word, bit) = self.position(tag);
+        self.enabled[word].fetch_or(bit, Ordering::Release);
+    }
+
+    /// Disable tracing of the given tag.
+    ///
+    /// Panics if `tag` is not below this sink's `num_tags`.
+    pub fn disable(&self, tag: u32) {
+        let (word, bit) = self.position(tag);
+        self.enabled[word].fetch_and(!bit, Ordering::Release);
+    }
+
+    /// Enable tracing of every tag.
+    pub fn enable_all(&self) {
+        for word in &self.enabled {
+            word.store(!0, Ordering::Release);
+        }
+        self.others.store(true, Ordering::Release);
+    }
+
+    /// Disable tracing of every tag.
+    pub fn disable_all(&self) {
+        for word in &self.enabled {
+            word.store(0, Ordering::Release);
+        }
+        self.others.store(false, Ordering::Release);
+    }
+
+    /// Return `true` if tracing of the given tag is enabled, `false`
+    /// otherwise.
+    pub fn is_enabled(&self, tag: u32) -> bool {
+        if tag >= self.num_tags {
+            return self.others.load(Ordering::Acquire);
+        }
+        let (word, bit) = self.position(tag);
+        self.enabled[word].load(Ordering::Acquire) & bit != 0
+    }
+
+    fn position(&self, tag: u32) -> (usize, usize) {
+        assert!(tag < self.num_tags,
+                "tag {} is not below the FilterSink's num_tags, {}",
+                tag,
+                self.num_tags);
+        (tag as usize / BITS, 1 << (tag as usize % BITS))
+    }
+}
+
+impl<S> AsRef<S> for FilterSink<S> {
+    fn as_ref(&self) -> &S {
+        &self.sink
+    }
+}
+
+impl<S> AsMut<S> for FilterSink<S> {
+    fn as_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+}
+
+impl<S, T> TraceSink<T> for FilterSink<S>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        if self.is_enabled(trace.tag()) {
+            self.sink.trace_event(trace, why)
+        } else {
+            T::Id::new_id()
+        }
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        if self.is_enabled(trace.tag()) {
+            let id = self.sink.trace_start(trace, why);
+            self.live.insert((id.thread(), id.u32()));
+            id
+        } else {
+            T::Id::new_id()
+        }
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        if self.live.remove(&(id.thread(), id.u32())) {
+            self.sink.trace_stop(id, trace);
+        }
+    }
+
+    fn trace_sample_rate(&mut self, tag: u32, rate: f64) {
+        self.sink.trace_sample_rate(tag, rate);
+    }
+
+    fn trace_dropped(&mut self, tag: u32, count: u64) {
+        if self.is_enabled(tag) {
+            self.sink.trace_dropped(tag, count);
+        }
+    }
+}
+
+impl<S, T> TraceSinkWithId<T> for FilterSink<S>
+    where S: TraceSinkWithId<T>,
+          T: Trace
+{
+    fn trace_event_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        if self.is_enabled(trace.tag()) {
+            self.sink.trace_event_with_id(id, trace, why);
+        }
+    }
+
+    fn trace_start_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        if self.is_enabled(trace.tag()) {
+            self.live.insert((id.thread(), id.u32()));
+            self.sink.trace_start_with_id(id, trace, why);
+        }
+    }
+}
+
+/// How often a `SamplingSink` records a tag's traces.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SampleRate {
+    /// Record every Nth trace, starting with the first.
+    EveryNth(u64),
+    /// Record each trace at random, with the given probability.
+    Fraction(f64),
+}
+
+impl SampleRate {
+    /// Get the fraction of traces that are recorded at this rate.
+    pub fn fraction(&self) -> f64 {
+        match *self {
+            SampleRate::EveryNth(n) => 1.0 / n as f64,
+            SampleRate::Fraction(p) => p,
+        }
+    }
+
+    fn assert_valid(&self) {
+        match *self {
+            SampleRate::EveryNth(n) => assert!(n > 0, "cannot sample every 0th trace"),
+            SampleRate::Fraction(p) => {
+                assert!(p > 0.0 && p <= 1.0, "sample fraction {} is not in (0, 1]", p)
+            }
+        }
+    }
+}
+
+/// A wrapper around another `TraceSink` that only passes through a sample of
+/// each tag's traces.
+///
+/// Events and starts are sampled independently for each tag. A stop is passed
+/// through exactly when its start was, so that spans stay paired. Entries
+/// whose `why` was sampled out will be missing their cause.
+///
+/// The first time a tag is traced, its rate is passed to the underlying sink
+/// with `TraceSink::trace_sample_rate`, so statistics can be rescaled; see
+/// `stats::Summary::with_sample_rates`. A rate that is passed to this sink,
+/// for example by another `SamplingSink` wrapped around it, is multiplied by
+/// the tag's own rate before it is passed on.
+///
+/// ```
+/// use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+/// use eep::sink_combinators::{SampleRate, SamplingSink};
+/// use eep::traits::{Trace, TraceSink};
+///
+/// let mut sink = SamplingSink::new(SimpleTraceBuffer::default(), SampleRate::EveryNth(10));
+/// for _ in 0..100 {
+///     sink.trace_event(SimpleTrace::FooEvent, None);
+/// }
+///
+/// let buffer = sink.as_ref();
+/// assert_eq!(buffer.iter().count(), 10);
+/// assert_eq!(buffer.sample_rates()[&SimpleTrace::FooEvent.tag()], 0.1);
+/// ```
+#[derive(Debug)]
+pub struct SamplingSink<S> {
+    sink: S,
+    rate: SampleRate,
+    tags: HashMap<u32, TagSampler>,
+    // The starts that were passed through, whose stops should be too.
+    live: HashSet<(Option<ThreadId>, u32)>,
+    // The state of a xorshift64* generator, for `SampleRate::Fraction`.
+    rng: u64,
+}
+
+#[derive(Debug)]
+struct TagSampler {
+    rate: SampleRate,
+    count: u64,
+    reported: bool,
+}
+
+impl TagSampler {
+    fn new(rate: SampleRate) -> TagSampler {
+        TagSampler {
+            rate: rate,
+            count: 0,
+            reported: false,
+        }
+    }
+}
+
+impl<S> SamplingSink<S> {
+    /// Construct a new `SamplingSink` with the given `sink` that samples every
+    /// tag at the given rate.
+    ///
+    /// Panics if the rate is every 0th trace, or a fraction that is not greater
+    /// than zero and at most one.
+    pub fn new(sink: S, rate: SampleRate) -> SamplingSink<S> {
+        rate.assert_valid();
+        // Seed from the standard library's per-process random keys, and make
+        // sure the seed is non-zero.
+        let seed = RandomState::new().build_hasher().finish() | 1;
+        SamplingSink {
+            sink: sink,
+            rate: rate,
+            tags: HashMap::new(),
+            live: HashSet::new(),
+            rng: seed,
+        }
+    }
+
+    /// Sample the given tag at the given rate, instead of this sink's default
+    /// rate.
+    ///
+    /// Panics under the same conditions as `new`.
+    pub fn with_tag_rate(mut self, tag: u32, rate: SampleRate) -> SamplingSink<S> {
+        rate.assert_valid();
+        self.tags.insert(tag, TagSampler::new(rate));
+        self
+    }
+
+    /// Get the rate that the given tag is sampled at.
+    pub fn rate(&self, tag: u32) -> SampleRate {
+        self.tags.get(&tag).map_or(self.rate, |t| t.rate)
+    }
+
+    // Decide whether to pass through the next trace with the given tag.
+    fn sample<T>(&mut self, tag: u32) -> bool
+        where S: TraceSink<T>,
+              T: Trace
+    {
+        let rate = self.rate;
+        let sampler = self.tags.entry(tag).or_insert_with(|| TagSampler::new(rate));
+        if !sampler.reported {
+            sampler.reported = true;
+            self.sink.trace_sample_rate(tag, sampler.rate.fraction());
+        }
+
+        match sampler.rate {
+            SampleRate::EveryNth(n) => {
+                let sampled = sampler.count % n == 0;
+                sampler.count += 1;
+                sampled
+            }
+            SampleRate::Fraction(p) => {
+                self.rng ^= self.rng >> 12;
+                self.rng ^= self.rng << 25;
+                self.rng ^= self.rng >> 27;
+                let bits = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+                (bits as f64 / (1u64 << 53) as f64) < p
+            }
+        }
+    }
+}
+
+impl<S> AsRef<S> for SamplingSink<S> {
+    fn as_ref(&self) -> &S {
+        &self.sink
+    }
+}
+
+impl<S> AsMut<S> for SamplingSink<S> {
+    fn as_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+}
+
+impl<S, T> TraceSink<T> for SamplingSink<S>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        if self.sample(trace.tag()) {
+            self.sink.trace_event(trace, why)
+        } else {
+            T::Id::new_id()
+        }
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        if self.sample(trace.tag()) {
+            let id = self.sink.trace_start(trace, why);
+            self.live.insert((id.thread(), id.u32()));
+            id
+        } else {
+            T::Id::new_id()
+        }
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        if self.live.remove(&(id.thread(), id.u32())) {
+            self.sink.trace_stop(id, trace);
+        }
+    }
+
+    fn trace_sample_rate(&mut self, tag: u32, rate: f64) {
+        let default = self.rate;
+        let sampler = self.tags.entry(tag).or_insert_with(|| TagSampler::new(default));
+        // The combined rate already includes this sink's own, which must not
+        // replace it when the tag is first sampled.
+        sampler.reported = true;
+        self.sink.trace_sample_rate(tag, rate * sampler.rate.fraction());
+    }
+
+    fn trace_dropped(&mut self, tag: u32, count: u64) {
+        self.sink.trace_dropped(tag, count);
+    }
+}
+
+impl<S, T> TraceSinkWithId<T> for SamplingSink<S>
+    where S: TraceSinkWithId<T>,
+          T: Trace
+{
+    fn trace_event_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        if self.sample(trace.tag()) {
+            self.sink.trace_event_with_id(id, trace, why);
+        }
+    }
+
+    fn trace_start_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        if self.sample(trace.tag()) {
+            self.live.insert((id.thread(), id.u32()));
+            self.sink.trace_start_with_id(id, trace, why);
+        }
+    }
+}
+
+/// A wrapper around another `TraceSink` that limits how many traces with each
+/// tag are passed through in a window of time, so that a hot loop cannot
+/// evict the rest of the history from a `RingBuffer`.
+///
+/// Each tag has a token bucket that holds up to `limit` tokens, and refills at
+/// `limit` tokens per `window`. Every event and start with the tag takes a
+/// token, and is dropped if there are none. A stop is passed through exactly
+/// when its start was, so that spans stay paired. Stops never take a token,
+/// and the stop of a dropped start is not counted as dropped again.
+///
+/// The next time a tag's trace is passed through after some were dropped, the
+/// number dropped is passed to the underlying sink first, with
+/// `TraceSink::trace_dropped`. Call `report_dropped` to pass on the counts
+/// without waiting for the next trace, for example before saving a trace.
+///
+/// ```
+/// use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+/// use eep::sink_combinators::RateLimitSink;
+/// use eep::ring_buffer::TraceKind;
+/// use eep::traits::TraceSink;
+/// use std::time::Duration;
+///
+/// let mut sink = RateLimitSink::new(SimpleTraceBuffer::default(), 10, Duration::from_secs(60));
+/// for _ in 0..100 {
+///     sink.trace_event(SimpleTrace::FooEvent, None);
+/// }
+/// sink.report_dropped::<SimpleTrace>();
+///
+/// let entries: Vec<_> = sink.as_ref().iter().collect();
+/// assert_eq!(entries.len(), 11);
+/// assert_eq!(entries[10].kind(), TraceKind::Dropped);
+/// assert_eq!(entries[10].args()[0], 90);
+/// ```
+#[derive(Debug)]
+pub struct RateLimitSink<S, C = MonotonicClock> {
+    sink: S,
+    clock: C,
+    limit: u64,
+    window: u64,
+    buckets: HashMap<u32, Bucket>,
+    // The starts that were passed through, whose stops should be too.
+    live: HashSet<(Option<ThreadId>, u32)>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    // When `tokens` was last refilled.
+    refilled: NsSinceEpoch,
+    dropped: u64,
+}
+
+impl<S> RateLimitSink<S> {
+    /// Construct a new `RateLimitSink` with the given `sink` that passes
+    /// through at most `limit` traces with each tag per `window`, measured
+    /// with a `MonotonicClock`.
+    ///
+    /// Panics if `limit` or `window` is zero.
+    pub fn new(sink: S, limit: u64, window: Duration) -> RateLimitSink<S> {
+        RateLimitSink::with_clock(sink, limit, window, MonotonicClock::new())
+    }
+}
+
+impl<S, C> RateLimitSink<S, C>
+    where C: Clock
+{
+    /// Construct a new `RateLimitSink` with the given `sink` that passes
+    /// through at most `limit` traces with each tag per `window`, measured
+    /// with the given clock.
+    ///
+    /// Panics if `limit` or `window` is zero.
+    pub fn with_clock(sink: S, limit: u64, window: Duration, clock: C) -> RateLimitSink<S, C> {
+        let window = window.as_secs() * 1_000_000_000 + window.subsec_nanos() as u64;
+        assert!(limit > 0, "cannot limit traces to zero per window");
+        assert!(window > 0, "cannot limit traces per empty window");
+        RateLimitSink {
+            sink: sink,
+            clock: clock,
+            limit: limit,
+            window: window,
+            buckets: HashMap::new(),
+            live: HashSet::new(),
+        }
+    }
+
+    /// Pass the number of traces that were dropped for each tag, since it was
+    /// last passed, to the underlying sink.
+    pub fn report_dropped<T>(&mut self)
+        where S: TraceSink<T>,
+              T: Trace
+    {
+        let mut tags: Vec<_> = self.buckets
+            .iter()
+            .filter(|&(_, bucket)| bucket.dropped > 0)
+            .map(|(&tag, _)| tag)
+            .collect();
+        tags.sort();
+        for tag in tags {
+            let bucket = self.buckets.get_mut(&tag).unwrap();
+            self.sink.trace_dropped(tag, bucket.dropped);
+            bucket.dropped = 0;
+        }
+    }
+
+    // Take a token for the next trace with the given tag, or count the trace
+    // as dropped if there are none.
+    fn admit<T>(&mut self, tag: u32) -> bool
+        where S: TraceSink<T>,
+              T: Trace
+    {
+        let now = self.clock.time_base().to_wall(self.clock.now());
+        let limit = self.limit as f64;
+        let bucket = self.buckets.entry(tag).or_insert_with(|| {
+            Bucket {
+                tokens: limit,
+                refilled: now,
+                dropped: 0,
+            }
+        });
+
+        let elapsed = now.0.saturating_sub(bucket.refilled.0);
+        bucket.tokens = (bucket.tokens + elapsed as f64 * limit / self.window as f64).min(limit);
+        bucket.refilled = NsSinceEpoch(::std::cmp::max(now.0, bucket.refilled.0));
+
+        if bucket.tokens < 1.0 {
+            bucket.dropped += 1;
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+        if bucket.dropped > 0 {
+            self.sink.trace_dropped(tag, bucket.dropped);
+            bucket.dropped = 0;
+        }
+        true
+    }
+}
+
+impl<S, C> AsRef<S> for RateLimitSink<S, C> {
+    fn as_ref(&self) -> &S {
+        &self.sink
+    }
+}
+
+impl<S, C> AsMut<S> for RateLimitSink<S, C> {
+    fn as_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+}
+
+impl<S, C, T> TraceSink<T> for RateLimitSink<S, C>
+    where S: TraceSink<T>,
+          C: Clock,
+          T: Trace
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        if self.admit(trace.tag()) {
+            self.sink.trace_event(trace, why)
+        } else {
+            T::Id::new_id()
+        }
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        if self.admit(trace.tag()) {
+            let id = self.sink.trace_start(trace, why);
+            self.live.insert((id.thread(), id.u32()));
+            id
+        } else {
+            T::Id::new_id()
+        }
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        if self.live.remove(&(id.thread(), id.u32())) {
+            self.sink.trace_stop(id, trace);
+        }
+    }
+
+    fn trace_sample_rate(&mut self, tag: u32, rate: f64) {
+        self.sink.trace_sample_rate(tag, rate);
+    }
+
+    fn trace_dropped(&mut self, tag: u32, count: u64) {
+        self.sink.trace_dropped(tag, count);
+    }
+}
+
+impl<S, C, T> TraceSinkWithId<T> for RateLimitSink<S, C>
+    where S: TraceSinkWithId<T>,
+          C: Clock,
+          T: Trace
+{
+    fn trace_event_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        if self.admit(trace.tag()) {
+            self.sink.trace_event_with_id(id, trace, why);
+        }
+    }
+
+    fn trace_start_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        if self.admit(trace.tag()) {
+            self.live.insert((id.thread(), id.u32()));
+            self.sink.trace_start_with_id(id, trace, why);
+        }
+    }
+}
+
+/// A `TraceSink` that forwards every trace to two other sinks.
+///
+/// Both sinks record the same ID for each trace, so the IDs that a `TeeSink`
+/// returns can be passed to `trace_stop` or used as a `why` for either sink.
+/// That requires both sinks to implement `traits::TraceSinkWithId`. Fan out to
+/// more sinks by nesting `TeeSink`s.
+///
+/// ```
+/// use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+/// use eep::sink_combinators::TeeSink;
+/// use eep::traits::TraceSink;
+///
+/// let mut sink = TeeSink::new(SimpleTraceBuffer::default(),
+///                             TeeSink::new(SimpleTraceBuffer::default(),
+///                                          SimpleTraceBuffer::default()));
+/// let id = sink.trace_start(SimpleTrace::OperationThing, None);
+/// sink.trace_stop(id, SimpleTrace::OperationThing);
+///
+/// let (first, rest) = sink.into_inner();
+/// let (second, third) = rest.into_inner();
+/// assert_eq!(first.iter().count(), 2);
+/// assert_eq!(second.iter().next().unwrap().id(), third.iter().next().unwrap().id());
+/// ```
+#[derive(Debug)]
+pub struct TeeSink<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> TeeSink<A, B> {
+    /// Construct a new `TeeSink` that forwards traces to `first` and then to
+    /// `second`.
+    pub fn new(first: A, second: B) -> TeeSink<A, B> {
+        TeeSink {
+            first: first,
+            second: second,
+        }
+    }
+
+    /// Get a reference to the first sink.
+    pub fn first(&self) -> &A {
+        &self.first
+    }
+
+    /// Get a mutable reference to the first sink.
+    pub fn first_mut(&mut self) -> &mut A {
+        &mut self.first
+    }
+
+    /// Get a reference to the second sink.
+    pub fn second(&self) -> &B {
+        &self.second
+    }
+
+    /// Get a mutable reference to the second sink.
+    pub fn second_mut(&mut self) -> &mut B {
+        &mut self.second
+    }
+
+    /// Take the two sinks.
+    pub fn into_inner(self) -> (A, B) {
+        (self.first, self.second)
+    }
+}
+
+impl<A, B, T> TraceSink<T> for TeeSink<A, B>
+    where A: TraceSinkWithId<T>,
+          B: TraceSinkWithId<T>,
+          T: Trace
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.trace_event_with_id(id, trace, why);
+        id
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.trace_start_with_id(id, trace, why);
+        id
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        self.first.trace_stop(id, trace);
+        self.second.trace_stop(id, trace);
+    }
+
+    fn trace_sample_rate(&mut self, tag: u32, rate: f64) {
+        self.first.trace_sample_rate(tag, rate);
+        self.second.trace_sample_rate(tag, rate);
+    }
+
+    fn trace_dropped(&mut self, tag: u32, count: u64) {
+        self.first.trace_dropped(tag, count);
+        self.second.trace_dropped(tag, count);
+    }
+}
+
+impl<A, B, T> TraceSinkWithId<T> for TeeSink<A, B>
+    where A: TraceSinkWithId<T>,
+          B: TraceSinkWithId<T>,
+          T: Trace
+{
+    fn trace_event_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        self.first.trace_event_with_id(id, trace, why);
+        self.second.trace_event_with_id(id, trace, why);
+    }
+
+    fn trace_start_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        self.first.trace_start_with_id(id, trace, why);
+        self.second.trace_start_with_id(id, trace, why);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
-    use traits::TraceSink;
+    use analysis;
+    use clock::{Clock, TimeBase};
+    use ring_buffer::NsSinceEpoch;
+    use stats;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use ring_buffer::TraceKind;
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer, SimpleTraceId};
+    use traits::{TraceId, TraceSink, TraceSinkWithId};
 
     #[test]
     fn does_not_trace_when_disabled() {
@@ -121,4 +886,293 @@ mod tests {
 
         assert!(sink.as_ref().iter().next().is_some());
     }
+
+    #[test]
+    fn tee_records_the_same_ids() {
+        let mut sink = TeeSink::new(SimpleTraceBuffer::default(), SimpleTraceBuffer::default());
+        let event = sink.trace_event(SimpleTrace::FooEvent, None);
+        let id = sink.trace_start(SimpleTrace::OperationThing, Some(event));
+        sink.trace_stop(id, SimpleTrace::OperationThing);
+
+        let first: Vec<_> = sink.first().iter().collect();
+        let second: Vec<_> = sink.second().iter().collect();
+        assert_eq!(first.len(), 3);
+        for (a, b) in first.iter().zip(second.iter()) {
+            assert_eq!(a.kind(), b.kind());
+            assert_eq!(a.tag(), b.tag());
+            assert_eq!(a.id(), b.id());
+            assert_eq!(a.why(), b.why());
+        }
+        assert_eq!(first[1].why(), Some((None, first[0].id())));
+        assert_eq!(first[1].id(), first[2].id());
+    }
+
+    // A sink that only implements `TraceSink`, and records the IDs that it
+    // creates.
+    #[derive(Default)]
+    struct IdSink {
+        ids: Vec<u32>,
+    }
+
+    impl TraceSink<SimpleTrace> for IdSink {
+        fn trace_event(&mut self, _: SimpleTrace, _: Option<SimpleTraceId>) -> SimpleTraceId {
+            let id = SimpleTraceId::new_id();
+            self.ids.push(id.u32());
+            id
+        }
+
+        fn trace_start(&mut self, _: SimpleTrace, _: Option<SimpleTraceId>) -> SimpleTraceId {
+            let id = SimpleTraceId::new_id();
+            self.ids.push(id.u32());
+            id
+        }
+
+        fn trace_stop(&mut self, id: SimpleTraceId, _: SimpleTrace) {
+            self.ids.push(id.u32());
+        }
+    }
+
+    impl TraceSinkWithId<SimpleTrace> for IdSink {
+        fn trace_event_with_id(&mut self,
+                               id: SimpleTraceId,
+                               _: SimpleTrace,
+                               _: Option<SimpleTraceId>) {
+            self.ids.push(id.u32());
+        }
+
+        fn trace_start_with_id(&mut self,
+                               id: SimpleTraceId,
+                               _: SimpleTrace,
+                               _: Option<SimpleTraceId>) {
+            self.ids.push(id.u32());
+        }
+    }
+
+    #[test]
+    fn tee_gives_every_sink_the_same_ids() {
+        let mut sink = TeeSink::new(IdSink::default(), SimpleTraceBuffer::default());
+        let event = sink.trace_event(SimpleTrace::FooEvent, None);
+        let id = sink.trace_start(SimpleTrace::OperationThing, Some(event));
+        sink.trace_stop(id, SimpleTrace::OperationThing);
+
+        let expected = vec![event.u32(), id.u32(), id.u32()];
+        assert_eq!(sink.first().ids, expected);
+        assert_eq!(sink.second().iter().map(|e| e.id()).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn wraps_sinks_without_ids() {
+        let mut sink = SamplingSink::new(ToggleSink::new_enabled(IdSink::default()),
+                                         SampleRate::EveryNth(2));
+        let first = sink.trace_start(SimpleTrace::OperationThing, None);
+        let second = sink.trace_start(SimpleTrace::OperationThing, None);
+        sink.trace_stop(second, SimpleTrace::OperationThing);
+        sink.trace_stop(first, SimpleTrace::OperationThing);
+
+        assert_eq!(sink.as_ref().as_ref().ids, vec![first.u32(), first.u32()]);
+    }
+
+    #[test]
+    fn tee_skips_disabled_sinks() {
+        let mut sink = TeeSink::new(ToggleSink::new_disabled(SimpleTraceBuffer::default()),
+                                    SimpleTraceBuffer::default());
+        sink.trace_event(SimpleTrace::FooEvent, None);
+
+        assert_eq!(sink.first().as_ref().iter().next(), None);
+        assert!(sink.second().iter().next().is_some());
+    }
+
+    #[test]
+    fn filters_disabled_tags() {
+        let mut sink = FilterSink::new_enabled(SimpleTraceBuffer::default(), 3);
+        sink.disable(SimpleTrace::FooEvent.tag());
+        assert!(!sink.is_enabled(SimpleTrace::FooEvent.tag()));
+        assert!(sink.is_enabled(SimpleTrace::OperationThing.tag()));
+
+        let first = sink.trace_event(SimpleTrace::FooEvent, None);
+        let second = sink.trace_event(SimpleTrace::FooEvent, None);
+        assert!(first != second);
+        let id = sink.trace_start(SimpleTrace::OperationThing, Some(first));
+        sink.trace_stop(id, SimpleTrace::OperationThing);
+
+        let tags: Vec<_> = sink.as_ref().iter().map(|e| e.tag()).collect();
+        assert_eq!(tags,
+                   vec![SimpleTrace::OperationThing.tag(), SimpleTrace::OperationThing.tag()]);
+    }
+
+    #[test]
+    fn filtered_spans_stay_paired() {
+        let tag = SimpleTrace::OperationThing.tag();
+        let mut sink = FilterSink::new_enabled(SimpleTraceBuffer::default(), 3);
+
+        let passed = sink.trace_start(SimpleTrace::OperationThing, None);
+        sink.disable(tag);
+        let filtered = sink.trace_start(SimpleTrace::OperationThing, None);
+        sink.trace_stop(passed, SimpleTrace::OperationThing);
+        sink.enable(tag);
+        sink.trace_stop(filtered, SimpleTrace::OperationThing);
+
+        let pairing = analysis::pair(sink.as_ref().iter());
+        assert_eq!(pairing.spans().len(), 1);
+        assert_eq!(pairing.spans()[0].id(), passed.u32());
+        assert!(pairing.orphaned_starts().is_empty());
+        assert!(pairing.orphaned_stops().is_empty());
+    }
+
+    #[test]
+    fn filter_enables_tags_across_words() {
+        let sink = FilterSink::new_disabled(SimpleTraceBuffer::default(), 200);
+        for tag in 0..200 {
+            assert!(!sink.is_enabled(tag));
+        }
+
+        sink.enable(0);
+        sink.enable(64);
+        sink.enable(199);
+        let enabled: Vec<_> = (0..200).filter(|&tag| sink.is_enabled(tag)).collect();
+        assert_eq!(enabled, vec![0, 64, 199]);
+
+        sink.enable_all();
+        assert!((0..300).all(|tag| sink.is_enabled(tag)));
+        sink.disable_all();
+        assert!((0..300).all(|tag| !sink.is_enabled(tag)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn filter_enable_out_of_range_tag() {
+        FilterSink::new_disabled(SimpleTraceBuffer::default(), 3).enable(3);
+    }
+
+    #[test]
+    fn samples_every_nth_trace_per_tag() {
+        let mut sink = SamplingSink::new(SimpleTraceBuffer::new(64 * 1024),
+                                         SampleRate::EveryNth(3))
+            .with_tag_rate(SimpleTrace::OperationThing.tag(), SampleRate::EveryNth(2));
+        assert_eq!(sink.rate(SimpleTrace::FooEvent.tag()), SampleRate::EveryNth(3));
+
+        for _ in 0..9 {
+            sink.trace_event(SimpleTrace::FooEvent, None);
+        }
+        for _ in 0..10 {
+            let id = sink.trace_start(SimpleTrace::OperationThing, None);
+            sink.trace_stop(id, SimpleTrace::OperationThing);
+        }
+
+        let buffer = sink.as_ref();
+        let count = |kind| buffer.iter().filter(|e| e.kind() == kind).count();
+        assert_eq!(count(TraceKind::Event), 3);
+        assert_eq!(count(TraceKind::Start), 5);
+        assert_eq!(count(TraceKind::Stop), 5);
+
+        assert_eq!(buffer.sample_rates().len(), 2);
+        assert_eq!(buffer.sample_rates()[&SimpleTrace::FooEvent.tag()], 1.0 / 3.0);
+        assert_eq!(buffer.sample_rates()[&SimpleTrace::OperationThing.tag()], 0.5);
+    }
+
+    #[test]
+    fn sampled_spans_stay_paired() {
+        let mut sink = SamplingSink::new(SimpleTraceBuffer::new(1024 * 1024),
+                                         SampleRate::Fraction(0.25));
+        for _ in 0..1000 {
+            let outer = sink.trace_start(SimpleTrace::OperationThing, None);
+            let inner = sink.trace_start(SimpleTrace::OperationAnother, Some(outer));
+            sink.trace_stop(inner, SimpleTrace::OperationAnother);
+            sink.trace_stop(outer, SimpleTrace::OperationThing);
+        }
+
+        let pairing = analysis::pair(sink.as_ref().iter());
+        assert!(pairing.orphaned_starts().is_empty());
+        assert!(pairing.orphaned_stops().is_empty());
+        let spans = pairing.spans().len();
+        assert!(spans > 300 && spans < 700, "sampled {} of 2000 spans", spans);
+    }
+
+    #[test]
+    fn nested_sample_rates_multiply() {
+        let inner = SamplingSink::new(SimpleTraceBuffer::default(), SampleRate::EveryNth(5));
+        let mut sink = SamplingSink::new(inner, SampleRate::EveryNth(2));
+        for _ in 0..1000 {
+            sink.trace_event(SimpleTrace::FooEvent, None);
+        }
+
+        let buffer = sink.as_ref().as_ref();
+        assert_eq!(buffer.iter().count(), 100);
+        assert_eq!(buffer.sample_rates()[&SimpleTrace::FooEvent.tag()], 0.1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn invalid_sample_rate() {
+        SamplingSink::new(SimpleTraceBuffer::default(), SampleRate::Fraction(0.0));
+    }
+
+    #[derive(Clone, Debug)]
+    struct ManualClock(Rc<Cell<u64>>);
+
+    impl Clock for ManualClock {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+
+        fn time_base(&self) -> TimeBase {
+            TimeBase::new(0, NsSinceEpoch(0))
+        }
+    }
+
+    #[test]
+    fn rate_limits_each_tag() {
+        let clock = ManualClock(Rc::new(Cell::new(0)));
+        let mut sink = RateLimitSink::with_clock(SimpleTraceBuffer::new(64 * 1024),
+                                                 2,
+                                                 Duration::from_millis(1),
+                                                 clock.clone());
+
+        for _ in 0..5 {
+            sink.trace_event(SimpleTrace::FooEvent, None);
+        }
+        let id = sink.trace_start(SimpleTrace::OperationThing, None);
+        sink.trace_stop(id, SimpleTrace::OperationThing);
+
+        // Half a window later, one more token has been refilled.
+        clock.0.set(500_000);
+        sink.trace_event(SimpleTrace::FooEvent, None);
+        sink.trace_event(SimpleTrace::FooEvent, None);
+        sink.report_dropped::<SimpleTrace>();
+
+        let kinds: Vec<_> = sink.as_ref().iter().map(|e| (e.kind(), e.args()[0])).collect();
+        assert_eq!(kinds,
+                   vec![(TraceKind::Event, 0),
+                        (TraceKind::Event, 0),
+                        (TraceKind::Start, 0),
+                        (TraceKind::Stop, 0),
+                        (TraceKind::Dropped, 3),
+                        (TraceKind::Event, 0),
+                        (TraceKind::Dropped, 1)]);
+
+        let summary = stats::summarize(sink.as_ref().iter());
+        assert_eq!(summary.get(SimpleTrace::FooEvent.tag()).unwrap().dropped(), 4);
+    }
+
+    #[test]
+    fn rate_limited_spans_stay_paired() {
+        let clock = ManualClock(Rc::new(Cell::new(0)));
+        let mut sink = RateLimitSink::with_clock(SimpleTraceBuffer::new(64 * 1024),
+                                                 1,
+                                                 Duration::from_secs(1),
+                                                 clock);
+
+        let first = sink.trace_start(SimpleTrace::OperationThing, None);
+        let second = sink.trace_start(SimpleTrace::OperationThing, None);
+        sink.trace_stop(second, SimpleTrace::OperationThing);
+        sink.trace_stop(first, SimpleTrace::OperationThing);
+        sink.report_dropped::<SimpleTrace>();
+
+        let pairing = analysis::pair(sink.as_ref().iter());
+        assert_eq!(pairing.spans().len(), 1);
+        assert!(pairing.orphaned_starts().is_empty());
+        assert!(pairing.orphaned_stops().is_empty());
+        assert_eq!(pairing.dropped().len(), 1);
+        assert_eq!(pairing.dropped()[0].args()[0], 1);
+    }
 }
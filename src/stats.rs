@@ -0,0 +1,417 @@
+//! Per-tag duration statistics.
+//!
+//! ```
+//! use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+//! use eep::stats;
+//! use eep::traits::{Trace, TraceSink};
+//!
+//! let mut buffer = SimpleTraceBuffer::default();
+//! for _ in 0..10 {
+//!     let id = buffer.trace_start(SimpleTrace::OperationThing, None);
+//!     buffer.trace_event(SimpleTrace::FooEvent, Some(id));
+//!     buffer.trace_stop(id, SimpleTrace::OperationThing);
+//! }
+//!
+//! let summary = stats::summarize(buffer.iter());
+//! let thing = summary.get(SimpleTrace::OperationThing.tag()).unwrap();
+//! assert_eq!(thing.count(), 10);
+//! assert!(thing.min() <= thing.p50() && thing.p50() <= thing.max());
+//! assert_eq!(summary.get(SimpleTrace::FooEvent.tag()).unwrap().events(), 10);
+//!
+//! println!("{}", summary);
+//! ```
+
+use analysis::{self, Pairing, SpanNode};
+use ring_buffer::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use traits::Trace;
+
+/// Statistics about every tag in a sequence of entries.
+#[derive(Clone, Debug)]
+pub struct Summary<T> {
+    tags: Vec<TagSummary<T>>,
+}
+
+/// Summarize a timestamp-ordered sequence of entries, such as a
+/// `RingBuffer::iter()`.
+pub fn summarize<T, I>(entries: I) -> Summary<T>
+    where I: IntoIterator<Item = Entry<T>>
+{
+    Summary::from_pairing(&analysis::pair(entries))
+}
+
+impl<T> Summary<T> {
+    /// Summarize the spans and events of a `Pairing`.
+    ///
+    /// Orphaned starts and stops are not counted, since their durations are
+    /// unknown.
+    pub fn from_pairing(pairing: &Pairing<T>) -> Summary<T> {
+        fn add_self_times<T>(nodes: &[SpanNode<T>], self_time: &mut HashMap<u32, u64>) {
+            for node in nodes {
+                *self_time.entry(node.span().tag()).or_insert(0) += node.self_time();
+                add_self_times(node.children(), self_time);
+            }
+        }
+
+        let mut durations: HashMap<u32, Vec<u64>> = HashMap::new();
+        for span in pairing.spans() {
+            durations.entry(span.tag()).or_insert_with(Vec::new).push(span.duration());
+        }
+
+        let mut self_time = HashMap::new();
+        for tree in pairing.trees() {
+            add_self_times(tree.roots(), &mut self_time);
+        }
+
+        let mut events = HashMap::new();
+        for event in pairing.events() {
+            *events.entry(event.tag()).or_insert(0) += 1;
+            durations.entry(event.tag()).or_insert_with(Vec::new);
+        }
+
+        let mut dropped = HashMap::new();
+        for entry in pairing.dropped() {
+            *dropped.entry(entry.tag()).or_insert(0) += entry.args()[0];
+            durations.entry(entry.tag()).or_insert_with(Vec::new);
+        }
+
+        let mut tags: Vec<_> = durations.into_iter()
+            .map(|(tag, mut durations)| {
+                durations.sort();
+                TagSummary {
+                    tag: tag,
+                    count: durations.len(),
+                    total: durations.iter().sum(),
+                    self_time: self_time.get(&tag).cloned().unwrap_or(0),
+                    min: durations.first().cloned().unwrap_or(0),
+                    max: durations.last().cloned().unwrap_or(0),
+                    p50: percentile(&durations, 50),
+                    p90: percentile(&durations, 90),
+                    p99: percentile(&durations, 99),
+                    events: events.get(&tag).cloned().unwrap_or(0),
+                    dropped: dropped.get(&tag).cloned().unwrap_or(0),
+                    sample_rate: 1.0,
+                    phantom: PhantomData,
+                }
+            })
+            .collect();
+        tags.sort_by_key(|t| t.tag);
+
+        Summary { tags: tags }
+    }
+
+    /// Get the statistics of every tag, ordered by tag.
+    pub fn tags(&self) -> &[TagSummary<T>] {
+        &self.tags
+    }
+
+    /// Get the statistics of the given tag, if it has any spans or events.
+    pub fn get(&self, tag: u32) -> Option<&TagSummary<T>> {
+        self.tags.binary_search_by_key(&tag, |t| t.tag).ok().map(|i| &self.tags[i])
+    }
+
+    /// Record the rates that tags were sampled at, such as a
+    /// `LoadedTrace::sample_rates`, so that the summary can estimate how many
+    /// spans and events there were before sampling.
+    pub fn with_sample_rates(mut self, sample_rates: &HashMap<u32, f64>) -> Summary<T> {
+        for t in &mut self.tags {
+            t.sample_rate = sample_rates.get(&t.tag).cloned().unwrap_or(1.0);
+        }
+        self
+    }
+
+    /// Display this summary as a table, using the given labels for tags
+    /// instead of `Trace::label`.
+    ///
+    /// This is useful for summaries of loaded traces, whose labels were
+    /// serialized along with them.
+    pub fn with_labels<'a>(&'a self, labels: &'a HashMap<u32, String>) -> WithLabels<'a, T> {
+        WithLabels {
+            summary: self,
+            labels: labels,
+        }
+    }
+
+    fn fmt_table<F>(&self, f: &mut fmt::Formatter, label: F) -> fmt::Result
+        where F: Fn(u32) -> String
+    {
+        try!(writeln!(f,
+                      "{:<24} {:>8} {:>12} {:>12} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} \
+                       {:>8} {:>8} {:>8}",
+                      "label",
+                      "count",
+                      "total",
+                      "self",
+                      "min",
+                      "max",
+                      "mean",
+                      "p50",
+                      "p90",
+                      "p99",
+                      "events",
+                      "dropped",
+                      "rate"));
+        for t in &self.tags {
+            try!(writeln!(f,
+                          "{:<24} {:>8} {:>12} {:>12} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} \
+                           {:>8} {:>8} {:>8}",
+                          label(t.tag),
+                          t.count,
+                          t.total,
+                          t.self_time,
+                          t.min,
+                          t.max,
+                          t.mean(),
+                          t.p50,
+                          t.p90,
+                          t.p99,
+                          t.events,
+                          t.dropped,
+                          t.sample_rate));
+        }
+        Ok(())
+    }
+}
+
+/// Displays the summary as a table of durations, in nanoseconds.
+impl<T> fmt::Display for Summary<T>
+    where T: Trace
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_table(f, |tag| T::label(tag).to_string())
+    }
+}
+
+/// A `Summary` that is displayed with the given labels. See
+/// `Summary::with_labels`.
+#[derive(Debug)]
+pub struct WithLabels<'a, T>
+    where T: 'a
+{
+    summary: &'a Summary<T>,
+    labels: &'a HashMap<u32, String>,
+}
+
+impl<'a, T> fmt::Display for WithLabels<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let labels = self.labels;
+        self.summary.fmt_table(f, |tag| {
+            labels.get(&tag).cloned().unwrap_or_else(|| format!("<tag {}>", tag))
+        })
+    }
+}
+
+// The nearest-rank percentile of sorted durations.
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (p * sorted.len() + 99) / 100;
+    sorted[rank.max(1) - 1]
+}
+
+/// Statistics about one tag's spans and events.
+///
+/// All durations are in nanoseconds, and are zero if the tag has no spans.
+/// Counts are of the recorded spans and events; if the tag was sampled, use
+/// `estimated_count` and `estimated_events` for the counts before sampling.
+#[derive(Clone, Debug)]
+pub struct TagSummary<T> {
+    tag: u32,
+    count: usize,
+    total: u64,
+    self_time: u64,
+    min: u64,
+    max: u64,
+    p50: u64,
+    p90: u64,
+    p99: u64,
+    events: usize,
+    dropped: u64,
+    sample_rate: f64,
+    phantom: PhantomData<T>,
+}
+
+impl<T> TagSummary<T> {
+    /// Get the tag.
+    pub fn tag(&self) -> u32 {
+        self.tag
+    }
+
+    /// Get the number of completed spans.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Get the sum of the spans' durations.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Get the sum of the spans' durations, excluding the time spent in spans
+    /// nested within them.
+    pub fn self_time(&self) -> u64 {
+        self.self_time
+    }
+
+    /// Get the shortest span's duration.
+    pub fn min(&self) -> u64 {
+        self.min
+    }
+
+    /// Get the longest span's duration.
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Get the mean of the spans' durations.
+    pub fn mean(&self) -> u64 {
+        if self.count == 0 {
+            0
+        } else {
+            self.total / self.count as u64
+        }
+    }
+
+    /// Get the median of the spans' durations.
+    pub fn p50(&self) -> u64 {
+        self.p50
+    }
+
+    /// Get the 90th percentile of the spans' durations.
+    pub fn p90(&self) -> u64 {
+        self.p90
+    }
+
+    /// Get the 99th percentile of the spans' durations.
+    pub fn p99(&self) -> u64 {
+        self.p99
+    }
+
+    /// Get the number of `TraceKind::Event` entries.
+    pub fn events(&self) -> usize {
+        self.events
+    }
+
+    /// Get the number of entries that were dropped before they were recorded,
+    /// according to `TraceKind::Dropped` entries.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Get the fraction of this tag's traces that were recorded. This is
+    /// `1.0` unless sample rates were given with `Summary::with_sample_rates`.
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    /// Estimate the number of spans before sampling.
+    pub fn estimated_count(&self) -> u64 {
+        (self.count as f64 / self.sample_rate).round() as u64
+    }
+
+    /// Estimate the number of `TraceKind::Event` entries before sampling.
+    pub fn estimated_events(&self) -> u64 {
+        (self.events as f64 / self.sample_rate).round() as u64
+    }
+}
+
+impl<T> TagSummary<T>
+    where T: Trace
+{
+    /// Get the tag's label.
+    pub fn label(&self) -> &'static str {
+        T::label(self.tag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring_buffer::{Entry, NsSinceEpoch, TraceKind};
+    use simple_trace::SimpleTrace;
+    use std::collections::HashMap;
+    use traits::Trace;
+
+    fn entry(kind: TraceKind, trace: SimpleTrace, id: u32, timestamp: u64) -> Entry<SimpleTrace> {
+        Entry::new(kind, trace.tag(), id, None, None, NsSinceEpoch(timestamp))
+    }
+
+    #[test]
+    fn percentiles() {
+        let durations: Vec<_> = (1..101).collect();
+        assert_eq!(percentile(&durations, 50), 50);
+        assert_eq!(percentile(&durations, 90), 90);
+        assert_eq!(percentile(&durations, 99), 99);
+        assert_eq!(percentile(&[7], 99), 7);
+        assert_eq!(percentile(&[1, 2], 50), 1);
+        assert_eq!(percentile(&[], 50), 0);
+    }
+
+    #[test]
+    fn summarizes_tags() {
+        // Thing 0 [0, 100] contains Another 1 [10, 30] and Another 2 [40, 80],
+        // then Thing 3 [200, 210] and a Foo event.
+        let entries = vec![entry(TraceKind::Start, SimpleTrace::OperationThing, 0, 0),
+                           entry(TraceKind::Start, SimpleTrace::OperationAnother, 1, 10),
+                           entry(TraceKind::Stop, SimpleTrace::OperationAnother, 1, 30),
+                           entry(TraceKind::Start, SimpleTrace::OperationAnother, 2, 40),
+                           entry(TraceKind::Event, SimpleTrace::FooEvent, 4, 50),
+                           entry(TraceKind::Stop, SimpleTrace::OperationAnother, 2, 80),
+                           entry(TraceKind::Stop, SimpleTrace::OperationThing, 0, 100),
+                           entry(TraceKind::Start, SimpleTrace::OperationThing, 3, 200),
+                           entry(TraceKind::Stop, SimpleTrace::OperationThing, 3, 210)];
+        let summary = summarize(entries);
+        assert_eq!(summary.tags().len(), 3);
+
+        let thing = summary.get(SimpleTrace::OperationThing.tag()).unwrap();
+        assert_eq!(thing.label(), "Thing");
+        assert_eq!(thing.count(), 2);
+        assert_eq!(thing.total(), 110);
+        assert_eq!(thing.self_time(), 110 - 20 - 40);
+        assert_eq!(thing.min(), 10);
+        assert_eq!(thing.max(), 100);
+        assert_eq!(thing.mean(), 55);
+        assert_eq!(thing.p50(), 10);
+        assert_eq!(thing.p99(), 100);
+        assert_eq!(thing.events(), 0);
+
+        let another = summary.get(SimpleTrace::OperationAnother.tag()).unwrap();
+        assert_eq!(another.count(), 2);
+        assert_eq!(another.total(), 60);
+        assert_eq!(another.self_time(), 60);
+
+        let foo = summary.get(SimpleTrace::FooEvent.tag()).unwrap();
+        assert_eq!(foo.count(), 0);
+        assert_eq!(foo.total(), 0);
+        assert_eq!(foo.events(), 1);
+        assert_eq!(foo.sample_rate(), 1.0);
+        assert_eq!(foo.estimated_events(), 1);
+
+        let mut sample_rates = HashMap::new();
+        sample_rates.insert(SimpleTrace::FooEvent.tag(), 0.01);
+        let summary = summary.with_sample_rates(&sample_rates);
+        let foo = summary.get(SimpleTrace::FooEvent.tag()).unwrap();
+        assert_eq!(foo.sample_rate(), 0.01);
+        assert_eq!(foo.estimated_events(), 100);
+        assert_eq!(summary.get(SimpleTrace::OperationThing.tag()).unwrap().estimated_count(), 2);
+    }
+
+    #[test]
+    fn displays_table() {
+        let entries = vec![entry(TraceKind::Start, SimpleTrace::OperationThing, 0, 0),
+                           entry(TraceKind::Stop, SimpleTrace::OperationThing, 0, 100)];
+        let summary = summarize(entries);
+
+        let table = format!("{}", summary);
+        assert_eq!(table.lines().count(), 2);
+        assert!(table.lines().nth(1).unwrap().starts_with("Thing "));
+
+        let mut labels = HashMap::new();
+        labels.insert(SimpleTrace::OperationThing.tag(), "Loaded".to_string());
+        let table = format!("{}", summary.with_labels(&labels));
+        assert!(table.lines().nth(1).unwrap().starts_with("Loaded "));
+    }
+}
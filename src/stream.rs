@@ -0,0 +1,695 @@
+//! Streaming traces to any `io::Write`.
+//!
+//! A `RingBuffer` only keeps its most recent entries. A `StreamSink` instead
+//! keeps every entry: it encodes entries into an in-memory batch, and writes
+//! each full batch to an underlying `io::Write`, such as a file, pipe, or
+//! socket. A `StreamReader` reads the entries back.
+//!
+//! A stream begins with a header:
+//!
+//! | Field                  | Encoding                          |
+//! |------------------------|-----------------------------------|
+//! | magic                  | the four bytes `MAGIC`            |
+//! | stream version         | unsigned LEB128                   |
+//! | entry format version   | unsigned LEB128                   |
+//! | time base reading      | unsigned LEB128                   |
+//! | time base wall time    | unsigned LEB128                   |
+//! | time base ticks per ns | little-endian IEEE 754 `f64` bits |
+//!
+//! The header is followed by any number of batches:
+//!
+//! | Field             | Encoding                                             |
+//! |-------------------|------------------------------------------------------|
+//! | label count       | unsigned LEB128                                      |
+//! | labels            | per label, its tag, byte length, and UTF-8 bytes     |
+//! | sample rate count | unsigned LEB128                                      |
+//! | sample rates      | per rate, its tag and little-endian `f64` bits       |
+//! | entries size      | unsigned LEB128                                      |
+//! | entries           | entries in the `encoding` module's layout            |
+//!
+//! Tags are labeled in the first batch that contains them, which makes up the
+//! stream's table of labels. A tag's sample rate, if it is sampled, is in the
+//! batch after `TraceSink::trace_sample_rate` was called for it. Entries'
+//! timestamps are raw clock readings, and each one is delta-encoded against the
+//! previous entry's, across batches. The first entry is delta-encoded against
+//! the time base's reading.
+//!
+//! Any change to this layout must bump `STREAM_VERSION`.
+//!
+//! ```
+//! use eep::simple_trace::SimpleTrace;
+//! use eep::stream::{StreamReader, StreamSink};
+//! use eep::traits::{Trace, TraceSink};
+//!
+//! let mut bytes = vec![];
+//! {
+//!     let mut sink = StreamSink::new(&mut bytes).unwrap();
+//!     let id = sink.trace_start(SimpleTrace::OperationThing, None);
+//!     sink.trace_stop(id, SimpleTrace::OperationThing);
+//!     sink.flush().unwrap();
+//! }
+//!
+//! let reader = StreamReader::<SimpleTrace, _>::new(&bytes[..]).unwrap();
+//! let trace = reader.into_loaded_trace().unwrap();
+//! assert_eq!(trace.entries().len(), 2);
+//! assert_eq!(trace.label(SimpleTrace::OperationThing.tag()), Some("Thing"));
+//! ```
+
+use clock::{Clock, MonotonicClock, TimeBase};
+use encoding::{self, DecodeError, MAX_ENCODED_ENTRY_SIZE};
+use leb128;
+use loaded_trace::LoadedTrace;
+use ring_buffer::{Entry, NsSinceEpoch, TraceKind};
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::fmt;
+use std::io::{self, Read};
+use std::marker::PhantomData;
+use traits::{Trace, TraceId, TraceSink, TraceSinkWithId};
+
+/// The bytes that every stream begins with.
+pub const MAGIC: &'static [u8; 4] = b"eeps";
+
+/// The version of the stream layout described in this module's documentation.
+pub const STREAM_VERSION: u32 = 2;
+
+/// The default number of bytes of encoded entries that a `StreamSink` buffers
+/// before writing them out as a batch.
+pub const DEFAULT_BATCH_SIZE: usize = 64 * 1024;
+
+/// A `TraceSink` that writes every entry to an `io::Write`, in batches.
+///
+/// Entries are buffered until a batch is full, `flush` is called, or the sink
+/// is dropped. Writing to the underlying `io::Write` happens while tracing, so
+/// it should not block for long.
+///
+/// `TraceSink` methods cannot fail, so if writing a batch fails, the error is
+/// returned from the next call to `flush`, and every later entry is discarded.
+#[derive(Debug)]
+pub struct StreamSink<T, W, C = MonotonicClock>
+    where W: io::Write
+{
+    writer: W,
+    clock: C,
+    batch: Vec<u8>,
+    batch_size: usize,
+    prev_timestamp: u64,
+    labeled: HashSet<u32>,
+    new_labels: Vec<(u32, &'static str)>,
+    new_sample_rates: Vec<(u32, f64)>,
+    failed: bool,
+    error: Option<io::Error>,
+    phantom: PhantomData<T>,
+}
+
+impl<T, W> StreamSink<T, W>
+    where W: io::Write
+{
+    /// Construct a new `StreamSink` that timestamps entries with a
+    /// `MonotonicClock`, and write the stream's header to `writer`.
+    pub fn new(writer: W) -> io::Result<StreamSink<T, W>> {
+        StreamSink::with_clock(writer, MonotonicClock::new())
+    }
+}
+
+impl<T, W, C> StreamSink<T, W, C>
+    where W: io::Write,
+          C: Clock
+{
+    /// Construct a new `StreamSink` that timestamps entries with the given
+    /// clock, and write the stream's header to `writer`.
+    pub fn with_clock(mut writer: W, clock: C) -> io::Result<StreamSink<T, W, C>> {
+        let time_base = clock.time_base();
+        try!(writer.write_all(MAGIC));
+        try!(leb128::write::unsigned(&mut writer, STREAM_VERSION as u64));
+        try!(leb128::write::unsigned(&mut writer, encoding::FORMAT_VERSION as u64));
+        try!(leb128::write::unsigned(&mut writer, time_base.reading()));
+        try!(leb128::write::unsigned(&mut writer, time_base.wall().0));
+        try!(writer.write_all(&time_base.ticks_per_ns().to_bits().to_le_bytes()));
+
+        Ok(StreamSink {
+            writer: writer,
+            clock: clock,
+            batch: Vec::with_capacity(DEFAULT_BATCH_SIZE),
+            batch_size: DEFAULT_BATCH_SIZE,
+            prev_timestamp: time_base.reading(),
+            labeled: HashSet::new(),
+            new_labels: Vec::new(),
+            new_sample_rates: Vec::new(),
+            failed: false,
+            error: None,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Buffer up to `batch_size` bytes of encoded entries before writing them
+    /// out as a batch, instead of `DEFAULT_BATCH_SIZE`.
+    ///
+    /// Smaller batches reach the underlying `io::Write` sooner, but have more
+    /// framing overhead.
+    pub fn with_batch_size(mut self, batch_size: usize) -> StreamSink<T, W, C> {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Get this sink's clock.
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+}
+
+impl<T, W, C> StreamSink<T, W, C>
+    where W: io::Write
+{
+    /// Get a reference to the underlying `io::Write`.
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Write out any buffered entries and sample rates as a batch, and flush
+    /// the underlying `io::Write`.
+    ///
+    /// If writing an earlier batch failed, return that error instead.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.failed {
+            return Err(self.error.take().unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::Other, "an earlier write to the stream failed")
+            }));
+        }
+
+        if !self.batch.is_empty() || !self.new_sample_rates.is_empty() {
+            if let Err(e) = self.write_batch() {
+                self.failed = true;
+                return Err(e);
+            }
+        }
+        self.writer.flush()
+    }
+
+    fn write_batch(&mut self) -> io::Result<()> {
+        try!(leb128::write::unsigned(&mut self.writer, self.new_labels.len() as u64));
+        for &(tag, label) in &self.new_labels {
+            try!(leb128::write::unsigned(&mut self.writer, tag as u64));
+            try!(leb128::write::unsigned(&mut self.writer, label.len() as u64));
+            try!(self.writer.write_all(label.as_bytes()));
+        }
+        try!(leb128::write::unsigned(&mut self.writer, self.new_sample_rates.len() as u64));
+        for &(tag, rate) in &self.new_sample_rates {
+            try!(leb128::write::unsigned(&mut self.writer, tag as u64));
+            try!(self.writer.write_all(&rate.to_bits().to_le_bytes()));
+        }
+        try!(leb128::write::unsigned(&mut self.writer, self.batch.len() as u64));
+        try!(self.writer.write_all(&self.batch));
+
+        self.new_labels.clear();
+        self.new_sample_rates.clear();
+        self.batch.clear();
+        Ok(())
+    }
+
+    fn write(&mut self, entry: &Entry<T>, label: &'static str) {
+        if self.failed {
+            return;
+        }
+
+        if self.batch.len() + MAX_ENCODED_ENTRY_SIZE > self.batch_size && !self.batch.is_empty() {
+            if let Err(e) = self.write_batch() {
+                self.failed = true;
+                self.error = Some(e);
+                return;
+            }
+        }
+
+        if self.labeled.insert(entry.tag()) {
+            self.new_labels.push((entry.tag(), label));
+        }
+        encoding::encode(entry, NsSinceEpoch(self.prev_timestamp), &mut self.batch)
+            .expect("writing to a Vec should never fail");
+        self.prev_timestamp = entry.timestamp().0;
+    }
+}
+
+impl<T, W, C> TraceSink<T> for StreamSink<T, W, C>
+    where T: Trace,
+          W: io::Write,
+          C: Clock
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.trace_event_with_id(id, trace, why);
+        id
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.trace_start_with_id(id, trace, why);
+        id
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        let entry = Entry::new(TraceKind::Stop,
+                               trace.tag(),
+                               id.u32(),
+                               id.thread(),
+                               None,
+                               NsSinceEpoch(self.clock.now()))
+            .with_args(trace.args());
+        self.write(&entry, T::label(trace.tag()));
+    }
+
+    fn trace_sample_rate(&mut self, tag: u32, rate: f64) {
+        self.new_sample_rates.push((tag, rate));
+    }
+
+    fn trace_dropped(&mut self, tag: u32, count: u64) {
+        let entry = Entry::new(TraceKind::Dropped,
+                               tag,
+                               0,
+                               None,
+                               None,
+                               NsSinceEpoch(self.clock.now()))
+            .with_args([count, 0, 0, 0]);
+        self.write(&entry, T::label(tag));
+    }
+}
+
+impl<T, W, C> TraceSinkWithId<T> for StreamSink<T, W, C>
+    where T: Trace,
+          W: io::Write,
+          C: Clock
+{
+    fn trace_event_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        let entry = Entry::new(TraceKind::Event,
+                               trace.tag(),
+                               id.u32(),
+                               id.thread(),
+                               why.map(|id| (id.thread(), id.u32())),
+                               NsSinceEpoch(self.clock.now()))
+            .with_args(trace.args());
+        self.write(&entry, T::label(trace.tag()));
+    }
+
+    fn trace_start_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>) {
+        let entry = Entry::new(TraceKind::Start,
+                               trace.tag(),
+                               id.u32(),
+                               id.thread(),
+                               why.map(|id| (id.thread(), id.u32())),
+                               NsSinceEpoch(self.clock.now()))
+            .with_args(trace.args());
+        self.write(&entry, T::label(trace.tag()));
+    }
+}
+
+/// Write out any buffered entries, ignoring errors. Call `flush` first to
+/// handle them.
+impl<T, W, C> Drop for StreamSink<T, W, C>
+    where W: io::Write
+{
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// An error that occurred while reading a stream.
+#[derive(Debug)]
+pub enum StreamError {
+    /// There was an underlying IO error, for example the input ended in the
+    /// middle of the header or a batch.
+    Io(io::Error),
+    /// The input does not begin with `MAGIC`.
+    InvalidMagic,
+    /// The stream's layout or entry format version is not supported.
+    UnsupportedVersion(u32),
+    /// The time base's ticks per nanosecond is not positive.
+    InvalidTimeBase,
+    /// A label is not valid UTF-8, or its tag does not fit in a `u32`.
+    InvalidLabel,
+    /// An entry could not be decoded.
+    Decode(DecodeError),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StreamError::Io(ref e) => write!(f, "could not read stream: {}", e),
+            StreamError::InvalidMagic => write!(f, "not an eep stream"),
+            StreamError::UnsupportedVersion(v) => write!(f, "unsupported stream version: {}", v),
+            StreamError::InvalidTimeBase => write!(f, "invalid time base"),
+            StreamError::InvalidLabel => write!(f, "invalid label"),
+            StreamError::Decode(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for StreamError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            StreamError::Io(ref e) => Some(e),
+            StreamError::Decode(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for StreamError {
+    fn from(e: io::Error) -> StreamError {
+        StreamError::Io(e)
+    }
+}
+
+impl From<DecodeError> for StreamError {
+    fn from(e: DecodeError) -> StreamError {
+        StreamError::Decode(e)
+    }
+}
+
+impl From<leb128::read::Error> for StreamError {
+    fn from(e: leb128::read::Error) -> StreamError {
+        match e {
+            leb128::read::Error::IoError(e) => StreamError::Io(e),
+            leb128::read::Error::Overflow => StreamError::Decode(DecodeError::Overflow),
+        }
+    }
+}
+
+/// Reads the entries a `StreamSink` wrote, oldest first.
+///
+/// Entries' timestamps are converted to wall-clock time with the stream's time
+/// base. Iteration stops after the first error.
+#[derive(Debug)]
+pub struct StreamReader<T, R> {
+    reader: R,
+    time_base: TimeBase,
+    labels: HashMap<u32, String>,
+    sample_rates: HashMap<u32, f64>,
+    batch: io::Cursor<Vec<u8>>,
+    prev_timestamp: u64,
+    done: bool,
+    phantom: PhantomData<T>,
+}
+
+impl<T, R> StreamReader<T, R>
+    where R: io::Read
+{
+    /// Construct a new `StreamReader`, and read the stream's header from
+    /// `reader`.
+    pub fn new(mut reader: R) -> Result<StreamReader<T, R>, StreamError> {
+        let mut magic = [0; 4];
+        try!(reader.read_exact(&mut magic));
+        if &magic != MAGIC {
+            return Err(StreamError::InvalidMagic);
+        }
+
+        for &supported in &[STREAM_VERSION, encoding::FORMAT_VERSION] {
+            let version = try!(read_u32(&mut reader));
+            if version != supported {
+                return Err(StreamError::UnsupportedVersion(version));
+            }
+        }
+
+        let reading = try!(leb128::read::unsigned(&mut reader));
+        let wall = NsSinceEpoch(try!(leb128::read::unsigned(&mut reader)));
+        let mut ticks_per_ns = [0; 8];
+        try!(reader.read_exact(&mut ticks_per_ns));
+        let ticks_per_ns = f64::from_bits(u64::from_le_bytes(ticks_per_ns));
+        if !(ticks_per_ns > 0.0) {
+            return Err(StreamError::InvalidTimeBase);
+        }
+
+        Ok(StreamReader {
+            reader: reader,
+            time_base: TimeBase::with_ticks_per_ns(reading, wall, ticks_per_ns),
+            labels: HashMap::new(),
+            sample_rates: HashMap::new(),
+            batch: io::Cursor::new(Vec::new()),
+            prev_timestamp: reading,
+            done: false,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Get the time base of the clock that timestamped the entries.
+    pub fn time_base(&self) -> TimeBase {
+        self.time_base
+    }
+
+    /// Get the labels of the tags of the entries read so far.
+    pub fn labels(&self) -> &HashMap<u32, String> {
+        &self.labels
+    }
+
+    /// Get the rates that tags were sampled at, as of the entries read so far.
+    pub fn sample_rates(&self) -> &HashMap<u32, f64> {
+        &self.sample_rates
+    }
+
+    /// Read the rest of the stream into a `LoadedTrace`.
+    pub fn into_loaded_trace(mut self) -> Result<LoadedTrace<T>, StreamError> {
+        let mut entries = vec![];
+        for entry in self.by_ref() {
+            entries.push(try!(entry));
+        }
+        Ok(LoadedTrace::new(Some(self.time_base), self.labels, entries)
+            .with_sample_rates(self.sample_rates))
+    }
+
+    // Read the next batch, or return `false` if the stream ended cleanly
+    // between batches.
+    fn read_batch(&mut self) -> Result<bool, StreamError> {
+        let mut first = [0];
+        if try!(self.reader.read(&mut first)) == 0 {
+            return Ok(false);
+        }
+
+        let num_labels = try!(leb128::read::unsigned(&mut (&first[..]).chain(&mut self.reader)));
+        for _ in 0..num_labels {
+            let tag = try!(read_u32(&mut self.reader));
+            let len = try!(leb128::read::unsigned(&mut self.reader));
+            let mut label = vec![];
+            try!((&mut self.reader).take(len).read_to_end(&mut label));
+            if label.len() as u64 != len {
+                return Err(StreamError::Io(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                          "stream ended in a label")));
+            }
+            let label = try!(String::from_utf8(label).map_err(|_| StreamError::InvalidLabel));
+            self.labels.insert(tag, label);
+        }
+
+        let num_sample_rates = try!(leb128::read::unsigned(&mut self.reader));
+        for _ in 0..num_sample_rates {
+            let tag = try!(read_u32(&mut self.reader));
+            let mut rate = [0; 8];
+            try!(self.reader.read_exact(&mut rate));
+            self.sample_rates.insert(tag, f64::from_bits(u64::from_le_bytes(rate)));
+        }
+
+        let len = try!(leb128::read::unsigned(&mut self.reader));
+        let mut batch = vec![];
+        try!((&mut self.reader).take(len).read_to_end(&mut batch));
+        if batch.len() as u64 != len {
+            return Err(StreamError::Io(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                      "stream ended in a batch")));
+        }
+        self.batch = io::Cursor::new(batch);
+        Ok(true)
+    }
+}
+
+impl<T, R> Iterator for StreamReader<T, R>
+    where R: io::Read
+{
+    type Item = Result<Entry<T>, StreamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if self.batch.position() < self.batch.get_ref().len() as u64 {
+                return match encoding::decode::<T, _>(&mut self.batch,
+                                                      NsSinceEpoch(self.prev_timestamp)) {
+                    Ok(entry) => {
+                        self.prev_timestamp = entry.timestamp().0;
+                        Some(Ok(to_wall(entry, &self.time_base)))
+                    }
+                    Err(e) => {
+                        self.done = true;
+                        Some(Err(e.into()))
+                    }
+                };
+            }
+
+            match self.read_batch() {
+                Ok(true) => {}
+                Ok(false) => self.done = true,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
+
+fn read_u32<R: io::Read>(r: &mut R) -> Result<u32, StreamError> {
+    let n = try!(leb128::read::unsigned(r));
+    if n > ::std::u32::MAX as u64 {
+        return Err(StreamError::Decode(DecodeError::Overflow));
+    }
+    Ok(n as u32)
+}
+
+fn to_wall<T>(entry: Entry<T>, time_base: &TimeBase) -> Entry<T> {
+    Entry::new(entry.kind(),
+               entry.tag(),
+               entry.id(),
+               entry.thread(),
+               entry.why(),
+               time_base.to_wall(entry.timestamp().0))
+        .with_args(entry.args())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clock::{TimeBase, WallClock};
+    use ring_buffer::{NsSinceEpoch, TraceKind};
+    use simple_trace::SimpleTrace;
+    use std::io;
+    use traits::{Trace, TraceSink};
+
+    fn trace_some<S: TraceSink<SimpleTrace>>(sink: &mut S, n: usize) {
+        for _ in 0..n {
+            let event = sink.trace_event(SimpleTrace::FooEvent, None);
+            let id = sink.trace_start(SimpleTrace::OperationThing, Some(event));
+            sink.trace_stop(id, SimpleTrace::OperationThing);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut bytes = vec![];
+        {
+            let mut sink = StreamSink::with_clock(&mut bytes, WallClock).unwrap()
+                .with_batch_size(100);
+            trace_some(&mut sink, 50);
+        }
+
+        let reader = StreamReader::<SimpleTrace, _>::new(&bytes[..]).unwrap();
+        assert_eq!(reader.time_base(), TimeBase::new(0, NsSinceEpoch(0)));
+        let trace = reader.into_loaded_trace().unwrap();
+
+        assert_eq!(trace.entries().len(), 150);
+        assert_eq!(trace.labels().len(), 2);
+        assert_eq!(trace.label(SimpleTrace::FooEvent.tag()), Some("Foo"));
+
+        let kinds: Vec<_> = trace.entries()[..3].iter().map(|e| e.kind()).collect();
+        assert_eq!(kinds, vec![TraceKind::Event, TraceKind::Start, TraceKind::Stop]);
+        for triple in trace.entries().chunks(3) {
+            assert_eq!(triple[1].why(), Some((None, triple[0].id())));
+            assert_eq!(triple[1].id(), triple[2].id());
+        }
+        for pair in trace.entries().windows(2) {
+            assert!(pair[0].timestamp() <= pair[1].timestamp());
+        }
+    }
+
+    #[test]
+    fn batches_are_written_when_full() {
+        let mut sink = StreamSink::<SimpleTrace, _, _>::with_clock(vec![], WallClock)
+            .unwrap()
+            .with_batch_size(MAX_ENCODED_ENTRY_SIZE);
+        let header_len = sink.get_ref().len();
+
+        sink.trace_event(SimpleTrace::FooEvent, None);
+        assert_eq!(sink.get_ref().len(), header_len);
+        sink.trace_event(SimpleTrace::FooEvent, None);
+        assert!(sink.get_ref().len() > header_len);
+    }
+
+    #[test]
+    fn labels_are_written_once() {
+        let mut sink = StreamSink::<SimpleTrace, _, _>::with_clock(vec![], WallClock)
+            .unwrap()
+            .with_batch_size(1);
+        trace_some(&mut sink, 10);
+        sink.flush().unwrap();
+
+        let bytes = sink.get_ref().clone();
+        let count = bytes.windows(5).filter(|w| w == b"Thing").count();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn invalid_header() {
+        assert!(match StreamReader::<SimpleTrace, _>::new(&b"nope\x01\x02"[..]) {
+            Err(StreamError::InvalidMagic) => true,
+            _ => false,
+        });
+        assert!(match StreamReader::<SimpleTrace, _>::new(&b"eeps\x07\x02"[..]) {
+            Err(StreamError::UnsupportedVersion(7)) => true,
+            _ => false,
+        });
+        assert!(match StreamReader::<SimpleTrace, _>::new(&b"eeps\x02"[..]) {
+            Err(StreamError::Io(_)) => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn truncated_batch() {
+        let mut bytes = vec![];
+        {
+            let mut sink = StreamSink::with_clock(&mut bytes, WallClock).unwrap();
+            trace_some(&mut sink, 3);
+        }
+        let len = bytes.len();
+
+        let reader = StreamReader::<SimpleTrace, _>::new(&bytes[..len - 1]).unwrap();
+        let results: Vec<_> = reader.collect();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_err());
+    }
+
+    #[test]
+    fn write_errors_are_reported() {
+        struct Broken;
+
+        impl io::Write for Broken {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if buf.len() > 10 {
+                    Err(io::Error::new(io::ErrorKind::Other, "broken"))
+                } else {
+                    Ok(buf.len())
+                }
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut sink = StreamSink::with_clock(Broken, WallClock).unwrap().with_batch_size(1);
+        trace_some(&mut sink, 10);
+        assert_eq!(sink.flush().unwrap_err().to_string(), "broken");
+        assert!(sink.flush().is_err());
+    }
+
+    #[test]
+    fn sample_rates() {
+        let mut bytes = vec![];
+        {
+            let mut sink = StreamSink::with_clock(&mut bytes, WallClock).unwrap();
+            sink.trace_sample_rate(SimpleTrace::FooEvent.tag(), 0.125);
+            sink.flush().unwrap();
+            sink.trace_event(SimpleTrace::FooEvent, None);
+        }
+
+        let trace = StreamReader::<SimpleTrace, _>::new(&bytes[..])
+            .unwrap()
+            .into_loaded_trace()
+            .unwrap();
+        assert_eq!(trace.entries().len(), 1);
+        assert_eq!(trace.sample_rate(SimpleTrace::FooEvent.tag()), 0.125);
+        assert_eq!(trace.sample_rate(SimpleTrace::OperationThing.tag()), 1.0);
+    }
+}
@@ -3,8 +3,11 @@
 extern crate serde;
 extern crate thread_id;
 
+#[cfg(feature = "derive")]
+pub use eep_derive::Trace;
+
 /// A unique identifier for a thread.
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 pub struct ThreadId(pub usize);
 
 impl ThreadId {
@@ -22,6 +25,14 @@ impl serde::Serialize for ThreadId {
     }
 }
 
+impl serde::Deserialize for ThreadId {
+    fn deserialize<D>(deserializer: &mut D) -> Result<ThreadId, D::Error>
+        where D: serde::Deserializer
+    {
+        usize::deserialize(deserializer).map(ThreadId)
+    }
+}
+
 /// A unique identifier for a traced event or start/stop pair.
 ///
 /// The pair of `(id.u32(), id.thread())` must be unique across all IDs of a
@@ -87,6 +98,9 @@ pub trait TraceId: Copy {
 /// }
 /// ```
 ///
+/// With the `derive` feature enabled, `#[derive(Trace)]` generates this
+/// implementation for fieldless enums; see the `eep-derive` crate.
+///
 /// We require `Copy` because we can't run `Drop` implementations, and we don't
 /// want to leak all over.
 pub trait Trace: Copy {
@@ -99,6 +113,15 @@ pub trait Trace: Copy {
 
     /// Get the tag value for this trace instance.
     fn tag(&self) -> u32;
+
+    /// Get the payload arguments for this trace instance, such as a number of
+    /// bytes decoded or DOM nodes visited.
+    ///
+    /// Sinks record the arguments along with the trace. By default there are
+    /// no arguments, and every argument is zero.
+    fn args(&self) -> [u64; 4] {
+        [0; 4]
+    }
 }
 
 /// TODO FITZGEN
@@ -117,4 +140,38 @@ pub trait TraceSink<T>
     ///
     /// Start the trace by calling `trace_start` to obtain an ID.
     fn trace_stop(&mut self, id: T::Id, trace: T);
+
+    /// Record that only the fraction `rate` of the traces with the given tag
+    /// reach this sink, because the rest were sampled out, for example by
+    /// `sink_combinators::SamplingSink`.
+    ///
+    /// Sinks that save their entries should save the rate along with them, so
+    /// that statistics can be rescaled. By default, the rate is ignored.
+    fn trace_sample_rate(&mut self, _tag: u32, _rate: f64) {}
+
+    /// Record that `count` traces with the given tag were dropped before they
+    /// reached this sink, for example by `sink_combinators::RateLimitSink`.
+    ///
+    /// Sinks that save their entries should save a `TraceKind::Dropped` entry.
+    /// By default, the count is ignored.
+    fn trace_dropped(&mut self, _tag: u32, _count: u64) {}
+}
+
+/// A `TraceSink` that can record traces with IDs chosen by its caller.
+///
+/// This is for sinks that forward traces to other sinks, such as
+/// `sink_combinators::TeeSink`, so that every sink records the same ID.
+pub trait TraceSinkWithId<T>: TraceSink<T>
+    where T: Trace
+{
+    /// Trace a one-off event with the given `id`.
+    ///
+    /// Otherwise, this is the same as `TraceSink::trace_event`.
+    fn trace_event_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>);
+
+    /// Trace the start of an operation with the given `id`.
+    ///
+    /// Finish the trace by calling `trace_stop` with `id`. Otherwise, this is
+    /// the same as `TraceSink::trace_start`.
+    fn trace_start_with_id(&mut self, id: T::Id, trace: T, why: Option<T::Id>);
 }